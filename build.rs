@@ -0,0 +1,17 @@
+use std::path::Path;
+
+#[path = "src/shader_check.rs"]
+mod shader_check;
+
+/* Every .vert/.frag file in here is validated at build time, so a broken shader fails CI
+instead of failing in the browser */
+const SHADER_DIR: &str = "src/shaders";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+    println!("cargo:rerun-if-changed=src/shader_check.rs");
+
+    if let Err(errors) = shader_check::validate_dir(Path::new(SHADER_DIR)) {
+        panic!("Shader validation failed:\n{}", errors);
+    }
+}
@@ -0,0 +1,313 @@
+#![allow(unused)]
+
+use std::rc::Rc;
+
+use crate::decode::{DecodedGif, DecodedImage};
+use crate::math::Rect;
+
+pub struct AnimationFrame {
+    /* Normalized region of the spritesheet, goes straight into Sprite::uv_rect */
+    pub uv_rect: Rect,
+    /* Seconds */
+    pub duration: f32,
+}
+
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /* Size of one frame in pixels */
+    pub frame_width: f32,
+    pub frame_height: f32,
+    /* Times the frames play through before holding the last one, None repeats forever */
+    pub loop_count: Option<u32>,
+}
+
+impl Animation {
+    /* Packs the GIF's frames into one spritesheet no larger than `max_size` on either side,
+    returned alongside the animation that plays it */
+    pub fn from_gif(gif: &DecodedGif, max_size: u32) -> Result<(DecodedImage, Animation), String> {
+        let images = gif
+            .frames
+            .iter()
+            .map(|frame| &frame.image)
+            .collect::<Vec<_>>();
+        let (sheet, uv_rects) = pack_frames(gif.width, gif.height, &images, max_size)?;
+
+        let frames = uv_rects
+            .into_iter()
+            .zip(&gif.frames)
+            .map(|(uv_rect, frame)| AnimationFrame {
+                uv_rect,
+                duration: frame.delay,
+            })
+            .collect();
+
+        let animation = Animation {
+            frames,
+            frame_width: gif.width as f32,
+            frame_height: gif.height as f32,
+            loop_count: gif.loop_count,
+        };
+
+        Ok((sheet, animation))
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames.iter().map(|frame| frame.duration).sum()
+    }
+
+    /* Seconds until the last loop ends, None when it never does */
+    pub fn total_duration(&self) -> Option<f32> {
+        self.loop_count
+            .map(|loop_count| self.duration() * loop_count as f32)
+    }
+
+    /* Frame shown `time` seconds in, wrapping until the loops run out and holding the last frame after */
+    pub fn frame_at(&self, time: f32) -> usize {
+        let duration = self.duration();
+        if self.frames.is_empty() || duration <= 0.0 {
+            return 0;
+        }
+
+        if self
+            .total_duration()
+            .is_some_and(|total_duration| time >= total_duration)
+        {
+            return self.frames.len() - 1;
+        }
+        /* Only endless animations wrap backwards when played in reverse */
+        let time = match self.loop_count {
+            Some(_) => time.max(0.0),
+            None => time,
+        };
+        let time = time.rem_euclid(duration);
+
+        let mut elapsed = 0.0;
+        for (index, frame) in self.frames.iter().enumerate() {
+            elapsed += frame.duration;
+            if time < elapsed {
+                return index;
+            }
+        }
+        self.frames.len() - 1
+    }
+}
+
+/* Lays equally sized frames out in a roughly square grid, returning the sheet and each frame's uv rect */
+pub fn pack_frames(
+    frame_width: u32,
+    frame_height: u32,
+    frames: &[&DecodedImage],
+    max_size: u32,
+) -> Result<(DecodedImage, Vec<Rect>), String> {
+    if frame_width == 0 || frame_height == 0 {
+        return Err(String::from("Frames have no size"));
+    }
+
+    let count = frames.len().max(1) as u32;
+    let columns = ((count as f32).sqrt().ceil() as u32)
+        .min(max_size / frame_width)
+        .max(1);
+    let rows = count.div_ceil(columns);
+
+    let sheet_width = columns * frame_width;
+    let sheet_height = rows * frame_height;
+    if sheet_width > max_size || sheet_height > max_size {
+        return Err(format!(
+            "{} frames of {}x{} don't fit in a {}x{} texture",
+            count, frame_width, frame_height, max_size, max_size
+        ));
+    }
+
+    let mut pixels = vec![0; sheet_width as usize * sheet_height as usize * 4];
+    let mut uv_rects = Vec::with_capacity(frames.len());
+
+    let row_bytes = frame_width as usize * 4;
+    for (index, frame) in frames.iter().enumerate() {
+        let column = index as u32 % columns;
+        let row = index as u32 / columns;
+        let x = column * frame_width;
+        let y = row * frame_height;
+
+        for line in 0..frame_height as usize {
+            let source = line * row_bytes;
+            let destination = ((y as usize + line) * sheet_width as usize + x as usize) * 4;
+            pixels[destination..destination + row_bytes]
+                .copy_from_slice(&frame.pixels[source..source + row_bytes]);
+        }
+
+        uv_rects.push(Rect::new(
+            x as f32 / sheet_width as f32,
+            y as f32 / sheet_height as f32,
+            frame_width as f32 / sheet_width as f32,
+            frame_height as f32 / sheet_height as f32,
+        ));
+    }
+
+    Ok((
+        DecodedImage::new(sheet_width, sheet_height, pixels),
+        uv_rects,
+    ))
+}
+
+/* Playback state for a shared Animation */
+pub struct AnimationPlayer {
+    pub animation: Rc<Animation>,
+    pub time: f32,
+    pub speed: f32,
+    pub playing: bool,
+}
+
+impl AnimationPlayer {
+    pub fn new(animation: Rc<Animation>) -> AnimationPlayer {
+        AnimationPlayer {
+            animation,
+            time: 0.0,
+            speed: 1.0,
+            playing: true,
+        }
+    }
+
+    /* Stops by itself once the animation's loops run out */
+    pub fn update(&mut self, delta_time: f32) {
+        if self.playing {
+            self.time += delta_time * self.speed;
+            if self.finished() {
+                self.playing = false;
+            }
+        }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.animation.frame_at(self.time)
+    }
+
+    pub fn uv_rect(&self) -> Rect {
+        match self.animation.frames.get(self.frame()) {
+            Some(frame) => frame.uv_rect,
+            None => Rect::UNIT,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.animation
+            .total_duration()
+            .is_some_and(|total_duration| self.time >= total_duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::GifFrame;
+
+    fn animation(durations: &[f32], loop_count: Option<u32>) -> Animation {
+        Animation {
+            frames: durations
+                .iter()
+                .map(|duration| AnimationFrame {
+                    uv_rect: Rect::UNIT,
+                    duration: *duration,
+                })
+                .collect(),
+            frame_width: 1.0,
+            frame_height: 1.0,
+            loop_count,
+        }
+    }
+
+    #[test]
+    fn endless_animations_wrap() {
+        let animation = animation(&[0.1, 0.2], None);
+        assert_eq!(animation.total_duration(), None);
+        assert_eq!(animation.frame_at(0.05), 0);
+        assert_eq!(animation.frame_at(0.15), 1);
+        assert_eq!(animation.frame_at(0.35), 0);
+        assert_eq!(animation.frame_at(30.25), 1);
+        assert_eq!(animation.frame_at(-0.05), 1);
+    }
+
+    #[test]
+    fn finite_animations_hold_the_last_frame_after_their_loops() {
+        let animation = animation(&[0.1, 0.2], Some(2));
+        assert_eq!(animation.total_duration(), Some(0.3 * 2.0));
+        assert_eq!(animation.frame_at(-1.0), 0);
+        assert_eq!(animation.frame_at(0.35), 0);
+        assert_eq!(animation.frame_at(0.55), 1);
+        assert_eq!(animation.frame_at(0.65), 1);
+        assert_eq!(animation.frame_at(100.0), 1);
+    }
+
+    #[test]
+    fn player_stops_after_its_loops() {
+        let mut player = AnimationPlayer::new(Rc::new(animation(&[0.5, 0.5], Some(3))));
+
+        for _ in 0..5 {
+            player.update(0.5);
+        }
+        assert!(player.playing);
+        assert!(!player.finished());
+        assert_eq!(player.frame(), 1);
+
+        player.update(0.5);
+        assert!(!player.playing);
+        assert!(player.finished());
+
+        player.update(10.0);
+        assert_eq!(player.time, 3.0);
+        assert_eq!(player.frame(), 1);
+    }
+
+    #[test]
+    fn endless_players_keep_going() {
+        let mut player = AnimationPlayer::new(Rc::new(animation(&[0.5, 0.5], None)));
+        for _ in 0..100 {
+            player.update(0.5);
+        }
+        assert!(player.playing);
+        assert!(!player.finished());
+    }
+
+    #[test]
+    fn from_gif_keeps_delays_and_loop_count() {
+        let frame = |value: u8, delay| GifFrame {
+            image: DecodedImage::new(1, 1, vec![value; 4]),
+            delay,
+        };
+        let gif = DecodedGif {
+            width: 1,
+            height: 1,
+            frames: vec![frame(1, 0.1), frame(2, 0.2), frame(3, 0.3)],
+            loop_count: Some(1),
+        };
+
+        let (sheet, animation) = Animation::from_gif(&gif, 64).unwrap();
+        assert_eq!(animation.loop_count, Some(1));
+        let durations: Vec<f32> = animation
+            .frames
+            .iter()
+            .map(|frame| frame.duration)
+            .collect();
+        assert_eq!(durations, [0.1, 0.2, 0.3]);
+
+        /* Two columns for three frames, the fourth cell left empty */
+        assert_eq!((sheet.width, sheet.height), (2, 2));
+        assert_eq!(sheet.pixel(0, 0), [1; 4]);
+        assert_eq!(sheet.pixel(1, 0), [2; 4]);
+        assert_eq!(sheet.pixel(0, 1), [3; 4]);
+        assert_eq!(sheet.pixel(1, 1), [0; 4]);
+        assert_eq!(animation.frames[2].uv_rect, Rect::new(0.0, 0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn pack_frames_respects_the_size_limit() {
+        let frame = DecodedImage::new(4, 4, vec![0; 64]);
+        let frames = vec![&frame; 5];
+
+        let error = pack_frames(4, 4, &frames, 8).unwrap_err();
+        assert!(error.contains("don't fit"), "{}", error);
+
+        let (sheet, uv_rects) = pack_frames(4, 4, &frames, 12).unwrap();
+        assert_eq!((sheet.width, sheet.height), (12, 8));
+        assert_eq!(uv_rects.len(), 5);
+    }
+}
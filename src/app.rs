@@ -2,19 +2,31 @@
 
 use crate::{camera, log};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 use js_sys::Date;
 use wasm_bindgen::prelude::*;
-use web_sys::{Document, HtmlCanvasElement, WebGl2RenderingContext, Window};
+use web_sys::{Document, Event, HtmlCanvasElement, KeyboardEvent, WebGl2RenderingContext, Window};
 
-use crate::camera::Camera;
+use crate::assets::Assets;
+use crate::bench::UploadBenchmark;
+use crate::camera::{Camera, Screen};
+use crate::capture;
+use crate::math::{Rect, Vec2};
 use crate::object::Object;
+use crate::record::{GifEncoder, Recorder};
 use crate::render::{self, Renderer};
+use crate::state::StateStats;
 use crate::{app, console_log};
 
 pub const BASE_FRAMERATE: f32 = 240.0;
+pub const SCREENSHOT_KEY: &str = "F2";
+pub const RECORD_KEY: &str = "F3";
+/* Three seconds at 15fps, GIFs get large quickly */
+pub const RECORD_FRAMES: usize = 45;
+pub const RECORD_FRAMERATE: f32 = 15.0;
+
 pub struct App {
     pub window: Window,
     pub document: Document,
@@ -24,6 +36,27 @@ pub struct App {
     pub cameras: Vec<Rc<RefCell<Camera>>>,
     pub renderer: Rc<Renderer>,
     pub framerate: f32,
+
+    /* GL state calls issued and skipped during the last drawn frame */
+    pub state_stats: StateStats,
+    pub benchmark: Option<UploadBenchmark>,
+
+    /* KeyboardEvent.key that saves a PNG of the canvas, None to disable */
+    pub screenshot_key: Option<String>,
+    /* Set by the key handler, the canvas can only be read back right after drawing */
+    pub screenshot_requested: Rc<Cell<bool>>,
+
+    /* KeyboardEvent.key that records a GIF of the canvas, None to disable */
+    pub record_key: Option<String>,
+    pub record_requested: Rc<Cell<bool>>,
+    /* Captures frames after each draw until it has enough */
+    pub recorder: Option<Recorder>,
+    /* Then encodes them a step per frame, and downloads the GIF when done */
+    pub gif_encoder: Option<GifEncoder>,
+
+    /* Set by the canvas context events, the main loop is paused in between */
+    pub context_lost: Rc<Cell<bool>>,
+    pub context_restored: Rc<Cell<bool>>,
 }
 
 impl App {
@@ -52,12 +85,29 @@ impl App {
             cameras: Vec::new(),
             renderer: renderer.clone(),
             framerate: BASE_FRAMERATE,
+
+            state_stats: StateStats::default(),
+            benchmark: None,
+
+            screenshot_key: Some(String::from(SCREENSHOT_KEY)),
+            screenshot_requested: Rc::new(Cell::new(false)),
+
+            record_key: Some(String::from(RECORD_KEY)),
+            record_requested: Rc::new(Cell::new(false)),
+            recorder: None,
+            gif_encoder: None,
+
+            context_lost: Rc::new(Cell::new(false)),
+            context_restored: Rc::new(Cell::new(false)),
         };
 
         Ok(app)
     }
 
     pub fn start_main_loop(mut self) {
+        self.listen_for_capture_keys();
+        self.listen_for_context_loss();
+
         let window = self.window.clone();
         let window_pointer = window.clone();
 
@@ -69,13 +119,56 @@ impl App {
 
         *callback.borrow_mut() = Some(Closure::new(move || {
             let current_time = Date::now();
+
+            if self.context_restored.take() {
+                self.restore_context();
+            }
+
+            /* Nothing can be drawn until the context comes back, and the time away isn't simulated */
+            if self.context_lost.get() {
+                start_time = current_time;
+                App::schedule_next_frame(&window_pointer, func.borrow().as_ref().unwrap());
+                return;
+            }
+
             let delta_time = (current_time - start_time) / 1000.0;
 
             if (delta_time > frame_time) {
                 start_time = current_time;
 
                 self.update(delta_time as f32);
+
+                if let Some(benchmark) = &self.benchmark {
+                    benchmark.begin_frame(&self.renderer);
+                }
+
+                let draw_start = App::now(&window_pointer);
                 self.draw(&self.renderer);
+                if let Some(benchmark) = &self.benchmark {
+                    benchmark.wait_for_gpu(&self.renderer);
+                }
+                let draw_time = App::now(&window_pointer) - draw_start;
+
+                if let Some(benchmark) = &mut self.benchmark {
+                    benchmark.end_frame(&self.renderer, draw_time);
+                }
+
+                if self.screenshot_requested.take() {
+                    if let Err(error) = self.save_screenshot() {
+                        console_log!("Unable to save screenshot: {:?}", error);
+                    }
+                }
+
+                if self.record_requested.take()
+                    && self.recorder.is_none()
+                    && self.gif_encoder.is_none()
+                {
+                    self.start_recording(RECORD_FRAMES, RECORD_FRAMERATE, true);
+                }
+                self.record_frame(delta_time as f32);
+                self.encode_recording();
+
+                self.state_stats = self.renderer.take_state_stats();
             }
 
             App::schedule_next_frame(&window_pointer, func.borrow().as_ref().unwrap());
@@ -84,6 +177,173 @@ impl App {
         App::schedule_next_frame(&window, callback.borrow().as_ref().unwrap());
     }
 
+    /* The canvas encoded as PNG, call right after drawing */
+    pub fn screenshot_png(&self) -> Result<Vec<u8>, String> {
+        capture::encode_png(&self.renderer.screenshot()?)
+    }
+
+    pub fn save_screenshot(&self) -> Result<(), JsValue> {
+        let bytes = self.screenshot_png()?;
+        let filename = format!("screenshot-{}.png", Date::now() as u64);
+        capture::download(&bytes, &filename, "image/png")
+    }
+
+    /* Screenshots are taken by the main loop after the next draw */
+    pub fn request_screenshot(&self) {
+        self.screenshot_requested.set(true);
+    }
+
+    /* Records `frame_count` frames at `framerate` from the main loop and downloads them as a GIF */
+    pub fn start_recording(&mut self, frame_count: usize, framerate: f32, dither: bool) {
+        console_log!("Recording {} frames at {}fps", frame_count, framerate);
+        self.recorder = Some(Recorder::new(frame_count, framerate, dither));
+    }
+
+    /* Call right after drawing, like screenshots. Only captures, encoding starts once it's done */
+    fn record_frame(&mut self, delta_time: f32) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+
+        if recorder.due(delta_time) {
+            match self.renderer.screenshot() {
+                Ok(frame) => recorder.push(frame),
+                Err(error) => {
+                    console_log!("Unable to record frame: {}", error);
+                    self.recorder = None;
+                    return;
+                }
+            }
+        }
+
+        if recorder.finished() {
+            let recorder = self.recorder.take().unwrap();
+            match recorder.into_encoder() {
+                Ok(encoder) => self.gif_encoder = Some(encoder),
+                Err(error) => console_log!("Unable to encode recording: {}", error),
+            }
+        }
+    }
+
+    /* One step of the recording's GIF per frame, so the page doesn't freeze while it encodes */
+    fn encode_recording(&mut self) {
+        let Some(encoder) = &mut self.gif_encoder else {
+            return;
+        };
+
+        let result = match encoder.step() {
+            Ok(None) => return,
+            Ok(Some(bytes)) => App::save_recording(&bytes),
+            Err(error) => Err(JsValue::from(error)),
+        };
+        if let Err(error) = result {
+            console_log!("Unable to save recording: {:?}", error);
+        }
+        self.gif_encoder = None;
+    }
+
+    fn save_recording(bytes: &[u8]) -> Result<(), JsValue> {
+        let filename = format!("recording-{}.gif", Date::now() as u64);
+        capture::download(bytes, &filename, "image/gif")
+    }
+
+    fn listen_for_capture_keys(&self) {
+        if self.screenshot_key.is_none() && self.record_key.is_none() {
+            return;
+        }
+
+        let screenshot_key = self.screenshot_key.clone();
+        let screenshot_requested = self.screenshot_requested.clone();
+        let record_key = self.record_key.clone();
+        let record_requested = self.record_requested.clone();
+        let on_key_down = Closure::<dyn FnMut(KeyboardEvent)>::new(move |event: KeyboardEvent| {
+            let key = event.key();
+            if screenshot_key.as_ref() == Some(&key) {
+                screenshot_requested.set(true);
+            }
+            if record_key.as_ref() == Some(&key) {
+                record_requested.set(true);
+            }
+        });
+
+        self.window
+            .add_event_listener_with_callback("keydown", on_key_down.as_ref().unchecked_ref())
+            .expect("Unable to register keydown listener");
+        on_key_down.forget();
+    }
+
+    /* Every GL object died with the old context. Rebuilds the renderer and re-uploads textures and
+    targets from what they were created from, then lets the main loop carry on */
+    pub fn restore_context(&mut self) {
+        console_log!("Restoring WebGL context");
+
+        let renderer = Rc::new(self.renderer.restore());
+        render::RENDERER.with(|renderer_mut| {
+            *renderer_mut.borrow_mut() = Some(renderer.clone());
+        });
+        self.renderer = renderer;
+
+        Assets::restore_textures(&self.renderer);
+        for camera_ref in &self.cameras {
+            camera_ref.borrow_mut().restore(&self.renderer);
+        }
+
+        self.context_lost.set(false);
+    }
+
+    fn listen_for_context_loss(&self) {
+        let lost = self.context_lost.clone();
+        let on_context_lost = Closure::<dyn FnMut(Event)>::new(move |event: Event| {
+            console_log!("WebGL context lost");
+
+            /* Without this the browser never restores the context */
+            event.prevent_default();
+            lost.set(true);
+        });
+
+        let restored = self.context_restored.clone();
+        let on_context_restored = Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+            restored.set(true);
+        });
+
+        for (event, callback) in [
+            ("webglcontextlost", &on_context_lost),
+            ("webglcontextrestored", &on_context_restored),
+        ] {
+            self.canvas
+                .add_event_listener_with_callback(event, callback.as_ref().unchecked_ref())
+                .expect("Unable to register context listener");
+        }
+        on_context_lost.forget();
+        on_context_restored.forget();
+    }
+
+    /* Current canvas layout for Camera::world_to_screen and Camera::screen_to_world */
+    pub fn screen(&self) -> Screen {
+        let buffer_size = Vec2::new(self.canvas.width() as f32, self.canvas.height() as f32);
+
+        /* Hidden canvases have no layout, assume they would be shown at the device pixel ratio */
+        let mut css_size = Vec2::new(
+            self.canvas.client_width() as f32,
+            self.canvas.client_height() as f32,
+        );
+        if css_size.x <= 0.0 || css_size.y <= 0.0 {
+            css_size = buffer_size / self.window.device_pixel_ratio() as f32;
+        }
+
+        let (x, y, width, height) = self.renderer.state.borrow().viewport;
+        let viewport = Rect::new(x as f32, y as f32, width as f32, height as f32);
+
+        Screen::new(css_size, buffer_size, viewport)
+    }
+
+    fn now(window: &Window) -> f64 {
+        match window.performance() {
+            Some(performance) => performance.now(),
+            None => Date::now(),
+        }
+    }
+
     fn schedule_next_frame(window: &Window, callback: &Closure<dyn FnMut()>) {
         window
             .request_animation_frame(callback.as_ref().unchecked_ref())
@@ -98,6 +358,13 @@ impl App {
             &JsValue::from_bool(true),
         )
         .expect("Unable to set context options");
+        /* Clip masks are drawn into the stencil buffer */
+        js_sys::Reflect::set(
+            &options,
+            &JsValue::from_str("stencil"),
+            &JsValue::from_bool(true),
+        )
+        .expect("Unable to set context options");
 
         let context_query = canvas.get_context_with_context_options("webgl2", &options);
         match context_query {
@@ -155,6 +422,11 @@ impl Object for App {
         for object in &mut self.objects {
             object.update(delta_time);
         }
+
+        /* After the objects so cameras follow where things ended up this frame */
+        for camera_ref in &self.cameras {
+            camera_ref.borrow_mut().update(delta_time);
+        }
     }
 
     fn draw(&self, renderer: &render::Renderer) {
@@ -5,20 +5,70 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::HtmlImageElement;
+use web_sys::Response;
 use web_sys::WebGlTexture;
 
+use crate::animation::Animation;
 use crate::console_log;
+use crate::decode::{self, DecodedImage};
+use crate::ktx2::Ktx2;
 use crate::log;
-use crate::render;
+use crate::render::{self, Renderer};
+use crate::texture::TextureOptions;
+
+/* What a texture was uploaded from, kept so it can be uploaded again after the context is lost */
+#[derive(PartialEq)]
+pub enum ImageSource {
+    Element(HtmlImageElement),
+    /* Generated or decoded elsewhere, this keeps a CPU copy of the pixels around */
+    Pixels(DecodedImage),
+    /* A PNG or QOI file, decoded again rather than keeping its pixels */
+    Bytes(Vec<u8>),
+    /* A GIF file, packed into the same spritesheet again */
+    Gif(Vec<u8>),
+    Ktx2(Vec<u8>),
+}
+
+impl ImageSource {
+    /* Returns the texture and the options it actually ended up with (see load_texture_ktx2) */
+    pub fn upload(
+        &self,
+        renderer: &Renderer,
+        options: &TextureOptions,
+    ) -> Result<(WebGlTexture, TextureOptions), String> {
+        match self {
+            ImageSource::Element(image) => {
+                Ok((renderer.load_texture_image(image, options), *options))
+            }
+            ImageSource::Pixels(decoded) => {
+                Ok((upload_decoded(renderer, decoded, options), *options))
+            }
+            ImageSource::Bytes(bytes) => {
+                let decoded = decode::decode(bytes)?;
+                Ok((upload_decoded(renderer, &decoded, options), *options))
+            }
+            ImageSource::Gif(bytes) => {
+                let gif = decode::decode_gif(bytes)?;
+                let (sheet, _) = Animation::from_gif(&gif, renderer.max_texture_size)?;
+                Ok((upload_decoded(renderer, &sheet, options), *options))
+            }
+            ImageSource::Ktx2(bytes) => renderer.load_texture_ktx2(&Ktx2::parse(bytes)?, options),
+        }
+    }
+}
 
 #[derive(PartialEq)]
 pub struct Image {
-    pub html_image: HtmlImageElement,
+    pub source: ImageSource,
     pub webl_gl_texture: WebGlTexture,
+    pub width: u32,
+    pub height: u32,
+    pub options: TextureOptions,
 }
 
 pub struct Assets {
     pub image_cache: HashMap<String, Rc<RefCell<Image>>>,
+    pub animation_cache: HashMap<String, Rc<Animation>>,
 }
 
 thread_local! {
@@ -29,21 +79,248 @@ impl Assets {
     pub fn new() -> Assets {
         Assets {
             image_cache: HashMap::new(),
+            animation_cache: HashMap::new(),
         }
     }
 
     pub async fn load_image(path: &str) -> Option<Rc<RefCell<Image>>> {
+        Assets::load_image_with(path, TextureOptions::new()).await
+    }
+
+    /* The options only apply the first time a path is loaded, later loads share the cached texture */
+    pub async fn load_image_with(
+        path: &str,
+        options: TextureOptions,
+    ) -> Option<Rc<RefCell<Image>>> {
         match Assets::check_cache_image(path).await {
             Some(image_pointer) => Some(image_pointer.clone()),
-            None => Assets::cache_image(path).await.ok(),
+            None => Assets::cache_image(path, options).await.ok(),
+        }
+    }
+
+    /* Variants are KTX2 paths with the vkFormat each was encoded in, from most to least preferred
+    (e.g. ASTC, ETC2, BC7). Only the first the device supports is downloaded, the next supported
+    one if that fails. Falls back to a regular image if none work */
+    pub async fn load_ktx2(
+        variants: &[(&str, u32)],
+        fallback: Option<&str>,
+        options: TextureOptions,
+    ) -> Option<Rc<RefCell<Image>>> {
+        let vk_formats: Vec<u32> = variants.iter().map(|(_, vk_format)| *vk_format).collect();
+
+        let mut start = 0;
+        while let Some(index) = render::with_renderer(|renderer| {
+            renderer.supported_formats.select(&vk_formats[start..])
+        }) {
+            let path = variants[start + index].0;
+            start += index + 1;
+
+            if let Some(image_pointer) = Assets::check_cache_image(path).await {
+                return Some(image_pointer);
+            }
+
+            match Assets::cache_ktx2(path, options).await {
+                Ok(image_pointer) => return Some(image_pointer),
+                Err(error) => console_log!("Skipping {}: {:?}", path, error),
+            }
+        }
+
+        match fallback {
+            Some(path) => Assets::load_image_with(path, options).await,
+            None => None,
+        }
+    }
+
+    pub async fn cache_ktx2(
+        path: &str,
+        options: TextureOptions,
+    ) -> Result<Rc<RefCell<Image>>, JsValue> {
+        console_log!("Caching KTX2 texture: {}", path);
+
+        let bytes = Assets::fetch_bytes(path).await?;
+        let ktx2 = Ktx2::parse(&bytes)?;
+        let (width, height) = (ktx2.width, ktx2.height);
+
+        let source = ImageSource::Ktx2(bytes);
+        let (webl_gl_texture, options) =
+            render::with_renderer(|renderer| source.upload(renderer, &options))?;
+
+        Ok(Assets::insert_image(
+            path,
+            Image {
+                source,
+                webl_gl_texture,
+                width,
+                height,
+                options,
+            },
+        ))
+    }
+
+    /* Fetches and decodes in Rust instead of through an HtmlImageElement (PNG and QOI) */
+    pub async fn load_image_bytes(
+        path: &str,
+        options: TextureOptions,
+    ) -> Option<Rc<RefCell<Image>>> {
+        if let Some(image_pointer) = Assets::check_cache_image(path).await {
+            return Some(image_pointer);
+        }
+
+        console_log!("Caching image bytes: {}", path);
+
+        let result = match Assets::fetch_bytes(path).await {
+            Ok(bytes) => decode::decode(&bytes)
+                .map(|decoded| {
+                    Assets::cache_uploaded(path, &decoded, ImageSource::Bytes(bytes), options)
+                })
+                .map_err(JsValue::from),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(image_pointer) => Some(image_pointer),
+            Err(error) => {
+                console_log!("Unable to load {}: {:?}", path, error);
+                None
+            }
+        }
+    }
+
+    /* Decodes an animated GIF into a spritesheet texture and its animation. The sheet is cached
+    under gif_key(path) so it can't be mistaken for the file loaded as a plain image. Mipmaps are
+    always off, smaller levels would blend neighbouring frames into each other */
+    pub async fn load_gif(
+        path: &str,
+        options: TextureOptions,
+    ) -> Option<(Rc<RefCell<Image>>, Rc<Animation>)> {
+        let options = options.with_mipmaps(false);
+        let sheet_key = gif_key(path);
+        let cached = ASSETS.with(|assets| {
+            let assets = assets.borrow();
+            Some((
+                assets.image_cache.get(&sheet_key).cloned()?,
+                assets.animation_cache.get(path).cloned()?,
+            ))
+        });
+        if cached.is_some() {
+            return cached;
+        }
+
+        console_log!("Caching GIF: {}", path);
+
+        let max_size = render::with_renderer(|renderer| renderer.max_texture_size);
+        let decoded = match Assets::fetch_bytes(path).await {
+            Ok(bytes) => decode::decode_gif(&bytes)
+                .and_then(|gif| Animation::from_gif(&gif, max_size))
+                .map(|decoded| (bytes, decoded))
+                .map_err(JsValue::from),
+            Err(error) => Err(error),
+        };
+
+        let (bytes, (sheet, animation)) = match decoded {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                console_log!("Unable to load {}: {:?}", path, error);
+                return None;
+            }
+        };
+        let animation = Rc::new(animation);
+        /* The file is a fraction of the sheet's size, so that is what's kept for a restore */
+        let image_ref =
+            Assets::cache_uploaded(&sheet_key, &sheet, ImageSource::Gif(bytes), options);
+
+        ASSETS.with(|assets| {
+            let mut a = assets.borrow_mut();
+            a.animation_cache
+                .insert(path.to_string(), animation.clone());
+        });
+
+        Some((image_ref, animation))
+    }
+
+    /* Uploads pixels that were decoded or generated elsewhere, e.g. from a bundle, under `key`.
+    They are kept to upload again after the context is lost */
+    pub fn cache_decoded(
+        key: &str,
+        decoded: DecodedImage,
+        options: TextureOptions,
+    ) -> Rc<RefCell<Image>> {
+        console_log!("Caching decoded image: {}", key);
+
+        let webl_gl_texture =
+            render::with_renderer(|renderer| upload_decoded(renderer, &decoded, &options));
+
+        Assets::insert_image(
+            key,
+            Image {
+                width: decoded.width,
+                height: decoded.height,
+                source: ImageSource::Pixels(decoded),
+                webl_gl_texture,
+                options,
+            },
+        )
+    }
+
+    /* Uploads `decoded` now, `source` is what it is uploaded from again after a context loss */
+    fn cache_uploaded(
+        key: &str,
+        decoded: &DecodedImage,
+        source: ImageSource,
+        options: TextureOptions,
+    ) -> Rc<RefCell<Image>> {
+        let webl_gl_texture =
+            render::with_renderer(|renderer| upload_decoded(renderer, decoded, &options));
+
+        Assets::insert_image(
+            key,
+            Image {
+                source,
+                webl_gl_texture,
+                width: decoded.width,
+                height: decoded.height,
+                options,
+            },
+        )
+    }
+
+    fn insert_image(key: &str, image: Image) -> Rc<RefCell<Image>> {
+        let image_ref = Rc::new(RefCell::new(image));
+
+        ASSETS.with(|assets| {
+            let mut a = assets.borrow_mut();
+            a.image_cache.insert(key.to_string(), image_ref.clone());
+        });
+
+        image_ref
+    }
+
+    pub async fn fetch_bytes(path: &str) -> Result<Vec<u8>, JsValue> {
+        let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window"))?;
+
+        let response: Response = JsFuture::from(window.fetch_with_str(path))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Unable to fetch {} ({})",
+                path,
+                response.status()
+            )));
         }
+
+        let buffer = JsFuture::from(response.array_buffer()?).await?;
+        Ok(js_sys::Uint8Array::new(&buffer).to_vec())
     }
 
     async fn check_cache_image(path: &str) -> Option<Rc<RefCell<Image>>> {
         ASSETS.with(|assets| assets.borrow().image_cache.get(path).cloned())
     }
 
-    pub async fn cache_image(path: &str) -> Result<Rc<RefCell<Image>>, JsValue> {
+    pub async fn cache_image(
+        path: &str,
+        options: TextureOptions,
+    ) -> Result<Rc<RefCell<Image>>, JsValue> {
         console_log!("Caching image: {}", path);
 
         let image = HtmlImageElement::new().unwrap();
@@ -63,7 +340,7 @@ impl Assets {
 
         JsFuture::from(promise).await?;
 
-        let image_ref = Assets::generate_texture(image);
+        let image_ref = Assets::generate_texture(image, options);
 
         ASSETS.with(|assets| {
             let mut a = assets.borrow_mut();
@@ -78,29 +355,69 @@ impl Assets {
 
         ASSETS.with(|assets| {
             let mut assets_mut = assets.borrow_mut();
+            if assets_mut.animation_cache.remove(path).is_some() {
+                assets_mut.image_cache.remove(&gif_key(path));
+            }
             if let Some(image) = assets_mut.image_cache.remove(path) {
-                let html_image = &image.borrow().html_image;
+                if let ImageSource::Element(html_image) = &image.borrow().source {
+                    html_image.set_onload(None);
+                    html_image.set_onerror(None);
 
-                html_image.set_onload(None);
-                html_image.set_onerror(None);
-
-                html_image.set_src("");
-                html_image.set_attribute("src", "").ok();
+                    html_image.set_src("");
+                    html_image.set_attribute("src", "").ok();
+                }
             };
         });
     }
 
-    fn generate_texture(image: HtmlImageElement) -> Rc<RefCell<Image>> {
+    /* Uploads every cached image again on a restored context, the old textures are gone with it */
+    pub fn restore_textures(renderer: &Renderer) {
+        ASSETS.with(|assets| {
+            for (path, image_ref) in &assets.borrow().image_cache {
+                let mut image = image_ref.borrow_mut();
+                match image.source.upload(renderer, &image.options) {
+                    Ok((webl_gl_texture, options)) => {
+                        image.webl_gl_texture = webl_gl_texture;
+                        image.options = options;
+                    }
+                    Err(error) => console_log!("Unable to restore {}: {}", path, error),
+                }
+            }
+        });
+    }
+
+    fn generate_texture(image: HtmlImageElement, options: TextureOptions) -> Rc<RefCell<Image>> {
         let webl_gl_texture = render::RENDERER.with(|renderer| {
             let binding = renderer.borrow();
             let renderer_borrow = binding.as_ref().unwrap();
-            renderer_borrow.load_texture_image(&image)
+            renderer_borrow.load_texture_image(&image, &options)
         });
 
         let texture = Image {
-            html_image: image,
+            width: image.width(),
+            height: image.height(),
+            source: ImageSource::Element(image),
             webl_gl_texture,
+            options,
         };
         Rc::new(RefCell::new(texture))
     }
 }
+
+fn upload_decoded(
+    renderer: &Renderer,
+    decoded: &DecodedImage,
+    options: &TextureOptions,
+) -> WebGlTexture {
+    renderer.load_texture_rgba(
+        decoded.width as i32,
+        decoded.height as i32,
+        &decoded.pixels,
+        options,
+    )
+}
+
+/* Image cache key for a GIF's spritesheet, apart from `path` loaded as a plain image */
+pub fn gif_key(path: &str) -> String {
+    format!("gif:{}", path)
+}
@@ -0,0 +1,88 @@
+#![allow(unused)]
+
+use web_sys::WebGl2RenderingContext;
+
+use crate::render::{Renderer, UploadMode};
+use crate::{console_log, log};
+
+/* Draws the same scene with the legacy and streamed upload paths and logs the average frame time,
+from submitting the draws until the GPU has finished them */
+pub struct UploadBenchmark {
+    pub frames: u32,
+    pub frame: u32,
+
+    pub legacy_time: f64,
+    pub stream_time: f64,
+}
+
+impl UploadBenchmark {
+    pub fn new(frames: u32) -> UploadBenchmark {
+        UploadBenchmark {
+            frames,
+            frame: 0,
+
+            legacy_time: 0.0,
+            stream_time: 0.0,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.frames * 2
+    }
+
+    /* Called before a frame is drawn, picks the upload path for that frame */
+    pub fn begin_frame(&self, renderer: &Renderer) {
+        let mode = if self.frame < self.frames {
+            UploadMode::Legacy
+        } else {
+            UploadMode::Stream
+        };
+        renderer.quads_buffer.upload_mode.set(mode);
+    }
+
+    /* Called right after drawing, before the clock is read. Reading a pixel back stalls until the
+    GPU is done, so the frame time includes the uploads being consumed and not just submitted */
+    pub fn wait_for_gpu(&self, renderer: &Renderer) {
+        if self.finished() {
+            return;
+        }
+
+        renderer.bind_framebuffer(WebGl2RenderingContext::READ_FRAMEBUFFER, None);
+        let mut pixel = [0; 4];
+        let _ = renderer.context.read_pixels_with_opt_u8_array(
+            0,
+            0,
+            1,
+            1,
+            WebGl2RenderingContext::RGBA,
+            WebGl2RenderingContext::UNSIGNED_BYTE,
+            Some(&mut pixel),
+        );
+    }
+
+    /* Called after a frame is drawn with how long the draw took in milliseconds */
+    pub fn end_frame(&mut self, renderer: &Renderer, draw_time: f64) {
+        if self.finished() {
+            return;
+        }
+
+        if self.frame < self.frames {
+            self.legacy_time += draw_time;
+        } else {
+            self.stream_time += draw_time;
+        }
+        self.frame += 1;
+
+        if self.finished() {
+            let frames = self.frames as f64;
+            console_log!(
+                "Upload benchmark over {} frames: legacy per-batch buffers {:.3}ms/frame, streamed ring {:.3}ms/frame",
+                self.frames,
+                self.legacy_time / frames,
+                self.stream_time / frames
+            );
+
+            renderer.quads_buffer.upload_mode.set(UploadMode::Stream);
+        }
+    }
+}
@@ -1,16 +1,24 @@
 #![allow(unused)]
 
 use crate::{log, render::BASE_QUAD_VERTS};
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, sync::Once};
 
 use web_sys::{WebGl2RenderingContext, WebGlProgram, WebGlTexture};
 
 use crate::{
     app,
     assets::Image,
+    clip::{self, Clip},
     console_log,
+    decode::DecodedImage,
+    effects::{CameraEffects, Ease, Overlay, Shake, Timer, Tween},
+    follow::{self, Follow, FollowTarget},
+    lighting::{self, Lighting},
+    math::{to_radians, Color, Mat3, Rect, Vec2},
     object::Object,
-    render::{self, BASE_QUAD_INDICES, BASE_QUAD_UVS},
+    post::{PostEffect, PostEffects},
+    program::Program,
+    render::{self, BASE_QUAD_INDICES, BASE_QUAD_UVS, QUAD_VERTEX_FLOATS},
     sprite::Sprite,
 };
 
@@ -24,19 +32,143 @@ pub struct Camera {
     pub scrolly: f32,
 
     pub draws: Vec<DrawCall>,
-    pub shader: Option<WebGlProgram>,
+    pub shader: Option<Rc<Program>>,
+
+    /* Submit sprites as instances and transform them on the GPU instead of in transform_tris */
+    pub instanced: bool,
+
+    /* Skip sprites whose bounds fall outside the view */
+    pub culling: bool,
+    pub cull_stats: CullStats,
+    pub last_cull_stats: CullStats,
+
+    pub follow: Option<Follow>,
+    /* World area the view is kept inside of */
+    pub bounds: Option<Rect>,
+
+    pub effects: CameraEffects,
+
+    /* Renders at a fixed low resolution and upscales by whole pixels */
+    pub pixel_perfect: Option<PixelPerfect>,
+
+    /* Canvas-sized target of this camera's own for a shader, lighting or effects without
+    pixel_perfect, so capture doesn't read whichever camera drew offscreen last */
+    pub offscreen: Option<render::PostProcessTarget>,
+
+    /* Clips pushed while drawing, the innermost applies to anything pushed after it */
+    pub clip_stack: Vec<Rc<Clip>>,
+
+    /* Lights the scene before the post-process shader when set, see enable_lighting */
+    pub lighting: Option<Lighting>,
+
+    /* Effects run in order over the lit image, before the post-process shader */
+    pub post_effects: Option<PostEffects>,
+}
+
+pub struct PixelPerfect {
+    pub target: render::PostProcessTarget,
+}
+
+impl PixelPerfect {
+    /* Largest whole multiple of the virtual resolution that fits the canvas, at least 1 */
+    pub fn integer_scale(&self, canvas_width: i32, canvas_height: i32) -> i32 {
+        (canvas_width / self.target.width)
+            .min(canvas_height / self.target.height)
+            .max(1)
+    }
+
+    /* Where the upscaled image lands on the canvas, centered with letterboxing */
+    pub fn viewport(&self, canvas_width: i32, canvas_height: i32) -> Rect {
+        let scale = self.integer_scale(canvas_width, canvas_height);
+        let width = self.target.width * scale;
+        let height = self.target.height * scale;
+
+        Rect::new(
+            ((canvas_width - width) / 2) as f32,
+            ((canvas_height - height) / 2) as f32,
+            width as f32,
+            height as f32,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+/* The unit quad corners in BASE_QUAD_VERTS, which is also the visible range of clip space */
+pub const CLIP_RECT: Rect = Rect::new(-1.0, -1.0, 2.0, 2.0);
+
+/* Masks are pushed again every frame, so going over the limit is only reported the first time */
+static MASK_LIMIT_WARNING: Once = Once::new();
+
+/* How the canvas is laid out on the page, needed to go between CSS pixels and clip space */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Screen {
+    /* Canvas size in CSS pixels, the space pointer event offsets are in */
+    pub css_size: Vec2,
+    /* Drawing buffer size in device pixels */
+    pub buffer_size: Vec2,
+    /* Region clip space maps to, in device pixels with GL's bottom-left origin */
+    pub viewport: Rect,
+}
+
+impl Screen {
+    pub fn new(css_size: Vec2, buffer_size: Vec2, viewport: Rect) -> Screen {
+        Screen {
+            css_size,
+            buffer_size,
+            viewport,
+        }
+    }
+
+    /* Device pixels per CSS pixel, the device pixel ratio when the canvas is sized for it */
+    pub fn pixel_ratio(&self) -> Vec2 {
+        Vec2::new(
+            self.buffer_size.x / self.css_size.x,
+            self.buffer_size.y / self.css_size.y,
+        )
+    }
+
+    /* Normalized device coordinates to CSS pixels from the canvas' top-left corner */
+    pub fn ndc_to_css(&self, ndc: Vec2) -> Vec2 {
+        let buffer_x = self.viewport.x + (ndc.x + 1.0) / 2.0 * self.viewport.width;
+        let buffer_y = self.viewport.y + (ndc.y + 1.0) / 2.0 * self.viewport.height;
+
+        let ratio = self.pixel_ratio();
+        Vec2::new(
+            buffer_x / ratio.x,
+            (self.buffer_size.y - buffer_y) / ratio.y,
+        )
+    }
+
+    pub fn css_to_ndc(&self, css: Vec2) -> Vec2 {
+        let ratio = self.pixel_ratio();
+        let buffer_x = css.x * ratio.x;
+        let buffer_y = self.buffer_size.y - css.y * ratio.y;
+
+        Vec2::new(
+            (buffer_x - self.viewport.x) / self.viewport.width * 2.0 - 1.0,
+            (buffer_y - self.viewport.y) / self.viewport.height * 2.0 - 1.0,
+        )
+    }
 }
 
 pub struct DrawCall {
     pub texture: WebGlTexture,
-    pub program: WebGlProgram,
+    pub program: Rc<Program>,
 
+    /* Transformed quad vertices, or per-sprite instance data when `instanced` */
     pub vertices: Vec<f32>,
     pub count: usize,
+    pub instanced: bool,
+    /* Whether the texture has premultiplied alpha, picks the blend function */
+    pub premultiplied: bool,
+    pub clip: Option<Rc<Clip>>,
 }
 
-pub const DEG_TO_RADIANS: f32 = (std::f64::consts::PI / 180.0) as f32;
-
 impl Camera {
     pub fn new(width: f32, height: f32) -> Camera {
         Camera {
@@ -48,158 +180,691 @@ impl Camera {
             scrolly: 0.0,
             draws: Vec::new(),
             shader: None,
+
+            instanced: false,
+
+            culling: true,
+            cull_stats: CullStats::default(),
+            last_cull_stats: CullStats::default(),
+
+            follow: None,
+            bounds: None,
+
+            effects: CameraEffects::new(),
+
+            pixel_perfect: None,
+
+            offscreen: None,
+
+            clip_stack: Vec::new(),
+
+            lighting: None,
+
+            post_effects: None,
         }
     }
 
-    pub fn transform_tris(&self, sprite: &Sprite) -> Vec<f32> {
-        let mut vertices = render::BASE_QUAD_VERTS;
+    /* Starts following `target`, the returned Follow can be tweaked for smoothing and deadzones */
+    pub fn start_follow(&mut self, target: FollowTarget) -> &mut Follow {
+        self.follow.insert(Follow::new(target))
+    }
 
-        for i in (0..vertices.len()).step_by(3) {
-            let mut x = vertices[i];
-            let mut y = vertices[i + 1];
-
-            /* Sprite transformations */
-            x *= sprite.width;
-            y *= sprite.height;
-
-            x *= sprite.scalex;
-            y *= sprite.scaley;
-
-            if sprite.rotation != 0.0 {
-                let radians = -sprite.rotation * DEG_TO_RADIANS;
-                let cos_theta = radians.cos();
-                let sin_theta = radians.sin();
-                let new_x = x * cos_theta - y * sin_theta;
-                let new_y = x * sin_theta + y * cos_theta;
-                x = new_x;
-                y = new_y;
+    pub fn stop_follow(&mut self) {
+        self.follow = None;
+    }
+
+    /* Renders at `width` x `height` and upscales by whole pixels. The camera size becomes the virtual
+    resolution, so set this before creating sprites so their textures pick NEAREST filtering */
+    pub fn set_pixel_perfect(&mut self, renderer: &render::Renderer, width: i32, height: i32) {
+        self.width = width as f32;
+        self.height = height as f32;
+
+        let target =
+            renderer.create_render_target(width, height, WebGl2RenderingContext::NEAREST, false);
+        self.pixel_perfect = Some(PixelPerfect { target });
+        self.offscreen = None;
+
+        if let Some(lighting) = &mut self.lighting {
+            lighting.resize(renderer, width, height);
+        }
+        if let Some(post_effects) = &mut self.post_effects {
+            post_effects.resize(renderer, width, height, WebGl2RenderingContext::NEAREST);
+        }
+    }
+
+    /* Darkens the scene to `ambient` and lights it with Lighting::lights. Sprites with a normal
+    map get shaded by it, everything else is treated as facing the viewer */
+    pub fn enable_lighting(
+        &mut self,
+        renderer: &render::Renderer,
+        ambient: Color,
+    ) -> &mut Lighting {
+        let (width, height) = self.target_size(renderer);
+        let lighting = Lighting::new(renderer, width, height, ambient);
+        self.lighting = Some(lighting);
+        self.prepare_offscreen(renderer);
+        self.lighting.as_mut().unwrap()
+    }
+
+    pub fn disable_lighting(&mut self) {
+        self.lighting = None;
+    }
+
+    /* Appends `effect` to the end of the chain, the returned PostEffects allows reordering and
+    tweaking them later */
+    pub fn add_post_effect(
+        &mut self,
+        renderer: &render::Renderer,
+        effect: PostEffect,
+    ) -> &mut PostEffects {
+        let (width, height) = self.target_size(renderer);
+        let filter = self.post_filter();
+        self.post_effects
+            .get_or_insert_with(|| PostEffects::new(renderer, width, height, filter))
+            .add(renderer, effect);
+        self.prepare_offscreen(renderer);
+        self.post_effects.as_mut().unwrap()
+    }
+
+    pub fn clear_post_effects(&mut self) {
+        self.post_effects = None;
+    }
+
+    /* Effects output the final image, so they keep pixel-perfect cameras crisp when upscaled */
+    fn post_filter(&self) -> u32 {
+        match self.pixel_perfect {
+            Some(_) => WebGl2RenderingContext::NEAREST,
+            None => WebGl2RenderingContext::LINEAR,
+        }
+    }
+
+    fn draws_offscreen(&self) -> bool {
+        self.shader.is_some() || self.lighting.is_some() || self.post_effects.is_some()
+    }
+
+    /* Creates or frees `offscreen` to match what the camera draws with. Runs every update, so a
+    shader assigned directly gets its target before the next draw */
+    pub fn prepare_offscreen(&mut self, renderer: &render::Renderer) {
+        if self.pixel_perfect.is_some() || !self.draws_offscreen() {
+            self.offscreen = None;
+        } else if self.offscreen.is_none() {
+            let (width, height) = (renderer.post_process.width, renderer.post_process.height);
+            self.offscreen = Some(renderer.create_render_target(
+                width,
+                height,
+                WebGl2RenderingContext::LINEAR,
+                true,
+            ));
+        }
+    }
+
+    /* Offscreen target this camera draws into, None when it draws straight to the canvas. The
+    shared renderer target only stands in until the next update creates `offscreen` */
+    pub fn target<'a>(
+        &'a self,
+        renderer: &'a render::Renderer,
+    ) -> Option<&'a render::PostProcessTarget> {
+        match &self.pixel_perfect {
+            Some(pixel_perfect) => Some(&pixel_perfect.target),
+            None if self.draws_offscreen() => {
+                Some(self.offscreen.as_ref().unwrap_or(&renderer.post_process))
             }
+            None => None,
+        }
+    }
+
+    fn target_size(&self, renderer: &render::Renderer) -> (i32, i32) {
+        match &self.pixel_perfect {
+            Some(pixel_perfect) => (pixel_perfect.target.width, pixel_perfect.target.height),
+            None => (renderer.post_process.width, renderer.post_process.height),
+        }
+    }
+
+    /* Recreates the offscreen target at its old size after the context was restored */
+    pub fn restore(&mut self, renderer: &render::Renderer) {
+        if let Some(pixel_perfect) = &self.pixel_perfect {
+            let (width, height) = (pixel_perfect.target.width, pixel_perfect.target.height);
+            self.set_pixel_perfect(renderer, width, height);
+        } else {
+            self.offscreen = None;
+            self.prepare_offscreen(renderer);
+
+            let (width, height) = (renderer.post_process.width, renderer.post_process.height);
+            if let Some(lighting) = &mut self.lighting {
+                lighting.resize(renderer, width, height);
+            }
+            if let Some(post_effects) = &mut self.post_effects {
+                post_effects.resize(renderer, width, height, WebGl2RenderingContext::LINEAR);
+            }
+        }
+        self.draws.clear();
+    }
+
+    /* Whether textures drawn by this camera should be filtered linearly */
+    pub fn smoothing(&self) -> bool {
+        self.pixel_perfect.is_none()
+    }
+
+    /* World offset that moves `world` onto the nearest pixel corner of the pixel-perfect target */
+    pub fn pixel_snap_offset(&self, world: Vec2) -> Vec2 {
+        let Some(pixel_perfect) = &self.pixel_perfect else {
+            return Vec2::ZERO;
+        };
+
+        let view = self.view_matrix();
+        let Some(inverse) = view.invert() else {
+            return Vec2::ZERO;
+        };
+
+        let size = Vec2::new(
+            pixel_perfect.target.width as f32,
+            pixel_perfect.target.height as f32,
+        );
+        let pixel = (view.transform_point(world) + Vec2::ONE) * size / 2.0;
+        let snapped = Vec2::new(pixel.x.round(), pixel.y.round());
+        let snapped_ndc = snapped * Vec2::new(2.0 / size.x, 2.0 / size.y) - Vec2::ONE;
+
+        inverse.transform_point(snapped_ndc) - world
+    }
+
+    /* Snaps the sprite's bounding box corner so its texels line up with target pixels */
+    pub fn pixel_snap(&self, sprite: &Sprite) -> Vec2 {
+        if self.pixel_perfect.is_none() {
+            return Vec2::ZERO;
+        }
+        self.pixel_snap_offset(sprite.bounds().min())
+    }
+
+    /* The screen as this camera sees it, pixel-perfect cameras only cover their letterboxed area */
+    pub fn camera_screen(&self, screen: &Screen) -> Screen {
+        match &self.pixel_perfect {
+            Some(pixel_perfect) => Screen {
+                viewport: pixel_perfect
+                    .viewport(screen.buffer_size.x as i32, screen.buffer_size.y as i32),
+                ..*screen
+            },
+            None => *screen,
+        }
+    }
+
+    /* Only draws pushed after this and before pop_clip land inside the world space rect */
+    pub fn push_clip_rect(&mut self, rect: Rect) {
+        let clip = self.current_clip().with_rect(rect, &self.view_matrix());
+        self.clip_stack.push(Rc::new(clip));
+    }
 
-            x += sprite.x;
-            y += sprite.y;
+    /* Like push_clip_rect with any polygon in world space, drawn through the stencil buffer.
+    Only the outermost clip::MAX_MASKS nested masks apply */
+    pub fn push_mask(&mut self, points: &[Vec2]) {
+        let clip = self.current_clip().with_mask(points, &self.view_matrix());
+        if clip.masks.len() > clip::MAX_MASKS {
+            MASK_LIMIT_WARNING.call_once(|| {
+                console_log!(
+                    "Only {} nested masks are supported, ignoring the rest",
+                    clip::MAX_MASKS
+                );
+            });
+        }
+        self.clip_stack.push(Rc::new(clip));
+    }
 
-            /* Camera transformations */
-            x -= self.scrollx;
-            y -= self.scrolly;
+    /* Circular mask, e.g. for reveal transitions */
+    pub fn push_circle_mask(&mut self, center: Vec2, radius: f32) {
+        self.push_mask(&clip::circle_points(center, radius));
+    }
 
-            x *= self.zoom;
-            y *= self.zoom;
+    pub fn pop_clip(&mut self) {
+        self.clip_stack.pop();
+    }
+
+    fn current_clip(&self) -> Clip {
+        match self.clip_stack.last() {
+            Some(clip) => Clip::clone(clip),
+            None => Clip::default(),
+        }
+    }
+
+    /* Shakes the view by up to `intensity` world units, fading out over `duration` seconds */
+    pub fn shake(&mut self, intensity: f32, duration: f32) -> &mut Shake {
+        self.effects.shake.insert(Shake::new(intensity, duration))
+    }
+
+    /* Covers the view with `color` and fades it away */
+    pub fn flash(&mut self, color: Color, duration: f32) {
+        self.effects.flash = Some(Overlay {
+            color,
+            from_alpha: 1.0,
+            to_alpha: 0.0,
+            timer: Timer::new(duration),
+            hold: false,
+        });
+    }
+
+    /* Fades to `color` and stays covered until fade_in or clear_fade */
+    pub fn fade_out(&mut self, color: Color, duration: f32) {
+        self.effects.fade = Some(Overlay {
+            color,
+            from_alpha: 0.0,
+            to_alpha: 1.0,
+            timer: Timer::new(duration),
+            hold: true,
+        });
+    }
 
-            if self.rotation != 0.0 {
-                let radians = self.rotation * DEG_TO_RADIANS;
-                let cos_theta = radians.cos();
-                let sin_theta = radians.sin();
-                let new_x = x * cos_theta - y * sin_theta;
-                let new_y = x * sin_theta + y * cos_theta;
-                x = new_x;
-                y = new_y;
+    /* Fades from `color` back to the scene */
+    pub fn fade_in(&mut self, color: Color, duration: f32) {
+        self.effects.fade = Some(Overlay {
+            color,
+            from_alpha: 1.0,
+            to_alpha: 0.0,
+            timer: Timer::new(duration),
+            hold: false,
+        });
+    }
+
+    pub fn clear_fade(&mut self) {
+        self.effects.fade = None;
+    }
+
+    pub fn zoom_to(&mut self, zoom: f32, duration: f32, ease: Ease) {
+        self.effects.zoom = Some(Tween::new(self.zoom, zoom, duration, ease));
+    }
+
+    /* Rotation in degrees, like Camera::rotation, turning the shorter way round */
+    pub fn rotate_to(&mut self, rotation: f32, duration: f32, ease: Ease) {
+        self.effects.rotation = Some(Tween::angle(self.rotation, rotation, duration, ease));
+    }
+
+    pub fn center(&self) -> Vec2 {
+        Vec2::new(self.scrollx, self.scrolly)
+    }
+
+    pub fn set_center(&mut self, center: Vec2) {
+        self.scrollx = center.x;
+        self.scrolly = center.y;
+    }
+
+    /* Where the view is actually centered this frame, including shake */
+    pub fn view_center(&self) -> Vec2 {
+        self.center() + self.effects.shake_offset()
+    }
+
+    /* World space to normalized device coordinates */
+    pub fn view_matrix(&self) -> Mat3 {
+        Mat3::scale(Vec2::new(1.0 / self.width, -1.0 / self.height))
+            * Mat3::rotation(to_radians(self.rotation))
+            * Mat3::scale(Vec2::new(self.zoom, self.zoom))
+            * Mat3::translation(-self.view_center())
+    }
+
+    /* World position to CSS pixels on the canvas, for anchoring UI to objects */
+    pub fn world_to_screen(&self, world: Vec2, screen: &Screen) -> Vec2 {
+        self.camera_screen(screen)
+            .ndc_to_css(self.view_matrix().transform_point(world))
+    }
+
+    /* CSS pixels on the canvas (e.g. a pointer event's offsetX/offsetY) to a world position */
+    pub fn screen_to_world(&self, point: Vec2, screen: &Screen) -> Option<Vec2> {
+        let inverse = self.view_matrix().invert()?;
+        Some(inverse.transform_point(self.camera_screen(screen).css_to_ndc(point)))
+    }
+
+    /* Area of the world the camera can see, as an axis aligned box around the (possibly rotated) view */
+    pub fn world_bounds(&self) -> Rect {
+        match self.view_matrix().invert() {
+            Some(inverse) => CLIP_RECT.transform(&inverse),
+            None => Rect::default(),
+        }
+    }
+
+    /* Tests the sprite's rotated and scaled quad in clip space, so camera rotation and zoom are exact */
+    pub fn is_visible(&self, sprite: &Sprite) -> bool {
+        let matrix = self.view_matrix() * sprite.quad_transform();
+        CLIP_RECT.transform(&matrix).intersects(&CLIP_RECT)
+    }
+
+    /* Returns true and counts the sprite as culled when it should not be submitted */
+    pub fn cull(&mut self, sprite: &Sprite) -> bool {
+        if self.culling && !self.is_visible(sprite) {
+            self.cull_stats.culled += 1;
+            return true;
+        }
+
+        self.cull_stats.drawn += 1;
+        false
+    }
+
+    pub fn transform_tris(&self, sprite: &Sprite) -> [f32; QUAD_VERTEX_FLOATS] {
+        self.sprite_vertices(sprite, sprite.vertex_color())
+    }
+
+    /* The sprite's quad for its normal map, with the normal rotation packed into the color */
+    pub fn normal_tris(&self, sprite: &Sprite) -> [f32; QUAD_VERTEX_FLOATS] {
+        let [x_x, x_y, y_x, y_y] =
+            lighting::normal_matrix(sprite.rotation, sprite.scalex, sprite.scaley);
+        self.sprite_vertices(sprite, Color::new(x_x, x_y, y_x, y_y))
+    }
+
+    fn sprite_vertices(&self, sprite: &Sprite, color: Color) -> [f32; QUAD_VERTEX_FLOATS] {
+        let matrix = self.view_matrix()
+            * Mat3::translation(self.pixel_snap(sprite))
+            * sprite.quad_transform();
+
+        let mut vertices = render::BASE_QUAD_VERTS;
+        for i in (0..vertices.len()).step_by(3) {
+            let point = matrix.transform_point(Vec2::new(vertices[i], vertices[i + 1]));
+            vertices[i] = point.x;
+            vertices[i + 1] = point.y;
+        }
+
+        render::quad_vertices(&vertices, sprite.uv_rect, color)
+    }
+
+    /* Batch draws that use the same texture and program, up to what a u16 index buffer can address */
+    pub fn push_quad(
+        &mut self,
+        texture: &WebGlTexture,
+        program: &Rc<Program>,
+        vertices: &[f32],
+        premultiplied: bool,
+    ) {
+        self.push_draw(texture, program, vertices, premultiplied, false);
+    }
+
+    pub fn push_instance(
+        &mut self,
+        texture: &WebGlTexture,
+        program: &Rc<Program>,
+        instance: &[f32],
+        premultiplied: bool,
+    ) {
+        self.push_draw(texture, program, instance, premultiplied, true);
+    }
+
+    fn push_draw(
+        &mut self,
+        texture: &WebGlTexture,
+        program: &Rc<Program>,
+        data: &[f32],
+        premultiplied: bool,
+        instanced: bool,
+    ) {
+        let clip = self.clip_stack.last().cloned();
+        if !extend_batch(&mut self.draws, texture, program, &clip, data) {
+            self.draws.push(DrawCall {
+                texture: texture.clone(),
+                program: program.clone(),
+                vertices: data.to_vec(),
+                count: 1,
+                instanced,
+                premultiplied,
+                clip,
+            });
+        }
+    }
+
+    /* Normals for lit cameras, pushed for every sprite in the order they are drawn in */
+    pub fn push_normal_quad(&mut self, texture: &WebGlTexture, vertices: &[f32]) {
+        if let Some(lighting) = &self.lighting {
+            let program = lighting.normal_program.clone();
+            self.push_normal_draw(texture, program, vertices);
+        }
+    }
+
+    /* Faces the viewer where `texture`, the sprite's own, isn't transparent */
+    pub fn push_flat_normal_quad(&mut self, texture: &WebGlTexture, vertices: &[f32]) {
+        if let Some(lighting) = &self.lighting {
+            let program = lighting.flat_normal_program.clone();
+            self.push_normal_draw(texture, program, vertices);
+        }
+    }
+
+    fn push_normal_draw(&mut self, texture: &WebGlTexture, program: Rc<Program>, vertices: &[f32]) {
+        let clip = self.clip_stack.last().cloned();
+        if let Some(lighting) = &mut self.lighting {
+            let draws = &mut lighting.normal_draws;
+            if !extend_batch(draws, texture, &program, &clip, vertices) {
+                draws.push(DrawCall {
+                    texture: texture.clone(),
+                    program,
+                    vertices: vertices.to_vec(),
+                    count: 1,
+                    instanced: false,
+                    premultiplied: false,
+                    clip,
+                });
             }
+        }
+    }
 
-            x /= self.width;
-            y /= self.height;
+    fn bind_instance_uniforms(&self, renderer: &render::Renderer, program: &Program) {
+        let context = &renderer.context;
 
-            vertices[i] = x;
-            vertices[i + 1] = -y;
+        if let Some(location) = program.uniform_location("camera_size") {
+            context.uniform2f(Some(&location), self.width, self.height);
+        }
+        if let Some(location) = program.uniform_location("camera_scroll") {
+            let center = self.view_center();
+            context.uniform2f(Some(&location), center.x, center.y);
         }
+        if let Some(location) = program.uniform_location("camera_zoom") {
+            context.uniform1f(Some(&location), self.zoom);
+        }
+        if let Some(location) = program.uniform_location("camera_rotation") {
+            context.uniform1f(Some(&location), to_radians(self.rotation));
+        }
+    }
+
+    /* What this camera rendered last frame. Offscreen cameras return their target before the
+    post-process shader, otherwise the canvas is read, which only works during the frame */
+    pub fn capture(&self, renderer: &render::Renderer) -> Result<DecodedImage, String> {
+        if let Some(output) = self.post_effects.as_ref().and_then(PostEffects::output) {
+            return renderer.capture_target(output);
+        }
+        if let Some(lighting) = &self.lighting {
+            return renderer.capture_target(&lighting.targets.lit);
+        }
+
+        match self.target(renderer) {
+            Some(target) => renderer.capture_target(target),
+            None => renderer.screenshot(),
+        }
+    }
 
-        vertices.to_vec()
+    /* Flash and fade overlays, drawn before post processing so they go through the camera shader */
+    fn draw_overlays(&self, renderer: &render::Renderer) {
+        if let (Some(program), Some(texture)) = (&renderer.base_program, &renderer.white_texture) {
+            renderer.set_alpha_blend(true);
+            for color in self.effects.overlays() {
+                let color = color.premultiplied();
+                renderer.use_program(program);
+                renderer.use_texture(texture);
+
+                let vertices = render::quad_vertices(&BASE_QUAD_VERTS, Rect::UNIT, color);
+                renderer.draw_quads(&renderer.quads_buffer, program, &vertices, 1);
+            }
+        }
     }
 
     pub fn clear_draws(&mut self) {
         self.draws.clear();
+        self.clip_stack.clear();
+        if let Some(lighting) = &mut self.lighting {
+            lighting.normal_draws.clear();
+        }
+        self.last_cull_stats = std::mem::take(&mut self.cull_stats);
+    }
+}
+
+/* Appends to the last draw when it uses the same texture, program and clip, false when a new
+batch has to start. Unbounded, Renderer::draw_quads splits batches at the u16 index limit */
+fn extend_batch(
+    draws: &mut [DrawCall],
+    texture: &WebGlTexture,
+    program: &Rc<Program>,
+    clip: &Option<Rc<Clip>>,
+    data: &[f32],
+) -> bool {
+    match draws.last_mut() {
+        Some(last)
+            if Rc::ptr_eq(&last.program, program)
+                && last.texture == *texture
+                && same_clip(&last.clip, clip) =>
+        {
+            last.vertices.extend_from_slice(data);
+            last.count += 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn same_clip(first: &Option<Rc<Clip>>, second: &Option<Rc<Clip>>) -> bool {
+    match (first, second) {
+        (Some(first), Some(second)) => Rc::ptr_eq(first, second),
+        (None, None) => true,
+        _ => false,
     }
 }
 
 impl Object for Camera {
-    fn update(&mut self, delta_time: f32) {}
+    fn update(&mut self, delta_time: f32) {
+        render::with_renderer(|renderer| self.prepare_offscreen(renderer));
+        self.effects.update(delta_time);
+        if let Some(post_effects) = &mut self.post_effects {
+            post_effects.update(delta_time);
+        }
+        if let Some(tween) = &self.effects.zoom {
+            self.zoom = tween.value();
+        }
+        if let Some(tween) = &self.effects.rotation {
+            self.rotation = tween.value();
+        }
+        for tween in [&mut self.effects.zoom, &mut self.effects.rotation] {
+            if tween.as_ref().is_some_and(|tween| tween.timer.finished()) {
+                *tween = None;
+            }
+        }
+
+        let center = self.center();
+        if let Some(follow) = &mut self.follow {
+            if let Some(center) = follow.step(center, delta_time) {
+                self.set_center(center);
+            }
+        }
+
+        if let Some(bounds) = self.bounds {
+            let view = self.world_bounds();
+            let half_extents = Vec2::new(view.width / 2.0, view.height / 2.0);
+            self.set_center(follow::clamp_to_bounds(
+                self.center(),
+                half_extents,
+                &bounds,
+            ));
+        }
+    }
 
     fn draw(&self, renderer: &render::Renderer) {
+        let target = self.target(renderer);
+
         /* Bind postproccess buffer */
-        if let Some(program) = &self.shader {
-            renderer.context.bind_framebuffer(
+        if let Some(target) = target {
+            renderer.bind_framebuffer(
                 WebGl2RenderingContext::FRAMEBUFFER,
-                Some(&renderer.post_process.frame_buffer_store),
+                Some(&target.frame_buffer_store),
             );
+            renderer.set_viewport(0, 0, target.width, target.height);
 
             renderer.clear_color(0.0, 0.0, 0.0, 0.0);
         }
 
+        let sampler = (!self.smoothing()).then_some(&renderer.nearest_sampler);
+        renderer.use_sampler(0, sampler);
+
+        let mut clip = None;
         for draw in &self.draws {
+            if !same_clip(&clip, &draw.clip) {
+                renderer.set_clip(draw.clip.as_deref());
+                clip = draw.clip.clone();
+            }
+
+            renderer.set_alpha_blend(draw.premultiplied);
             renderer.use_program(&draw.program);
             renderer.use_texture(&draw.texture);
 
-            renderer
-                .quads_buffer
-                .upload_vertices(&renderer.context, &draw.vertices);
-
-            let mut uvs = Vec::with_capacity(BASE_QUAD_UVS.len() * draw.count);
-            for _ in 0..draw.count {
-                uvs.extend_from_slice(&BASE_QUAD_UVS);
+            if draw.instanced {
+                self.bind_instance_uniforms(renderer, &draw.program);
+                renderer.draw_instances(
+                    &renderer.quads_buffer,
+                    &draw.program,
+                    &draw.vertices,
+                    draw.count,
+                );
+            } else {
+                renderer.draw_quads(
+                    &renderer.quads_buffer,
+                    &draw.program,
+                    &draw.vertices,
+                    draw.count,
+                );
             }
-            renderer.quads_buffer.upload_uvs(&renderer.context, &uvs);
-
-            let mut indices = Vec::with_capacity(BASE_QUAD_INDICES.len() * draw.count);
+        }
 
-            for quad in 0..draw.count {
-                let base = (quad * 4) as u16;
-                for &idx in BASE_QUAD_INDICES.iter() {
-                    indices.push(idx + base);
-                }
-            }
-            renderer
-                .quads_buffer
-                .upload_indices(&renderer.context, &indices);
+        /* Overlays and the blit below must cover the whole view, and sample as usual */
+        renderer.set_clip(None);
+        renderer.use_sampler(0, None);
 
-            renderer.draw_triangles(indices.len() as i32);
+        /* Lit cameras draw them after lighting instead, a flash shouldn't be darkened */
+        if self.lighting.is_none() {
+            self.draw_overlays(renderer);
         }
 
         /* Draw postproccess buffer */
 
-        if let Some(program) = &self.shader {
+        if let Some(target) = target {
             /* MSAA */
+            renderer.resolve_target(target);
 
-            renderer.context.bind_framebuffer(
-                WebGl2RenderingContext::READ_FRAMEBUFFER,
-                Some(&renderer.post_process.frame_buffer_store),
-            );
-            renderer.context.bind_framebuffer(
-                WebGl2RenderingContext::DRAW_FRAMEBUFFER,
-                Some(&renderer.post_process.frame_buffer_draw),
-            );
-
-            renderer.context.blit_framebuffer(
-                0,
-                0,
-                self.width as i32,
-                self.height as i32,
-                0,
-                0,
-                self.width as i32,
-                self.height as i32,
-                WebGl2RenderingContext::COLOR_BUFFER_BIT,
-                WebGl2RenderingContext::NEAREST,
-            );
+            let mut texture = &target.texture;
+            if let Some(lighting) = &self.lighting {
+                lighting.render(renderer, &self.view_matrix(), &target.texture, sampler);
+                self.draw_overlays(renderer);
+                texture = &lighting.targets.lit.texture;
+            }
+            if let Some(post_effects) = &self.post_effects {
+                texture = post_effects.render(renderer, texture);
+            }
 
-            renderer
-                .context
-                .bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+            renderer.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
 
             /* Render camera texture to screen */
 
+            let canvas_width = renderer.context.drawing_buffer_width();
+            let canvas_height = renderer.context.drawing_buffer_height();
+            let viewport = match &self.pixel_perfect {
+                Some(pixel_perfect) => pixel_perfect.viewport(canvas_width, canvas_height),
+                None => Rect::new(0.0, 0.0, canvas_width as f32, canvas_height as f32),
+            };
+            renderer.set_viewport(
+                viewport.x as i32,
+                viewport.y as i32,
+                viewport.width as i32,
+                viewport.height as i32,
+            );
+
+            let program = match (&self.shader, &renderer.base_program) {
+                (Some(program), _) | (None, Some(program)) => program,
+                (None, None) => return,
+            };
+            /* Everything was blended into the target as premultiplied */
+            renderer.set_alpha_blend(true);
             renderer.use_program(program);
-            renderer.use_texture(&renderer.post_process.texture);
-
-            renderer
-                .quads_buffer
-                .upload_vertices(&renderer.context, &BASE_QUAD_VERTS);
-            renderer
-                .quads_buffer
-                .upload_uvs(&renderer.context, &BASE_QUAD_UVS);
-            renderer
-                .quads_buffer
-                .upload_indices(&renderer.context, &BASE_QUAD_INDICES);
-
-            renderer.draw_triangles(BASE_QUAD_INDICES.len() as i32);
+            renderer.use_texture(texture);
+
+            let vertices = render::quad_vertices(&BASE_QUAD_VERTS, Rect::UNIT, Color::WHITE);
+            renderer.draw_quads(&renderer.quads_buffer, program, &vertices, 1);
+
+            renderer.set_viewport(0, 0, canvas_width, canvas_height);
         }
     }
 }
@@ -0,0 +1,147 @@
+#![allow(unused)]
+
+use wasm_bindgen::prelude::*;
+use web_sys::{Blob, BlobPropertyBag, HtmlAnchorElement, Url};
+
+use crate::decode::DecodedImage;
+
+/* readPixels returns the bottom row first, images want the top row first */
+pub fn flip_rows(pixels: &mut [u8], width: u32, height: u32) {
+    let row_bytes = width as usize * 4;
+    let height = height as usize;
+    for row in 0..height / 2 {
+        let (top, bottom) = pixels.split_at_mut((height - row - 1) * row_bytes);
+        top[row * row_bytes..(row + 1) * row_bytes].swap_with_slice(&mut bottom[..row_bytes]);
+    }
+}
+
+/* Everything is rendered premultiplied, PNG and GIF store straight alpha */
+pub fn unpremultiply(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha == 0 || alpha == 255 {
+            continue;
+        }
+        for channel in &mut pixel[..3] {
+            *channel = ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8;
+        }
+    }
+}
+
+pub fn encode_png(image: &DecodedImage) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut bytes, image.width, image.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder
+        .write_header()
+        .map_err(|error| format!("PNG encoding error: {}", error))?;
+    writer
+        .write_image_data(&image.pixels)
+        .map_err(|error| format!("PNG encoding error: {}", error))?;
+    writer
+        .finish()
+        .map_err(|error| format!("PNG encoding error: {}", error))?;
+
+    Ok(bytes)
+}
+
+/* Hands the bytes to the browser as a file download */
+pub fn download(bytes: &[u8], filename: &str, mime_type: &str) -> Result<(), JsValue> {
+    let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window"))?;
+    let document = window
+        .document()
+        .ok_or_else(|| JsValue::from_str("No document"))?;
+
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let options = BlobPropertyBag::new();
+    options.set_type(mime_type);
+    let blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+
+    let anchor: HtmlAnchorElement = document.create_element("a")?.dyn_into()?;
+    anchor.set_href(&url);
+    anchor.set_download(filename);
+    anchor.click();
+
+    /* Revoking right away can cancel the download in some browsers */
+    let revoke = Closure::once_into_js(move || {
+        Url::revoke_object_url(&url).ok();
+    });
+    window.set_timeout_with_callback_and_timeout_and_arguments_0(revoke.unchecked_ref(), 0)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(height: u32) -> Vec<u8> {
+        (0..height).flat_map(|row| [row as u8; 8]).collect()
+    }
+
+    #[test]
+    fn flips_even_and_odd_heights() {
+        let mut pixels = rows(4);
+        flip_rows(&mut pixels, 2, 4);
+        assert_eq!(pixels, [[3; 8], [2; 8], [1; 8], [0; 8]].concat());
+
+        /* The middle row stays put */
+        let mut pixels = rows(3);
+        flip_rows(&mut pixels, 2, 3);
+        assert_eq!(pixels, [[2; 8], [1; 8], [0; 8]].concat());
+
+        let mut pixels = rows(1);
+        flip_rows(&mut pixels, 2, 1);
+        assert_eq!(pixels, [0; 8]);
+    }
+
+    #[test]
+    fn flipping_twice_restores_the_image() {
+        let original: Vec<u8> = (0..5 * 7 * 4).map(|value| value as u8).collect();
+        let mut pixels = original.clone();
+        flip_rows(&mut pixels, 5, 7);
+        assert_ne!(pixels, original);
+        flip_rows(&mut pixels, 5, 7);
+        assert_eq!(pixels, original);
+    }
+
+    #[test]
+    fn unpremultiplies_partial_alpha() {
+        let mut pixels = vec![
+            64, 32, 0, 128, /* half transparent */
+            10, 20, 30, 255, /* opaque stays */
+            5, 5, 5, 0, /* fully transparent stays */
+            200, 10, 1, 100, /* rounds to nearest and clamps */
+        ];
+        unpremultiply(&mut pixels);
+        assert_eq!(
+            pixels,
+            [128, 64, 0, 128, 10, 20, 30, 255, 5, 5, 5, 0, 255, 26, 3, 100]
+        );
+    }
+
+    #[test]
+    fn unpremultiply_undoes_premultiplying() {
+        for alpha in 1..=255u32 {
+            for color in [0u32, 1, 77, 128, 254, 255] {
+                let premultiplied = ((color * alpha + 127) / 255) as u8;
+                let mut pixel = [premultiplied, 0, 0, alpha as u8];
+                unpremultiply(&mut pixel);
+                let error = (pixel[0] as i32 - color as i32).unsigned_abs();
+                /* Low alphas can't keep every color apart */
+                assert!(error <= 255 / alpha + 1, "{} {} {:?}", color, alpha, pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_png_that_decodes_back() {
+        let image = DecodedImage::new(2, 1, vec![255, 0, 0, 255, 0, 0, 255, 128]);
+        let bytes = encode_png(&image).unwrap();
+        assert_eq!(crate::decode::decode_png(&bytes).unwrap(), image);
+    }
+}
@@ -0,0 +1,248 @@
+#![allow(unused)]
+
+use std::rc::Rc;
+
+use crate::math::{Color, Mat3, Rect, Vec2};
+use crate::render::{self, QUAD_VERTEX_FLOATS};
+
+/* Each nested mask gets its own stencil bit */
+pub const MAX_MASKS: usize = 8;
+
+/* Clip rects within this much of axis aligned (in clip space) still use the scissor test */
+const AXIS_EPSILON: f32 = 1e-4;
+
+/* Circles are split into segments about this many world units long */
+const CIRCLE_SEGMENT_LENGTH: f32 = 8.0;
+const MIN_CIRCLE_SEGMENTS: usize = 12;
+const MAX_CIRCLE_SEGMENTS: usize = 128;
+
+/* Everything that limits where a draw call lands, already transformed into clip space. Nested
+clips hold their parents' rect and masks as well so a draw only needs the innermost one */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Clip {
+    /* Axis aligned rect drawn with the scissor test */
+    pub scissor: Option<Rect>,
+    /* Polygons written into the stencil buffer, drawing only happens inside all of them */
+    pub masks: Vec<Rc<[Vec2]>>,
+}
+
+impl Clip {
+    /* Adds a world space rect. It stays a scissor rect while the view isn't rotated and
+    becomes a mask when it is, since the scissor test can't rotate */
+    pub fn with_rect(&self, rect: Rect, view: &Mat3) -> Clip {
+        let corners = rect.corners().map(|corner| view.transform_point(corner));
+        if !is_axis_aligned(&corners) {
+            return self.with_mask(&rect.corners(), view);
+        }
+
+        let bounds = Rect::from_points(&corners);
+        Clip {
+            scissor: Some(match self.scissor {
+                Some(scissor) => scissor.intersection(&bounds),
+                None => bounds,
+            }),
+            masks: self.masks.clone(),
+        }
+    }
+
+    /* Adds a world space polygon, which can be concave since masks are filled even-odd */
+    pub fn with_mask(&self, points: &[Vec2], view: &Mat3) -> Clip {
+        let mut masks = self.masks.clone();
+        masks.push(
+            points
+                .iter()
+                .map(|point| view.transform_point(*point))
+                .collect(),
+        );
+
+        Clip {
+            scissor: self.scissor,
+            masks,
+        }
+    }
+}
+
+/* Whether four corners in order form a rect with horizontal and vertical edges */
+pub fn is_axis_aligned(corners: &[Vec2; 4]) -> bool {
+    (0..4).all(|index| {
+        let edge = corners[(index + 1) % 4] - corners[index];
+        edge.x.abs() <= AXIS_EPSILON || edge.y.abs() <= AXIS_EPSILON
+    })
+}
+
+/* Clip space rect to a scissor box in pixels of the `viewport` (x, y, width, height), rounded
+outwards so partially covered pixels are kept */
+pub fn scissor_box(ndc: Rect, viewport: (i32, i32, i32, i32)) -> (i32, i32, i32, i32) {
+    let (x, y, width, height) = viewport;
+    let to_pixel =
+        |ndc: f32, origin: i32, size: i32| origin as f32 + (ndc + 1.0) / 2.0 * size as f32;
+
+    let left = to_pixel(ndc.x, x, width).floor() as i32;
+    let bottom = to_pixel(ndc.y, y, height).floor() as i32;
+    let right = to_pixel(ndc.x + ndc.width, x, width).ceil() as i32;
+    let top = to_pixel(ndc.y + ndc.height, y, height).ceil() as i32;
+
+    (left, bottom, (right - left).max(0), (top - bottom).max(0))
+}
+
+/* Points around a circle in world space, with more segments for bigger circles */
+pub fn circle_points(center: Vec2, radius: f32) -> Vec<Vec2> {
+    let circumference = std::f32::consts::TAU * radius.abs();
+    let segments = ((circumference / CIRCLE_SEGMENT_LENGTH).ceil() as usize)
+        .clamp(MIN_CIRCLE_SEGMENTS, MAX_CIRCLE_SEGMENTS);
+
+    (0..segments)
+        .map(|segment| {
+            let angle = segment as f32 / segments as f32 * std::f32::consts::TAU;
+            center + Vec2::new(angle.cos(), angle.sin()) * radius
+        })
+        .collect()
+}
+
+/* Triangle fan around the first point, each triangle padded into a degenerate quad so masks go
+through the regular quad batches. Inverting the stencil per triangle fills it even-odd */
+pub fn fan_quads(points: &[Vec2]) -> Vec<f32> {
+    let mut vertices = Vec::with_capacity(points.len().saturating_sub(2) * QUAD_VERTEX_FLOATS);
+    for index in 1..points.len().saturating_sub(1) {
+        let (first, second, third) = (points[0], points[index], points[index + 1]);
+        let positions = [
+            first.x, first.y, 0.0, //
+            second.x, second.y, 0.0, //
+            third.x, third.y, 0.0, //
+            third.x, third.y, 0.0, //
+        ];
+        vertices.extend_from_slice(&render::quad_vertices(&positions, Rect::UNIT, Color::WHITE));
+    }
+    vertices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::VERTEX_FLOATS;
+
+    fn close(first: Vec2, second: Vec2) -> bool {
+        (first - second).length() < 1e-4
+    }
+
+    #[test]
+    fn rects_stay_scissored_while_axis_aligned() {
+        let view = Mat3::scale(Vec2::new(0.5, 0.5));
+        let clip = Clip::default().with_rect(Rect::new(-1.0, -1.0, 2.0, 2.0), &view);
+        assert_eq!(clip.scissor, Some(Rect::new(-0.5, -0.5, 1.0, 1.0)));
+        assert!(clip.masks.is_empty());
+
+        /* Nested rects intersect */
+        let nested = clip.with_rect(Rect::new(0.0, -4.0, 4.0, 4.0), &view);
+        assert_eq!(nested.scissor, Some(Rect::new(0.0, -0.5, 0.5, 0.5)));
+
+        /* A quarter turn keeps edges horizontal and vertical */
+        let quarter = Mat3::rotation(std::f32::consts::FRAC_PI_2);
+        let turned = Clip::default().with_rect(Rect::new(0.0, 0.0, 0.5, 0.25), &quarter);
+        assert!(turned.masks.is_empty());
+        let scissor = turned.scissor.unwrap();
+        assert!(close(scissor.min(), Vec2::new(-0.25, 0.0)));
+    }
+
+    #[test]
+    fn rotated_rects_become_masks() {
+        let view = Mat3::rotation(0.3);
+        let parent = Clip::default().with_rect(Rect::new(-0.5, -0.5, 1.0, 1.0), &Mat3::IDENTITY);
+        let clip = parent.with_rect(Rect::new(0.0, 0.0, 1.0, 1.0), &view);
+
+        assert_eq!(clip.scissor, parent.scissor);
+        assert_eq!(clip.masks.len(), 1);
+        let corners = Rect::new(0.0, 0.0, 1.0, 1.0).corners();
+        for (point, corner) in clip.masks[0].iter().zip(corners) {
+            assert!(close(*point, view.transform_point(corner)));
+        }
+    }
+
+    #[test]
+    fn nested_masks_keep_their_parents() {
+        let view = Mat3::translation(Vec2::new(1.0, 0.0));
+        let triangle = [Vec2::ZERO, Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)];
+
+        let outer = Clip::default().with_mask(&triangle, &view);
+        let inner = outer.with_mask(&triangle, &Mat3::IDENTITY);
+        assert_eq!(inner.masks.len(), 2);
+        assert!(Rc::ptr_eq(&inner.masks[0], &outer.masks[0]));
+        assert_eq!(outer.masks[0][0], Vec2::new(1.0, 0.0));
+        assert_eq!(inner.masks[1][0], Vec2::ZERO);
+    }
+
+    #[test]
+    fn detects_axis_aligned_corners() {
+        assert!(is_axis_aligned(&Rect::UNIT.corners()));
+
+        let skewed = Rect::UNIT
+            .corners()
+            .map(|corner| Mat3::rotation(0.01).transform_point(corner));
+        assert!(!is_axis_aligned(&skewed));
+    }
+
+    #[test]
+    fn scissor_boxes_round_outwards() {
+        let viewport = (0, 0, 800, 600);
+        assert_eq!(
+            scissor_box(Rect::new(-1.0, -1.0, 2.0, 2.0), viewport),
+            (0, 0, 800, 600)
+        );
+        assert_eq!(
+            scissor_box(Rect::new(-0.5, -0.5, 1.0, 1.0), viewport),
+            (200, 150, 400, 300)
+        );
+
+        /* Half a pixel in to half a pixel past the next, both pixels are kept */
+        let (x, y, width, height) = scissor_box(Rect::new(-0.99875, 0.0, 0.0025, 0.001), viewport);
+        assert_eq!((x, y, width, height), (0, 300, 2, 1));
+
+        /* Letterboxed viewports are offset */
+        assert_eq!(
+            scissor_box(Rect::new(-1.0, -1.0, 1.0, 2.0), (100, 50, 200, 100)),
+            (100, 50, 100, 100)
+        );
+
+        assert_eq!(
+            scissor_box(Rect::new(0.5, 0.5, -1.0, -1.0), viewport),
+            (600, 450, 0, 0)
+        );
+    }
+
+    #[test]
+    fn circles_scale_their_segments() {
+        let center = Vec2::new(3.0, -2.0);
+        let small = circle_points(center, 1.0);
+        assert_eq!(small.len(), MIN_CIRCLE_SEGMENTS);
+
+        let medium = circle_points(center, 100.0);
+        assert_eq!(medium.len(), 79);
+
+        assert_eq!(circle_points(center, 1e6).len(), MAX_CIRCLE_SEGMENTS);
+
+        for point in medium {
+            assert!(((point - center).length() - 100.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn fans_one_degenerate_quad_per_triangle() {
+        let points = circle_points(Vec2::ZERO, 1.0);
+        let vertices = fan_quads(&points);
+        assert_eq!(vertices.len(), (points.len() - 2) * QUAD_VERTEX_FLOATS);
+
+        let position = |quad: usize, corner: usize| {
+            let offset = quad * QUAD_VERTEX_FLOATS + corner * VERTEX_FLOATS;
+            Vec2::new(vertices[offset], vertices[offset + 1])
+        };
+        for quad in 0..points.len() - 2 {
+            assert_eq!(position(quad, 0), points[0]);
+            assert_eq!(position(quad, 1), points[quad + 1]);
+            assert_eq!(position(quad, 2), points[quad + 2]);
+            assert_eq!(position(quad, 3), points[quad + 2]);
+        }
+
+        assert!(fan_quads(&points[..2]).is_empty());
+        assert!(fan_quads(&[]).is_empty());
+    }
+}
@@ -0,0 +1,513 @@
+#![allow(unused)]
+
+use std::io::Cursor;
+
+/* Image decoding in Rust, so textures don't need an HtmlImageElement and run natively */
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const QOI_MAGIC: [u8; 4] = *b"qoif";
+
+/* Browsers play GIF delays this short at 100ms, so do the same */
+const GIF_MIN_DELAY: u16 = 2;
+const GIF_DEFAULT_DELAY: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Qoi,
+}
+
+impl ImageFormat {
+    /* Sniffs the format from the file's magic bytes */
+    pub fn detect(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            Some(ImageFormat::Png)
+        } else if bytes.starts_with(&QOI_MAGIC) {
+            Some(ImageFormat::Qoi)
+        } else {
+            None
+        }
+    }
+}
+
+/* Tightly packed 8-bit RGBA, top row first like the browser uploads images */
+#[derive(Clone, PartialEq, Debug)]
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl DecodedImage {
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> DecodedImage {
+        DecodedImage {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let index = (y as usize * self.width as usize + x as usize) * 4;
+        self.pixels[index..index + 4].try_into().unwrap()
+    }
+}
+
+pub struct GifFrame {
+    /* The whole composited canvas for this frame, not just the changed region */
+    pub image: DecodedImage,
+    /* Seconds */
+    pub delay: f32,
+}
+
+pub struct DecodedGif {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<GifFrame>,
+    /* Times the frames play through, None repeats forever */
+    pub loop_count: Option<u32>,
+}
+
+/* Composites every frame onto the logical screen, applying transparency and disposal methods */
+pub fn decode_gif(bytes: &[u8]) -> Result<DecodedGif, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+
+    let mut decoder = options
+        .read_info(Cursor::new(bytes))
+        .map_err(|error| format!("GIF decoding error: {}", error))?;
+
+    let width = decoder.width() as usize;
+    let height = decoder.height() as usize;
+    let mut canvas = vec![0; width * height * 4];
+    let mut frames = Vec::new();
+
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|error| format!("GIF decoding error: {}", error))?
+    {
+        let previous = match frame.dispose {
+            gif::DisposalMethod::Previous => Some(canvas.clone()),
+            _ => None,
+        };
+
+        /* Frames can hang off the logical screen, only the overlapping part is drawn */
+        let left = frame.left as usize;
+        let top = frame.top as usize;
+        let frame_width = frame.width as usize;
+        let columns = frame_width.min(width.saturating_sub(left));
+        let rows = (frame.height as usize).min(height.saturating_sub(top));
+
+        for y in 0..rows {
+            for x in 0..columns {
+                let source = (y * frame_width + x) * 4;
+                let pixel = &frame.buffer[source..source + 4];
+                if pixel[3] == 0 {
+                    continue;
+                }
+
+                let destination = ((top + y) * width + left + x) * 4;
+                canvas[destination..destination + 4].copy_from_slice(pixel);
+            }
+        }
+
+        let delay = if frame.delay < GIF_MIN_DELAY {
+            GIF_DEFAULT_DELAY
+        } else {
+            frame.delay as f32 / 100.0
+        };
+        frames.push(GifFrame {
+            image: DecodedImage::new(width as u32, height as u32, canvas.clone()),
+            delay,
+        });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in 0..rows {
+                    let start = ((top + y) * width + left) * 4;
+                    canvas[start..start + columns * 4].fill(0);
+                }
+            }
+            gif::DisposalMethod::Previous => {
+                if let Some(previous) = previous {
+                    canvas = previous;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(String::from("GIF has no frames"));
+    }
+
+    Ok(DecodedGif {
+        width: width as u32,
+        height: height as u32,
+        frames,
+        loop_count: loop_count(decoder.repeat()),
+    })
+}
+
+/* The NETSCAPE extension counts repeats after the first play, and files without it play once */
+pub fn loop_count(repeat: gif::Repeat) -> Option<u32> {
+    match repeat {
+        gif::Repeat::Infinite => None,
+        gif::Repeat::Finite(repeats) => Some(repeats as u32 + 1),
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<DecodedImage, String> {
+    match ImageFormat::detect(bytes) {
+        Some(ImageFormat::Png) => decode_png(bytes),
+        Some(ImageFormat::Qoi) => decode_qoi(bytes),
+        None => Err(String::from("Unknown image format")),
+    }
+}
+
+pub fn decode_png(bytes: &[u8]) -> Result<DecodedImage, String> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder
+        .read_info()
+        .map_err(|error| format!("PNG decoding error: {}", error))?;
+    let buffer_size = reader
+        .output_buffer_size()
+        .ok_or_else(|| String::from("PNG is too large"))?;
+
+    let mut buffer = vec![0; buffer_size];
+    let info = reader
+        .next_frame(&mut buffer)
+        .map_err(|error| format!("PNG decoding error: {}", error))?;
+    buffer.truncate(info.buffer_size());
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .flat_map(|ga| [ga[0], ga[0], ga[0], ga[1]])
+            .collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        png::ColorType::Indexed => {
+            return Err(String::from("PNG palette was not expanded"));
+        }
+    };
+
+    Ok(DecodedImage::new(info.width, info.height, pixels))
+}
+
+pub fn decode_qoi(bytes: &[u8]) -> Result<DecodedImage, String> {
+    let mut decoder = qoi::Decoder::new(bytes)
+        .map_err(|error| format!("QOI decoding error: {}", error))?
+        .with_channels(qoi::Channels::Rgba);
+
+    let header = *decoder.header();
+    let pixels = decoder
+        .decode_to_vec()
+        .map_err(|error| format!("QOI decoding error: {}", error))?;
+
+    Ok(DecodedImage::new(header.width, header.height, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    fn encode_png(
+        width: u32,
+        height: u32,
+        color: png::ColorType,
+        depth: png::BitDepth,
+        palette: Option<(&[u8], &[u8])>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        if let Some((palette, alphas)) = palette {
+            encoder.set_palette(palette.to_vec());
+            encoder.set_trns(alphas.to_vec());
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    fn png_8bit(width: u32, height: u32, color: png::ColorType, data: &[u8]) -> Vec<u8> {
+        encode_png(width, height, color, png::BitDepth::Eight, None, data)
+    }
+
+    #[test]
+    fn detects_formats_by_magic() {
+        let png = png_8bit(1, 1, png::ColorType::Rgba, &RED);
+        let qoi = qoi::encode_to_vec(RED, 1, 1).unwrap();
+
+        assert_eq!(ImageFormat::detect(&png), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::detect(&qoi), Some(ImageFormat::Qoi));
+        assert_eq!(ImageFormat::detect(b"GIF89a"), None);
+        assert_eq!(ImageFormat::detect(&[]), None);
+        assert_eq!(
+            decode(b"GIF89a").unwrap_err(),
+            "Unknown image format".to_string()
+        );
+    }
+
+    #[test]
+    fn decodes_png_rgba() {
+        let pixels = [RED, GREEN, BLUE, [10, 20, 30, 40]].concat();
+        let bytes = png_8bit(2, 2, png::ColorType::Rgba, &pixels);
+
+        let image = decode(&bytes).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.pixels, pixels);
+        assert_eq!(image.pixel(1, 1), [10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn expands_png_rgb_and_grayscale_to_rgba() {
+        let rgb = png_8bit(2, 1, png::ColorType::Rgb, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            decode_png(&rgb).unwrap().pixels,
+            [1, 2, 3, 255, 4, 5, 6, 255]
+        );
+
+        let gray = png_8bit(2, 1, png::ColorType::Grayscale, &[7, 8]);
+        assert_eq!(
+            decode_png(&gray).unwrap().pixels,
+            [7, 7, 7, 255, 8, 8, 8, 255]
+        );
+
+        let gray_alpha = png_8bit(1, 1, png::ColorType::GrayscaleAlpha, &[9, 128]);
+        assert_eq!(decode_png(&gray_alpha).unwrap().pixels, [9, 9, 9, 128]);
+    }
+
+    #[test]
+    fn normalizes_png_palettes_and_depths() {
+        let palette = [255, 0, 0, 0, 0, 255];
+        let indexed = encode_png(
+            2,
+            1,
+            png::ColorType::Indexed,
+            png::BitDepth::Eight,
+            Some((&palette, &[255, 0])),
+            &[0, 1],
+        );
+        assert_eq!(
+            decode_png(&indexed).unwrap().pixels,
+            [RED, [0, 0, 255, 0]].concat()
+        );
+
+        /* 16-bit samples are big endian, only the high byte survives */
+        let wide = encode_png(
+            1,
+            1,
+            png::ColorType::Grayscale,
+            png::BitDepth::Sixteen,
+            None,
+            &[0xAB, 0xCD],
+        );
+        assert_eq!(decode_png(&wide).unwrap().pixels, [0xAB, 0xAB, 0xAB, 255]);
+    }
+
+    #[test]
+    fn rejects_truncated_png() {
+        let bytes = png_8bit(2, 2, png::ColorType::Rgba, &[0; 16]);
+        let error = decode_png(&bytes[..bytes.len() - 20]).unwrap_err();
+        assert!(error.starts_with("PNG decoding error"), "{}", error);
+    }
+
+    #[test]
+    fn decodes_qoi() {
+        let pixels = [RED, GREEN, [1, 2, 3, 4]].concat();
+        let bytes = qoi::encode_to_vec(&pixels, 3, 1).unwrap();
+        let image = decode(&bytes).unwrap();
+        assert_eq!((image.width, image.height), (3, 1));
+        assert_eq!(image.pixels, pixels);
+
+        /* RGB files come out opaque */
+        let bytes = qoi::encode_to_vec([9, 8, 7, 6, 5, 4], 2, 1).unwrap();
+        assert_eq!(
+            decode_qoi(&bytes).unwrap().pixels,
+            [9, 8, 7, 255, 6, 5, 4, 255]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_qoi() {
+        let bytes = qoi::encode_to_vec([RED, GREEN].concat(), 2, 1).unwrap();
+        let error = decode_qoi(&bytes[..10]).unwrap_err();
+        assert!(error.starts_with("QOI decoding error"), "{}", error);
+    }
+
+    /* Index 0 is the transparent color in every frame */
+    const GIF_PALETTE: [u8; 12] = [0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+
+    struct GifFixtureFrame {
+        left: u16,
+        top: u16,
+        width: u16,
+        height: u16,
+        indices: Vec<u8>,
+        dispose: gif::DisposalMethod,
+        delay: u16,
+    }
+
+    fn encode_gif(width: u16, height: u16, frames: &[GifFixtureFrame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut bytes, width, height, &GIF_PALETTE).unwrap();
+            for frame in frames {
+                encoder
+                    .write_frame(&gif::Frame {
+                        left: frame.left,
+                        top: frame.top,
+                        width: frame.width,
+                        height: frame.height,
+                        buffer: Cow::Borrowed(&frame.indices),
+                        transparent: Some(0),
+                        dispose: frame.dispose,
+                        delay: frame.delay,
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+        }
+        bytes
+    }
+
+    fn canvas(gif: &DecodedGif, frame: usize) -> Vec<[u8; 4]> {
+        let image = &gif.frames[frame].image;
+        (0..image.height)
+            .flat_map(|y| (0..image.width).map(move |x| image.pixel(x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn composites_gif_frames_with_disposal() {
+        use gif::DisposalMethod::{Background, Keep, Previous};
+
+        let bytes = encode_gif(
+            3,
+            3,
+            &[
+                /* Fills the screen red */
+                GifFixtureFrame {
+                    left: 0,
+                    top: 0,
+                    width: 3,
+                    height: 3,
+                    indices: vec![1; 9],
+                    dispose: Keep,
+                    delay: 5,
+                },
+                /* Green square with a transparent corner, cleared afterwards */
+                GifFixtureFrame {
+                    left: 1,
+                    top: 1,
+                    width: 2,
+                    height: 2,
+                    indices: vec![0, 2, 2, 2],
+                    dispose: Background,
+                    delay: 1,
+                },
+                /* Blue pixel that is undone afterwards */
+                GifFixtureFrame {
+                    left: 0,
+                    top: 0,
+                    width: 1,
+                    height: 1,
+                    indices: vec![3],
+                    dispose: Previous,
+                    delay: 10,
+                },
+                GifFixtureFrame {
+                    left: 2,
+                    top: 0,
+                    width: 1,
+                    height: 1,
+                    indices: vec![3],
+                    dispose: Keep,
+                    delay: 10,
+                },
+            ],
+        );
+
+        let gif = decode_gif(&bytes).unwrap();
+        assert_eq!((gif.width, gif.height), (3, 3));
+        assert_eq!(gif.frames.len(), 4);
+
+        assert_eq!(canvas(&gif, 0), [RED; 9]);
+
+        #[rustfmt::skip]
+        let expected = [
+            RED, RED, RED,
+            RED, RED, GREEN,
+            RED, GREEN, GREEN,
+        ];
+        assert_eq!(canvas(&gif, 1), expected);
+
+        #[rustfmt::skip]
+        let expected = [
+            BLUE, RED, RED,
+            RED, CLEAR, CLEAR,
+            RED, CLEAR, CLEAR,
+        ];
+        assert_eq!(canvas(&gif, 2), expected);
+
+        #[rustfmt::skip]
+        let expected = [
+            RED, RED, BLUE,
+            RED, CLEAR, CLEAR,
+            RED, CLEAR, CLEAR,
+        ];
+        assert_eq!(canvas(&gif, 3), expected);
+
+        let delays: Vec<f32> = gif.frames.iter().map(|frame| frame.delay).collect();
+        assert_eq!(delays, [0.05, GIF_DEFAULT_DELAY, 0.1, 0.1]);
+    }
+
+    #[test]
+    fn keeps_the_gif_loop_count() {
+        let loop_count = |repeat: Option<gif::Repeat>| {
+            let mut bytes = Vec::new();
+            {
+                let mut encoder = gif::Encoder::new(&mut bytes, 1, 1, &GIF_PALETTE).unwrap();
+                if let Some(repeat) = repeat {
+                    encoder.set_repeat(repeat).unwrap();
+                }
+                encoder
+                    .write_frame(&gif::Frame {
+                        width: 1,
+                        height: 1,
+                        buffer: Cow::Borrowed(&[1]),
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+            decode_gif(&bytes).unwrap().loop_count
+        };
+
+        assert_eq!(loop_count(None), Some(1));
+        assert_eq!(loop_count(Some(gif::Repeat::Infinite)), None);
+        assert_eq!(loop_count(Some(gif::Repeat::Finite(2))), Some(3));
+    }
+
+    #[test]
+    fn rejects_broken_gifs() {
+        let error = decode_gif(b"GIF89a").err().unwrap();
+        assert!(error.starts_with("GIF decoding error"), "{}", error);
+    }
+}
@@ -0,0 +1,356 @@
+#![allow(unused)]
+
+use crate::math::{lerp, wrap_degrees, Color, Vec2};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ease {
+    Linear,
+    InQuad,
+    OutQuad,
+    InOutQuad,
+}
+
+impl Ease {
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Ease::Linear => t,
+            Ease::InQuad => t * t,
+            Ease::OutQuad => t * (2.0 - t),
+            Ease::InOutQuad => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    -1.0 + (4.0 - 2.0 * t) * t
+                }
+            }
+        }
+    }
+}
+
+/* Elapsed time against a duration, shared by every effect */
+#[derive(Clone, Copy, Debug)]
+pub struct Timer {
+    pub duration: f32,
+    pub elapsed: f32,
+}
+
+impl Timer {
+    pub fn new(duration: f32) -> Timer {
+        Timer {
+            duration,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn tick(&mut self, delta_time: f32) {
+        self.elapsed = (self.elapsed + delta_time).min(self.duration);
+    }
+
+    pub fn progress(&self) -> f32 {
+        if self.duration <= 0.0 {
+            1.0
+        } else {
+            self.elapsed / self.duration
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+}
+
+pub struct Tween {
+    pub from: f32,
+    pub to: f32,
+    pub ease: Ease,
+    pub timer: Timer,
+}
+
+impl Tween {
+    pub fn new(from: f32, to: f32, duration: f32, ease: Ease) -> Tween {
+        Tween {
+            from,
+            to,
+            ease,
+            timer: Timer::new(duration),
+        }
+    }
+
+    /* Degrees the shorter way round, so `to` may end up a whole turn from the angle passed in */
+    pub fn angle(from: f32, to: f32, duration: f32, ease: Ease) -> Tween {
+        Tween::new(from, from + wrap_degrees(to - from), duration, ease)
+    }
+
+    pub fn value(&self) -> f32 {
+        lerp(self.from, self.to, self.ease.apply(self.timer.progress()))
+    }
+}
+
+pub struct Shake {
+    /* Maximum offset in world units */
+    pub intensity: f32,
+    /* Whether the intensity decays to zero over the duration */
+    pub falloff: bool,
+    pub timer: Timer,
+    pub offset: Vec2,
+}
+
+impl Shake {
+    pub fn new(intensity: f32, duration: f32) -> Shake {
+        Shake {
+            intensity,
+            falloff: true,
+            timer: Timer::new(duration),
+            offset: Vec2::ZERO,
+        }
+    }
+
+    pub fn current_intensity(&self) -> f32 {
+        if self.falloff {
+            self.intensity * (1.0 - self.timer.progress())
+        } else {
+            self.intensity
+        }
+    }
+}
+
+pub struct Overlay {
+    pub color: Color,
+    pub from_alpha: f32,
+    pub to_alpha: f32,
+    pub timer: Timer,
+    /* Keep drawing at `to_alpha` once finished, used by fades so the screen stays covered */
+    pub hold: bool,
+}
+
+impl Overlay {
+    pub fn current_color(&self) -> Color {
+        let alpha = lerp(self.from_alpha, self.to_alpha, self.timer.progress());
+        self.color.with_alpha(self.color.a * alpha)
+    }
+
+    pub fn done(&self) -> bool {
+        self.timer.finished() && !self.hold
+    }
+}
+
+/* Timed camera juice, advanced by Camera::update */
+pub struct CameraEffects {
+    pub shake: Option<Shake>,
+    pub flash: Option<Overlay>,
+    pub fade: Option<Overlay>,
+    pub zoom: Option<Tween>,
+    pub rotation: Option<Tween>,
+
+    /* xorshift state for shake offsets */
+    pub seed: u32,
+}
+
+impl CameraEffects {
+    pub fn new() -> CameraEffects {
+        CameraEffects {
+            shake: None,
+            flash: None,
+            fade: None,
+            zoom: None,
+            rotation: None,
+
+            seed: 0x9e37_79b9,
+        }
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        let mut shake_offset = None;
+        if let Some(shake) = &mut self.shake {
+            shake.timer.tick(delta_time);
+            shake_offset = Some(shake.current_intensity());
+        }
+        if let Some(intensity) = shake_offset {
+            let offset = Vec2::new(self.random_signed(), self.random_signed()) * intensity;
+            if let Some(shake) = &mut self.shake {
+                shake.offset = offset;
+            }
+        }
+        if self
+            .shake
+            .as_ref()
+            .is_some_and(|shake| shake.timer.finished())
+        {
+            self.shake = None;
+        }
+
+        for overlay in [&mut self.flash, &mut self.fade] {
+            if let Some(effect) = overlay {
+                effect.timer.tick(delta_time);
+            }
+            if overlay.as_ref().is_some_and(|effect| effect.done()) {
+                *overlay = None;
+            }
+        }
+
+        for tween in [&mut self.zoom, &mut self.rotation].into_iter().flatten() {
+            tween.timer.tick(delta_time);
+        }
+    }
+
+    pub fn shake_offset(&self) -> Vec2 {
+        match &self.shake {
+            Some(shake) => shake.offset,
+            None => Vec2::ZERO,
+        }
+    }
+
+    /* Overlay colors to draw over the camera, in order */
+    pub fn overlays(&self) -> impl Iterator<Item = Color> + '_ {
+        [&self.fade, &self.flash]
+            .into_iter()
+            .flatten()
+            .map(|overlay| overlay.current_color())
+            .filter(|color| color.a > 0.0)
+    }
+
+    /* Uniform in [-1, 1] */
+    fn random_signed(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl Default for CameraEffects {
+    fn default() -> CameraEffects {
+        CameraEffects::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EASES: [Ease; 4] = [Ease::Linear, Ease::InQuad, Ease::OutQuad, Ease::InOutQuad];
+
+    #[test]
+    fn eases_start_at_zero_and_end_at_one() {
+        for ease in EASES {
+            assert_eq!(ease.apply(0.0), 0.0, "{:?}", ease);
+            assert_eq!(ease.apply(1.0), 1.0, "{:?}", ease);
+            /* Clamped outside [0, 1] */
+            assert_eq!(ease.apply(-0.5), 0.0, "{:?}", ease);
+            assert_eq!(ease.apply(1.5), 1.0, "{:?}", ease);
+        }
+    }
+
+    #[test]
+    fn eases_bend_the_right_way() {
+        assert_eq!(Ease::Linear.apply(0.25), 0.25);
+        assert!(Ease::InQuad.apply(0.25) < 0.25);
+        assert!(Ease::OutQuad.apply(0.25) > 0.25);
+        assert_eq!(Ease::InOutQuad.apply(0.5), 0.5);
+        assert!(Ease::InOutQuad.apply(0.25) < 0.25);
+        assert!(Ease::InOutQuad.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn timer_finishes_at_its_duration() {
+        let mut timer = Timer::new(1.0);
+        assert_eq!(timer.progress(), 0.0);
+        assert!(!timer.finished());
+
+        timer.tick(0.25);
+        assert_eq!(timer.progress(), 0.25);
+        assert!(!timer.finished());
+
+        /* Overshooting stops at the duration */
+        timer.tick(2.0);
+        assert_eq!(timer.elapsed, 1.0);
+        assert_eq!(timer.progress(), 1.0);
+        assert!(timer.finished());
+    }
+
+    #[test]
+    fn zero_duration_timer_is_already_done() {
+        let timer = Timer::new(0.0);
+        assert_eq!(timer.progress(), 1.0);
+        assert!(timer.finished());
+    }
+
+    #[test]
+    fn tween_follows_its_ease() {
+        let mut tween = Tween::new(10.0, 20.0, 2.0, Ease::InQuad);
+        assert_eq!(tween.value(), 10.0);
+        tween.timer.tick(1.0);
+        assert_eq!(tween.value(), 12.5);
+        tween.timer.tick(1.0);
+        assert_eq!(tween.value(), 20.0);
+    }
+
+    #[test]
+    fn angle_tween_takes_the_shorter_way() {
+        let tween = Tween::angle(350.0, 10.0, 1.0, Ease::Linear);
+        assert_eq!(tween.to, 370.0);
+
+        let tween = Tween::angle(10.0, 350.0, 1.0, Ease::Linear);
+        assert_eq!(tween.to, -10.0);
+
+        let tween = Tween::angle(0.0, 720.0 + 90.0, 1.0, Ease::Linear);
+        assert_eq!(tween.to, 90.0);
+    }
+
+    #[test]
+    fn shake_decays_to_nothing() {
+        let mut shake = Shake::new(8.0, 1.0);
+        assert_eq!(shake.current_intensity(), 8.0);
+        shake.timer.tick(0.5);
+        assert_eq!(shake.current_intensity(), 4.0);
+        shake.timer.tick(0.5);
+        assert_eq!(shake.current_intensity(), 0.0);
+
+        let mut shake = Shake::new(8.0, 1.0);
+        shake.falloff = false;
+        shake.timer.tick(0.9);
+        assert_eq!(shake.current_intensity(), 8.0);
+    }
+
+    #[test]
+    fn shake_offsets_stay_within_intensity_and_stop() {
+        let mut effects = CameraEffects::new();
+        effects.shake = Some(Shake::new(5.0, 1.0));
+        effects.shake.as_mut().unwrap().falloff = false;
+
+        for _ in 0..9 {
+            effects.update(0.1);
+            let offset = effects.shake_offset();
+            assert!(
+                offset.x.abs() <= 5.0 && offset.y.abs() <= 5.0,
+                "{:?}",
+                offset
+            );
+        }
+
+        effects.update(0.2);
+        assert!(effects.shake.is_none());
+        assert_eq!(effects.shake_offset(), Vec2::ZERO);
+    }
+
+    #[test]
+    fn held_overlays_stay_after_finishing() {
+        let overlay = |hold| Overlay {
+            color: Color::new(0.0, 0.0, 0.0, 1.0),
+            from_alpha: 0.0,
+            to_alpha: 1.0,
+            timer: Timer::new(0.5),
+            hold,
+        };
+
+        let mut effects = CameraEffects::new();
+        effects.flash = Some(overlay(false));
+        effects.fade = Some(overlay(true));
+        effects.update(1.0);
+
+        assert!(effects.flash.is_none());
+        let colors: Vec<Color> = effects.overlays().collect();
+        assert_eq!(colors, [Color::new(0.0, 0.0, 0.0, 1.0)]);
+    }
+}
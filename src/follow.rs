@@ -0,0 +1,181 @@
+#![allow(unused)]
+
+use std::{cell::Cell, rc::Weak};
+
+use crate::math::{Rect, Vec2};
+
+/* Lerp factors are given per frame at this rate and rescaled for the actual delta time */
+pub const REFERENCE_FRAMERATE: f32 = 60.0;
+
+pub enum FollowTarget {
+    Point(Vec2),
+    /* A position its owner keeps up to date, e.g. from Sprite::follow_target. Weak so following
+    stops once the owner drops it */
+    Position(Weak<Cell<Vec2>>),
+}
+
+impl FollowTarget {
+    pub fn position(&self) -> Option<Vec2> {
+        match self {
+            FollowTarget::Point(point) => Some(*point),
+            FollowTarget::Position(position) => position.upgrade().map(|position| position.get()),
+        }
+    }
+}
+
+pub struct Follow {
+    pub target: FollowTarget,
+    pub offset: Vec2,
+
+    /* Fraction of the remaining distance covered each frame, 1.0 snaps to the target */
+    pub lerp: Vec2,
+    /* Size of the box around the camera center the target can move in without scrolling */
+    pub deadzone: Option<Vec2>,
+    /* Seconds of target velocity to lead the camera by */
+    pub look_ahead: Vec2,
+    pub look_ahead_lerp: f32,
+
+    pub last_position: Option<Vec2>,
+    pub look_ahead_offset: Vec2,
+}
+
+impl Follow {
+    pub fn new(target: FollowTarget) -> Follow {
+        Follow {
+            target,
+            offset: Vec2::ZERO,
+
+            lerp: Vec2::ONE,
+            deadzone: None,
+            look_ahead: Vec2::ZERO,
+            look_ahead_lerp: 0.1,
+
+            last_position: None,
+            look_ahead_offset: Vec2::ZERO,
+        }
+    }
+
+    /* Returns where the camera center should move to this frame, or None if the target is gone */
+    pub fn step(&mut self, center: Vec2, delta_time: f32) -> Option<Vec2> {
+        let position = self.target.position()?;
+
+        if delta_time > 0.0 {
+            let velocity = match self.last_position {
+                Some(last_position) => (position - last_position) / delta_time,
+                None => Vec2::ZERO,
+            };
+
+            let amount = frame_lerp(self.look_ahead_lerp, delta_time);
+            self.look_ahead_offset = self
+                .look_ahead_offset
+                .lerp(velocity * self.look_ahead, amount);
+        }
+        self.last_position = Some(position);
+
+        let target = position + self.offset + self.look_ahead_offset;
+
+        let mut desired = center;
+        match self.deadzone {
+            Some(deadzone) => {
+                let half = deadzone / 2.0;
+                desired.x = target.x.clamp(center.x - half.x, center.x + half.x);
+                desired.y = target.y.clamp(center.y - half.y, center.y + half.y);
+
+                /* Only the part of the target outside the deadzone moves the camera */
+                desired = center + (target - desired);
+            }
+            None => desired = target,
+        }
+
+        Some(Vec2::new(
+            center.x + (desired.x - center.x) * frame_lerp(self.lerp.x, delta_time),
+            center.y + (desired.y - center.y) * frame_lerp(self.lerp.y, delta_time),
+        ))
+    }
+}
+
+/* Converts a per-frame lerp factor into one for `delta_time` seconds */
+pub fn frame_lerp(factor: f32, delta_time: f32) -> f32 {
+    if factor >= 1.0 {
+        return 1.0;
+    }
+    1.0 - (1.0 - factor.max(0.0)).powf(delta_time * REFERENCE_FRAMERATE)
+}
+
+/* Moves `center` so a view with the given half extents stays inside `bounds`, centering when it can't fit */
+pub fn clamp_to_bounds(center: Vec2, half_extents: Vec2, bounds: &Rect) -> Vec2 {
+    let clamp_axis = |center: f32, half: f32, min: f32, size: f32| {
+        if half * 2.0 >= size {
+            min + size / 2.0
+        } else {
+            center.clamp(min + half, min + size - half)
+        }
+    };
+
+    Vec2::new(
+        clamp_axis(center.x, half_extents.x, bounds.x, bounds.width),
+        clamp_axis(center.y, half_extents.y, bounds.y, bounds.height),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn follows_a_shared_position_until_it_is_dropped() {
+        let position = Rc::new(Cell::new(Vec2::new(10.0, 20.0)));
+        let mut follow = Follow::new(FollowTarget::Position(Rc::downgrade(&position)));
+
+        assert_eq!(
+            follow.step(Vec2::ZERO, 1.0 / 60.0),
+            Some(Vec2::new(10.0, 20.0))
+        );
+
+        position.set(Vec2::new(-5.0, 0.0));
+        assert_eq!(
+            follow.step(Vec2::ZERO, 1.0 / 60.0),
+            Some(Vec2::new(-5.0, 0.0))
+        );
+
+        drop(position);
+        assert_eq!(follow.step(Vec2::ZERO, 1.0 / 60.0), None);
+    }
+
+    #[test]
+    fn deadzone_only_moves_by_the_overshoot() {
+        let mut follow = Follow::new(FollowTarget::Point(Vec2::new(30.0, 5.0)));
+        follow.deadzone = Some(Vec2::new(40.0, 40.0));
+
+        assert_eq!(
+            follow.step(Vec2::ZERO, 1.0 / 60.0),
+            Some(Vec2::new(10.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn frame_lerp_matches_the_reference_rate() {
+        assert_eq!(frame_lerp(1.0, 0.5), 1.0);
+        assert!((frame_lerp(0.1, 1.0 / REFERENCE_FRAMERATE) - 0.1).abs() < 1e-5);
+        /* Two half-length frames cover the same distance as one full one */
+        let half = frame_lerp(0.1, 0.5 / REFERENCE_FRAMERATE);
+        assert!((1.0 - (1.0 - half) * (1.0 - half) - 0.1).abs() < 1e-5);
+    }
+
+    #[test]
+    fn clamp_to_bounds_keeps_the_view_inside() {
+        let bounds = Rect::new(0.0, 0.0, 100.0, 50.0);
+        let half = Vec2::new(10.0, 10.0);
+
+        assert_eq!(
+            clamp_to_bounds(Vec2::new(-20.0, 45.0), half, &bounds),
+            Vec2::new(10.0, 40.0)
+        );
+        /* Wider than the bounds, so it centers */
+        assert_eq!(
+            clamp_to_bounds(Vec2::new(0.0, 0.0), Vec2::new(60.0, 10.0), &bounds),
+            Vec2::new(50.0, 10.0)
+        );
+    }
+}
@@ -0,0 +1,514 @@
+#![allow(unused)]
+
+/* KTX2 container parsing and GPU format selection, kept free of web-sys so it runs natively.
+Spec: https://registry.khronos.org/KTX/specs/2.0/ktxspec.v2.html */
+
+pub const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+
+const HEADER_LENGTH: usize = 80;
+const LEVEL_INDEX_ENTRY_LENGTH: usize = 24;
+
+/* Data format descriptor fields, relative to the start of the descriptor block */
+const DFD_BLOCK_OFFSET: usize = 4;
+const DFD_FLAGS_OFFSET: usize = 11;
+const KHR_DF_FLAG_ALPHA_PREMULTIPLIED: u8 = 1;
+
+pub const COMPRESSED_EXTENSIONS: [&str; 5] = [
+    "WEBGL_compressed_texture_etc",
+    "WEBGL_compressed_texture_astc",
+    "WEBGL_compressed_texture_s3tc",
+    "WEBGL_compressed_texture_s3tc_srgb",
+    "EXT_texture_compression_bptc",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Family {
+    Uncompressed,
+    Etc2,
+    Astc,
+    S3tc,
+    Bptc,
+}
+
+impl Family {
+    /* WebGL extension that has to be enabled to upload this family */
+    pub fn extension(&self, srgb: bool) -> Option<&'static str> {
+        match (self, srgb) {
+            (Family::Uncompressed, _) => None,
+            (Family::Etc2, _) => Some("WEBGL_compressed_texture_etc"),
+            (Family::Astc, _) => Some("WEBGL_compressed_texture_astc"),
+            (Family::S3tc, false) => Some("WEBGL_compressed_texture_s3tc"),
+            (Family::S3tc, true) => Some("WEBGL_compressed_texture_s3tc_srgb"),
+            (Family::Bptc, _) => Some("EXT_texture_compression_bptc"),
+        }
+    }
+}
+
+/* A vkFormat this runtime can upload, with what GL needs to know about it */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TextureFormat {
+    pub vk_format: u32,
+    pub family: Family,
+    pub gl_internal_format: u32,
+    pub srgb: bool,
+    pub block_width: u32,
+    pub block_height: u32,
+    pub block_bytes: u32,
+}
+
+impl TextureFormat {
+    pub fn from_vk_format(vk_format: u32) -> Option<TextureFormat> {
+        let format = |family, gl_internal_format, srgb, block_width, block_height, block_bytes| {
+            Some(TextureFormat {
+                vk_format,
+                family,
+                gl_internal_format,
+                srgb,
+                block_width,
+                block_height,
+                block_bytes,
+            })
+        };
+
+        match vk_format {
+            /* R8G8B8A8, uploaded with texImage2D as RGBA8 / SRGB8_ALPHA8 */
+            37 => format(Family::Uncompressed, 0x8058, false, 1, 1, 4),
+            43 => format(Family::Uncompressed, 0x8C43, true, 1, 1, 4),
+
+            /* BC1 - BC3 */
+            131 => format(Family::S3tc, 0x83F0, false, 4, 4, 8),
+            132 => format(Family::S3tc, 0x8C4C, true, 4, 4, 8),
+            133 => format(Family::S3tc, 0x83F1, false, 4, 4, 8),
+            134 => format(Family::S3tc, 0x8C4D, true, 4, 4, 8),
+            135 => format(Family::S3tc, 0x83F2, false, 4, 4, 16),
+            136 => format(Family::S3tc, 0x8C4E, true, 4, 4, 16),
+            137 => format(Family::S3tc, 0x83F3, false, 4, 4, 16),
+            138 => format(Family::S3tc, 0x8C4F, true, 4, 4, 16),
+
+            /* BC7 */
+            145 => format(Family::Bptc, 0x8E8C, false, 4, 4, 16),
+            146 => format(Family::Bptc, 0x8E8D, true, 4, 4, 16),
+
+            /* ETC2 RGB, RGB with punchthrough alpha, RGBA */
+            147 => format(Family::Etc2, 0x9274, false, 4, 4, 8),
+            148 => format(Family::Etc2, 0x9275, true, 4, 4, 8),
+            149 => format(Family::Etc2, 0x9276, false, 4, 4, 8),
+            150 => format(Family::Etc2, 0x9277, true, 4, 4, 8),
+            151 => format(Family::Etc2, 0x9278, false, 4, 4, 16),
+            152 => format(Family::Etc2, 0x9279, true, 4, 4, 16),
+
+            /* ASTC, every block size is 16 bytes and the UNORM/SRGB pairs are adjacent */
+            157..=184 => {
+                const BLOCKS: [(u32, u32); 14] = [
+                    (4, 4),
+                    (5, 4),
+                    (5, 5),
+                    (6, 5),
+                    (6, 6),
+                    (8, 5),
+                    (8, 6),
+                    (8, 8),
+                    (10, 5),
+                    (10, 6),
+                    (10, 8),
+                    (10, 10),
+                    (12, 10),
+                    (12, 12),
+                ];
+                let index = (vk_format - 157) / 2;
+                let srgb = (vk_format - 157) % 2 == 1;
+                let (block_width, block_height) = BLOCKS[index as usize];
+                let base = if srgb { 0x93D0 } else { 0x93B0 };
+                format(
+                    Family::Astc,
+                    base + index,
+                    srgb,
+                    block_width,
+                    block_height,
+                    16,
+                )
+            }
+
+            _ => None,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.family != Family::Uncompressed
+    }
+
+    pub fn extension(&self) -> Option<&'static str> {
+        self.family.extension(self.srgb)
+    }
+
+    /* Bytes a mip level of this size takes, partial blocks round up */
+    pub fn level_size(&self, width: u32, height: u32) -> usize {
+        let blocks_x = width.div_ceil(self.block_width);
+        let blocks_y = height.div_ceil(self.block_height);
+        blocks_x as usize * blocks_y as usize * self.block_bytes as usize
+    }
+}
+
+/* The WebGL extensions that were found, by name */
+#[derive(Clone, Debug, Default)]
+pub struct SupportedFormats {
+    pub extensions: Vec<String>,
+}
+
+impl SupportedFormats {
+    pub fn new(extensions: Vec<String>) -> SupportedFormats {
+        SupportedFormats { extensions }
+    }
+
+    pub fn supports(&self, format: &TextureFormat) -> bool {
+        match format.extension() {
+            Some(extension) => self.extensions.iter().any(|name| name == extension),
+            None => true,
+        }
+    }
+
+    /* Index of the first vkFormat in preference order that is known and can be uploaded */
+    pub fn select(&self, vk_formats: &[u32]) -> Option<usize> {
+        vk_formats.iter().position(|vk_format| {
+            TextureFormat::from_vk_format(*vk_format).is_some_and(|format| self.supports(&format))
+        })
+    }
+}
+
+pub struct Level<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub data: &'a [u8],
+}
+
+pub struct Ktx2<'a> {
+    pub format: TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    /* Largest level first */
+    pub levels: Vec<Level<'a>>,
+    pub premultiplied: bool,
+}
+
+impl<'a> Ktx2<'a> {
+    /* Only single-layer 2D textures without supercompression are supported */
+    pub fn parse(bytes: &'a [u8]) -> Result<Ktx2<'a>, String> {
+        if bytes.len() < HEADER_LENGTH || bytes[..IDENTIFIER.len()] != IDENTIFIER {
+            return Err(String::from("Not a KTX2 file"));
+        }
+
+        let vk_format = read_u32(bytes, 12)?;
+        let width = read_u32(bytes, 20)?;
+        let height = read_u32(bytes, 24)?;
+        let depth = read_u32(bytes, 28)?;
+        let layer_count = read_u32(bytes, 32)?;
+        let face_count = read_u32(bytes, 36)?;
+        let level_count = read_u32(bytes, 40)?.max(1);
+        let supercompression = read_u32(bytes, 44)?;
+        let dfd_offset = read_u32(bytes, 48)?;
+        let dfd_length = read_u32(bytes, 52)?;
+
+        let format = TextureFormat::from_vk_format(vk_format)
+            .ok_or_else(|| format!("Unsupported KTX2 vkFormat {}", vk_format))?;
+
+        if supercompression != 0 {
+            return Err(format!(
+                "Unsupported KTX2 supercompression scheme {}",
+                supercompression
+            ));
+        }
+        if width == 0 || height == 0 || depth > 1 || layer_count > 1 || face_count != 1 {
+            return Err(String::from("Only 2D KTX2 textures are supported"));
+        }
+        if level_count > 32 {
+            return Err(format!("Invalid KTX2 level count {}", level_count));
+        }
+
+        let mut levels = Vec::with_capacity(level_count as usize);
+        for level in 0..level_count {
+            let entry = HEADER_LENGTH + level as usize * LEVEL_INDEX_ENTRY_LENGTH;
+            let offset = read_u64(bytes, entry)?;
+            let length = read_u64(bytes, entry + 8)?;
+
+            let level_width = (width >> level).max(1);
+            let level_height = (height >> level).max(1);
+            if length != format.level_size(level_width, level_height) as u64 {
+                return Err(format!("KTX2 level {} has the wrong size", level));
+            }
+
+            let data = offset
+                .checked_add(length)
+                .and_then(|end| slice(bytes, offset, end))
+                .ok_or_else(|| format!("KTX2 level {} is out of bounds", level))?;
+
+            levels.push(Level {
+                width: level_width,
+                height: level_height,
+                data,
+            });
+        }
+
+        let dfd_end = dfd_offset as u64 + dfd_length as u64;
+        let premultiplied = match slice(bytes, dfd_offset as u64, dfd_end) {
+            Some(dfd) => dfd
+                .get(DFD_BLOCK_OFFSET + DFD_FLAGS_OFFSET)
+                .is_some_and(|flags| flags & KHR_DF_FLAG_ALPHA_PREMULTIPLIED != 0),
+            None => return Err(String::from("KTX2 data format descriptor is out of bounds")),
+        };
+
+        Ok(Ktx2 {
+            format,
+            width,
+            height,
+            levels,
+            premultiplied,
+        })
+    }
+}
+
+/* Offsets are 64-bit in the file and may not fit a wasm32 usize */
+fn slice(bytes: &[u8], start: u64, end: u64) -> Option<&[u8]> {
+    let start = usize::try_from(start).ok()?;
+    let end = usize::try_from(end).ok()?;
+    bytes.get(start..end)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|slice| u32::from_le_bytes(slice.try_into().unwrap()))
+        .ok_or_else(|| String::from("Unexpected end of KTX2 file"))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    bytes
+        .get(offset..offset + 8)
+        .map(|slice| u64::from_le_bytes(slice.try_into().unwrap()))
+        .ok_or_else(|| String::from("Unexpected end of KTX2 file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGBA8: u32 = 37;
+    const BC1: u32 = 131;
+    const BC7: u32 = 145;
+    const ETC2_RGBA: u32 = 151;
+    const ASTC_4X4: u32 = 157;
+    const ASTC_4X4_SRGB: u32 = 158;
+    const ASTC_12X12_SRGB: u32 = 184;
+
+    /* A minimal file: header, level index, a zeroed basic DFD block with `flags`, then the levels.
+    Written largest first, which the spec doesn't but the index offsets make irrelevant */
+    fn fixture(vk_format: u32, width: u32, height: u32, level_count: u32, flags: u8) -> Vec<u8> {
+        let format = TextureFormat::from_vk_format(vk_format).unwrap();
+        let levels = level_count.max(1);
+
+        let dfd_offset = HEADER_LENGTH + levels as usize * LEVEL_INDEX_ENTRY_LENGTH;
+        let mut dfd = vec![0u8; DFD_BLOCK_OFFSET + 24];
+        dfd[DFD_BLOCK_OFFSET + DFD_FLAGS_OFFSET] = flags;
+        let mut data_offset = dfd_offset + dfd.len();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&IDENTIFIER);
+        for field in [
+            vk_format,
+            1,
+            width,
+            height,
+            0,
+            0,
+            1,
+            level_count,
+            0,
+            dfd_offset as u32,
+            dfd.len() as u32,
+            0,
+            0,
+        ] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        assert_eq!(bytes.len(), HEADER_LENGTH);
+
+        let mut data = Vec::new();
+        for level in 0..levels {
+            let size = format.level_size((width >> level).max(1), (height >> level).max(1));
+            bytes.extend_from_slice(&(data_offset as u64).to_le_bytes());
+            bytes.extend_from_slice(&(size as u64).to_le_bytes());
+            bytes.extend_from_slice(&(size as u64).to_le_bytes());
+            data.extend(std::iter::repeat_n(level as u8 + 1, size));
+            data_offset += size;
+        }
+
+        bytes.extend_from_slice(&dfd);
+        bytes.extend_from_slice(&data);
+        bytes
+    }
+
+    fn level_entry(level: usize) -> usize {
+        HEADER_LENGTH + level * LEVEL_INDEX_ENTRY_LENGTH
+    }
+
+    fn parse_error(bytes: &[u8]) -> String {
+        match Ktx2::parse(bytes) {
+            Ok(_) => panic!("expected the file to be rejected"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn parses_levels_from_the_index() {
+        let bytes = fixture(RGBA8, 4, 2, 3, 0);
+        let ktx2 = Ktx2::parse(&bytes).unwrap();
+
+        assert_eq!(ktx2.format.gl_internal_format, 0x8058);
+        assert_eq!((ktx2.width, ktx2.height), (4, 2));
+        assert!(!ktx2.premultiplied);
+
+        let sizes: Vec<_> = ktx2
+            .levels
+            .iter()
+            .map(|level| (level.width, level.height, level.data.len()))
+            .collect();
+        assert_eq!(sizes, [(4, 2, 32), (2, 1, 8), (1, 1, 4)]);
+
+        for (index, level) in ktx2.levels.iter().enumerate() {
+            assert!(level.data.iter().all(|byte| *byte == index as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn zero_level_count_means_one_level() {
+        let bytes = fixture(BC7, 8, 8, 0, 0);
+        let ktx2 = Ktx2::parse(&bytes).unwrap();
+        assert_eq!(ktx2.levels.len(), 1);
+        assert_eq!(ktx2.levels[0].data.len(), 64);
+    }
+
+    #[test]
+    fn reads_the_premultiplied_flag_from_the_dfd() {
+        let bytes = fixture(ETC2_RGBA, 4, 4, 1, KHR_DF_FLAG_ALPHA_PREMULTIPLIED);
+        assert!(Ktx2::parse(&bytes).unwrap().premultiplied);
+    }
+
+    #[test]
+    fn rejects_a_bad_identifier() {
+        let mut bytes = fixture(RGBA8, 1, 1, 1, 0);
+        bytes[1] = b'X';
+        assert_eq!(parse_error(&bytes), "Not a KTX2 file");
+        assert_eq!(parse_error(&bytes[..40]), "Not a KTX2 file");
+    }
+
+    #[test]
+    fn rejects_unknown_formats_and_supercompression() {
+        let mut bytes = fixture(RGBA8, 1, 1, 1, 0);
+        bytes[12..16].copy_from_slice(&9999u32.to_le_bytes());
+        assert!(parse_error(&bytes).contains("vkFormat 9999"));
+
+        let mut bytes = fixture(RGBA8, 1, 1, 1, 0);
+        bytes[44..48].copy_from_slice(&1u32.to_le_bytes());
+        assert!(parse_error(&bytes).contains("supercompression"));
+    }
+
+    #[test]
+    fn rejects_arrays_and_cubemaps() {
+        let mut bytes = fixture(RGBA8, 1, 1, 1, 0);
+        bytes[36..40].copy_from_slice(&6u32.to_le_bytes());
+        assert_eq!(parse_error(&bytes), "Only 2D KTX2 textures are supported");
+    }
+
+    #[test]
+    fn rejects_a_level_of_the_wrong_size() {
+        let mut bytes = fixture(BC1, 8, 8, 2, 0);
+        let length = level_entry(1) + 8;
+        bytes[length..length + 8].copy_from_slice(&4u64.to_le_bytes());
+        assert_eq!(parse_error(&bytes), "KTX2 level 1 has the wrong size");
+    }
+
+    #[test]
+    fn rejects_levels_out_of_bounds() {
+        let bytes = fixture(RGBA8, 2, 2, 1, 0);
+        assert_eq!(
+            parse_error(&bytes[..bytes.len() - 1]),
+            "KTX2 level 0 is out of bounds"
+        );
+
+        let mut bytes = fixture(RGBA8, 2, 2, 1, 0);
+        let offset = level_entry(0);
+        bytes[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(parse_error(&bytes), "KTX2 level 0 is out of bounds");
+    }
+
+    #[test]
+    fn rejects_a_truncated_level_index() {
+        let bytes = fixture(RGBA8, 2, 2, 1, 0);
+        assert_eq!(
+            parse_error(&bytes[..HEADER_LENGTH + 4]),
+            "Unexpected end of KTX2 file"
+        );
+    }
+
+    #[test]
+    fn maps_vk_formats_to_gl() {
+        let gl = |vk_format| {
+            TextureFormat::from_vk_format(vk_format)
+                .map(|format| (format.family, format.gl_internal_format, format.srgb))
+        };
+
+        assert_eq!(gl(RGBA8), Some((Family::Uncompressed, 0x8058, false)));
+        assert_eq!(gl(43), Some((Family::Uncompressed, 0x8C43, true)));
+        assert_eq!(gl(BC1), Some((Family::S3tc, 0x83F0, false)));
+        assert_eq!(gl(138), Some((Family::S3tc, 0x8C4F, true)));
+        assert_eq!(gl(BC7), Some((Family::Bptc, 0x8E8C, false)));
+        assert_eq!(gl(ETC2_RGBA), Some((Family::Etc2, 0x9278, false)));
+        assert_eq!(gl(ASTC_4X4), Some((Family::Astc, 0x93B0, false)));
+        assert_eq!(gl(ASTC_4X4_SRGB), Some((Family::Astc, 0x93D0, true)));
+        assert_eq!(gl(183), Some((Family::Astc, 0x93BD, false)));
+        assert_eq!(gl(ASTC_12X12_SRGB), Some((Family::Astc, 0x93DD, true)));
+        assert_eq!(gl(156), None);
+        assert_eq!(gl(185), None);
+
+        let astc = TextureFormat::from_vk_format(167).unwrap();
+        assert_eq!((astc.block_width, astc.block_height), (8, 5));
+    }
+
+    #[test]
+    fn level_size_rounds_up_partial_blocks() {
+        let bc1 = TextureFormat::from_vk_format(BC1).unwrap();
+        assert_eq!(bc1.level_size(1, 1), 8);
+        assert_eq!(bc1.level_size(5, 4), 16);
+
+        let astc = TextureFormat::from_vk_format(ASTC_12X12_SRGB).unwrap();
+        assert_eq!(astc.level_size(13, 12), 32);
+
+        let rgba = TextureFormat::from_vk_format(RGBA8).unwrap();
+        assert_eq!(rgba.level_size(3, 5), 60);
+    }
+
+    #[test]
+    fn selects_the_first_supported_variant() {
+        let none = SupportedFormats::default();
+        let s3tc = SupportedFormats::new(vec![String::from("WEBGL_compressed_texture_s3tc")]);
+        let astc = SupportedFormats::new(vec![
+            String::from("WEBGL_compressed_texture_etc"),
+            String::from("WEBGL_compressed_texture_astc"),
+        ]);
+
+        let preferred = [ASTC_4X4, ETC2_RGBA, BC1, RGBA8];
+        assert_eq!(astc.select(&preferred), Some(0));
+        assert_eq!(s3tc.select(&preferred), Some(2));
+        assert_eq!(none.select(&preferred), Some(3));
+        assert_eq!(none.select(&[ASTC_4X4, BC7]), None);
+
+        /* Unknown formats are skipped rather than assumed uploadable */
+        assert_eq!(none.select(&[9999, RGBA8]), Some(1));
+
+        /* The sRGB S3TC variants need their own extension */
+        assert!(!s3tc.supports(&TextureFormat::from_vk_format(132).unwrap()));
+    }
+}
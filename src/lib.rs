@@ -6,28 +6,48 @@ use std::rc::Rc;
 
 use wasm_bindgen::prelude::*;
 
+use crate::bench::UploadBenchmark;
 use crate::camera::Camera;
 use crate::object::Object;
+use crate::post::{Bloom, PostEffect};
 use crate::sprite::Sprite;
 
+mod animation;
 mod app;
 mod assets;
+mod bench;
 mod camera;
+mod capture;
+mod clip;
 mod debug;
+mod decode;
+mod effects;
+mod follow;
+mod ktx2;
+mod lighting;
+mod math;
 mod object;
+mod post;
+mod program;
+mod record;
 mod render;
+#[cfg(test)]
+mod shader_check;
+mod shadow;
 mod sprite;
+mod state;
+mod texture;
+
+const BENCHMARK_FRAMES: u32 = 600;
 
 #[wasm_bindgen(start)]
 async fn start() -> Result<(), JsValue> {
     let mut app = App::new()?;
 
-    let program = render::with_renderer(|renderer| {
-        renderer.create_program(None, Some(BLOOM_FRAGMENT_SHADER))
-    });
-
     let mut camera = Camera::new(app.canvas.width() as f32, app.canvas.height() as f32);
-    camera.shader = Some(program);
+    render::with_renderer(|renderer| {
+        camera.add_post_effect(renderer, PostEffect::Bloom(Bloom::new()));
+    });
     camera.rotation = 35.0;
 
     let camera_pointer = Rc::new(RefCell::new(camera));
@@ -44,6 +64,11 @@ async fn start() -> Result<(), JsValue> {
         app.objects.push(Box::new(banna));
     }
 
+    /* Append ?bench to the url to compare the vertex upload paths */
+    if app.window.location().search()?.contains("bench") {
+        app.benchmark = Some(UploadBenchmark::new(BENCHMARK_FRAMES));
+    }
+
     app.start_main_loop();
 
     Ok(())
@@ -133,55 +158,3 @@ impl Object for Banna {
         self.sprite.draw(render);
     }
 }
-
-pub const BLOOM_FRAGMENT_SHADER: &str = "#version 300 es
-    precision highp float;
-
-    #define PI 3.1415926535897932384626433832795
-    #define TWO_PI (PI * 2.0)
-
-    #define brightness 2.
-    #define threshold .7
-    #define directions 16.0
-    #define quality 6.0
-    #define size 20.0
-
-    in vec2 texture_coords;
-    uniform sampler2D texture_sampler;
-    out vec4 output_color;
-
-    void main() {
-        vec2 uv = texture_coords.xy;
-
-        vec4 color = texture(texture_sampler, uv);
-        
-        if (brightness <= 0.0 || size <= 0.0) {
-            output_color = color;
-            return;
-        }
-
-        vec4 bloom = vec4(0.0);
-        float weightSum = 0.0;
-
-        vec4 highlight = max(color - threshold, 0.0);
-
-        for (float d = 0.0; d < TWO_PI; d += TWO_PI / directions) {
-            for (float i = 1.0; i <= float(quality); i++) {
-                float offset = (i / float(quality)) * size;
-                float x_offset = (sin(d) * offset) / 300.0;
-                float y_offset = (cos(d) * offset) / 300.0;
-                vec2 sampleUV = clamp(uv + vec2(x_offset, y_offset), vec2(0.0), vec2(1.0));
-
-                vec4 sampleColor = max(texture(texture_sampler, sampleUV) - threshold, 0.0);
-                float weight = exp(-2.0 * (i / float(quality)));
-                bloom += sampleColor * weight;
-                weightSum += weight;
-            }
-        }
-
-        if (weightSum > 0.0) {
-            bloom /= weightSum;
-        }
-
-        output_color = color + (bloom * brightness);
-    }";
@@ -0,0 +1,574 @@
+#![allow(unused)]
+
+use std::rc::Rc;
+
+use web_sys::{WebGl2RenderingContext, WebGlSampler, WebGlTexture};
+
+use crate::camera::{self, DrawCall, CLIP_RECT};
+use crate::math::{to_radians, Color, Mat3, Rect, Vec2};
+use crate::program::Program;
+use crate::render::{self, PostProcessTarget, Renderer, BASE_QUAD_VERTS, QUAD_VERTEX_FLOATS};
+use crate::shadow::{self, Occluder};
+
+pub const NORMAL_FRAGMENT_SHADER: &str = include_str!("shaders/normal.frag");
+pub const FLAT_NORMAL_FRAGMENT_SHADER: &str = include_str!("shaders/flat_normal.frag");
+pub const LIGHT_FRAGMENT_SHADER: &str = include_str!("shaders/light.frag");
+pub const LIGHTING_FRAGMENT_SHADER: &str = include_str!("shaders/lighting.frag");
+
+/* Point and spot lights sit this far above the scene in world units, lower grazes normal maps more */
+pub const DEFAULT_LIGHT_HEIGHT: f32 = 32.0;
+/* Directional lights come in at this slope above the horizon */
+pub const DEFAULT_DIRECTIONAL_HEIGHT: f32 = 1.0;
+pub const DEFAULT_FALLOFF: f32 = 2.0;
+/* Copies each light with a source_radius is split into, more gives smoother penumbras */
+pub const DEFAULT_SHADOW_SAMPLES: usize = 6;
+
+/* Flat normal pointing at the viewer, what the normal target is cleared to and what sprites
+without a normal map write (see flat_normal.frag) */
+pub const FLAT_NORMAL: [f32; 3] = [0.0, 0.0, 1.0];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightKind {
+    Point,
+    /* Cone around `direction` in degrees, `angle` is half the cone's width in degrees and
+    `softness` how much of it fades out towards the edge, from 0 to 1 */
+    Spot {
+        direction: f32,
+        angle: f32,
+        softness: f32,
+    },
+    /* Reaches everything from one direction in degrees, like moonlight */
+    Directional {
+        direction: f32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    /* World space, unused by directional lights */
+    pub position: Vec2,
+    pub color: Color,
+    pub intensity: f32,
+    /* Distance in world units where the light reaches zero */
+    pub radius: f32,
+    /* Exponent on the linear falloff, 1 fades evenly and higher values keep the light tighter */
+    pub falloff: f32,
+    /* How far above the scene the light is, see DEFAULT_LIGHT_HEIGHT */
+    pub height: f32,
+
+    /* Whether Lighting::occluders block this light */
+    pub casts_shadows: bool,
+    /* Size of the light in world units (degrees of spread for directional lights), anything
+    above 0 softens the shadow edges, see shadow::sample_lights */
+    pub source_radius: f32,
+}
+
+impl Light {
+    pub fn point(position: Vec2, radius: f32, color: Color) -> Light {
+        Light {
+            kind: LightKind::Point,
+            position,
+            color,
+            intensity: 1.0,
+            radius,
+            falloff: DEFAULT_FALLOFF,
+            height: DEFAULT_LIGHT_HEIGHT,
+
+            casts_shadows: true,
+            source_radius: 0.0,
+        }
+    }
+
+    pub fn spot(position: Vec2, radius: f32, direction: f32, angle: f32, color: Color) -> Light {
+        Light {
+            kind: LightKind::Spot {
+                direction,
+                angle,
+                softness: 0.5,
+            },
+            ..Light::point(position, radius, color)
+        }
+    }
+
+    pub fn directional(direction: f32, color: Color) -> Light {
+        Light {
+            kind: LightKind::Directional { direction },
+            height: DEFAULT_DIRECTIONAL_HEIGHT,
+            ..Light::point(Vec2::ZERO, 0.0, color)
+        }
+    }
+
+    /* Unit vector the light shines along, zero for point lights */
+    pub fn direction(&self) -> Vec2 {
+        match self.kind {
+            LightKind::Point => Vec2::ZERO,
+            LightKind::Spot { direction, .. } | LightKind::Directional { direction } => {
+                let radians = to_radians(direction);
+                Vec2::new(radians.cos(), radians.sin())
+            }
+        }
+    }
+
+    /* Cosines of the outer and inner cone angles, everything is inside for other kinds */
+    pub fn cone(&self) -> (f32, f32) {
+        match self.kind {
+            LightKind::Spot {
+                angle, softness, ..
+            } => {
+                let outer = to_radians(angle);
+                let inner = outer * (1.0 - softness.clamp(0.0, 1.0));
+                (outer.cos(), inner.cos().max(outer.cos() + f32::EPSILON))
+            }
+            _ => (-2.0, -1.0),
+        }
+    }
+
+    /* Color the shader adds, before attenuation */
+    pub fn radiance(&self) -> [f32; 3] {
+        [
+            self.color.r * self.intensity,
+            self.color.g * self.intensity,
+            self.color.b * self.intensity,
+        ]
+    }
+
+    /* Distance and cone falloff at a world position, from 1 at the light to 0 */
+    pub fn attenuation(&self, world: Vec2) -> f32 {
+        if let LightKind::Directional { .. } = self.kind {
+            return 1.0;
+        }
+        if self.radius <= 0.0 {
+            return 0.0;
+        }
+
+        let offset = self.position - world;
+        let distance = offset.length();
+        let mut attenuation = (1.0 - distance / self.radius)
+            .clamp(0.0, 1.0)
+            .powf(self.falloff);
+
+        if let LightKind::Spot { .. } = self.kind {
+            if distance > 0.0 {
+                let (outer, inner) = self.cone();
+                let angle = (offset * (-1.0 / distance)).dot(self.direction());
+                attenuation *= smoothstep(outer, inner, angle);
+            }
+        }
+
+        attenuation
+    }
+
+    /* How strongly the light hits a surface with `normal` (world space, z towards the viewer),
+    the same as shaders/light.frag */
+    pub fn contribution(&self, world: Vec2, normal: [f32; 3]) -> f32 {
+        let to_light = match self.kind {
+            LightKind::Directional { .. } => {
+                let direction = self.direction();
+                [-direction.x, -direction.y, self.height]
+            }
+            _ => {
+                let offset = self.position - world;
+                [offset.x, offset.y, self.height]
+            }
+        };
+
+        let diffuse = dot3(normalize3(normal), normalize3(to_light)).max(0.0);
+        self.attenuation(world) * diffuse
+    }
+
+    /* World area the light can reach, None when it reaches everywhere */
+    pub fn bounds(&self) -> Option<Rect> {
+        match self.kind {
+            LightKind::Directional { .. } => None,
+            _ => Some(Rect::new(
+                self.position.x - self.radius,
+                self.position.y - self.radius,
+                self.radius * 2.0,
+                self.radius * 2.0,
+            )),
+        }
+    }
+
+    fn kind_index(&self) -> i32 {
+        match self.kind {
+            LightKind::Point => 0,
+            LightKind::Spot { .. } => 1,
+            LightKind::Directional { .. } => 2,
+        }
+    }
+}
+
+/* Quad covering what the light reaches. Positions are in clip space and the texture coordinates
+carry the world position, which is what shaders/light.frag reads them as */
+pub fn light_quad(light: &Light, view: &Mat3) -> Option<[f32; QUAD_VERTEX_FLOATS]> {
+    let corners = match light.bounds() {
+        Some(bounds) => bounds.corners(),
+        None => {
+            let inverse = view.invert()?;
+            CLIP_RECT
+                .corners()
+                .map(|corner| inverse.transform_point(corner))
+        }
+    };
+
+    let mut vertices = [0.0; QUAD_VERTEX_FLOATS];
+    for (index, world) in corners.iter().enumerate() {
+        let position = view.transform_point(*world);
+        let vertex =
+            &mut vertices[index * render::VERTEX_FLOATS..(index + 1) * render::VERTEX_FLOATS];
+        vertex[..4].copy_from_slice(&[position.x, position.y, world.x, world.y]);
+        vertex[4..].copy_from_slice(&Color::WHITE.to_array());
+    }
+    Some(vertices)
+}
+
+/* Rotation and flips a sprite applies to its normal map, column-major for shaders/normal.frag */
+pub fn normal_matrix(rotation: f32, scalex: f32, scaley: f32) -> [f32; 4] {
+    let matrix = Mat3::rotation(-to_radians(rotation))
+        * Mat3::scale(Vec2::new(scalex.signum(), scaley.signum()));
+
+    let x_axis = matrix.transform_vector(Vec2::new(1.0, 0.0));
+    let y_axis = matrix.transform_vector(Vec2::new(0.0, 1.0));
+    [x_axis.x, x_axis.y, y_axis.x, y_axis.y]
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn dot3(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn normalize3(vector: [f32; 3]) -> [f32; 3] {
+    let length = dot3(vector, vector).sqrt();
+    if length <= 0.0 {
+        return [0.0; 3];
+    }
+    vector.map(|component| component / length)
+}
+
+/* Offscreen targets the lighting passes render into, all the size of the camera's target */
+pub struct LightTargets {
+    /* World space normals, encoded to 0..1 */
+    pub normal: PostProcessTarget,
+    /* Ambient plus every light */
+    pub light: PostProcessTarget,
+    /* The camera's image multiplied by the light, what the final pass draws */
+    pub lit: PostProcessTarget,
+}
+
+impl LightTargets {
+    pub fn new(renderer: &Renderer, width: i32, height: i32) -> LightTargets {
+        let create =
+            || renderer.create_render_target(width, height, WebGl2RenderingContext::LINEAR, false);
+
+        LightTargets {
+            normal: create(),
+            light: create(),
+            lit: create(),
+        }
+    }
+}
+
+/* Per camera lighting, composed between the camera's target and its post-process shader */
+pub struct Lighting {
+    /* Light everything gets before any lights are added, black is pitch dark */
+    pub ambient: Color,
+    pub lights: Vec<Light>,
+    /* World space shapes that cast shadows, rebuilt by whoever moves them */
+    pub occluders: Vec<Occluder>,
+    pub shadow_samples: usize,
+
+    pub targets: LightTargets,
+    pub normal_program: Rc<Program>,
+    pub flat_normal_program: Rc<Program>,
+    pub light_program: Rc<Program>,
+    pub compose_program: Rc<Program>,
+
+    /* Every sprite's normals in draw order, batched like Camera::draws */
+    pub normal_draws: Vec<DrawCall>,
+}
+
+impl Lighting {
+    pub fn new(renderer: &Renderer, width: i32, height: i32, ambient: Color) -> Lighting {
+        Lighting {
+            ambient,
+            lights: Vec::new(),
+            occluders: Vec::new(),
+            shadow_samples: DEFAULT_SHADOW_SAMPLES,
+
+            targets: LightTargets::new(renderer, width, height),
+            normal_program: renderer.create_program(None, Some(NORMAL_FRAGMENT_SHADER)),
+            flat_normal_program: renderer.create_program(None, Some(FLAT_NORMAL_FRAGMENT_SHADER)),
+            light_program: renderer.create_program(None, Some(LIGHT_FRAGMENT_SHADER)),
+            compose_program: renderer.create_program(None, Some(LIGHTING_FRAGMENT_SHADER)),
+
+            normal_draws: Vec::new(),
+        }
+    }
+
+    /* Recreates the targets, also how they come back after the context is restored */
+    pub fn resize(&mut self, renderer: &Renderer, width: i32, height: i32) {
+        self.targets = LightTargets::new(renderer, width, height);
+    }
+
+    /* Renders normals and lights for the `view`, then multiplies `source` by them into the lit
+    target, which is left bound. `sampler` is the one the camera drew its sprites with */
+    pub fn render(
+        &self,
+        renderer: &Renderer,
+        view: &Mat3,
+        source: &WebGlTexture,
+        sampler: Option<&WebGlSampler>,
+    ) {
+        self.draw_normals(renderer, sampler);
+        self.draw_lights(renderer, view);
+        self.compose(renderer, source);
+    }
+
+    fn bind_target(renderer: &Renderer, target: &PostProcessTarget) {
+        renderer.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&target.frame_buffer_draw),
+        );
+        renderer.set_viewport(0, 0, target.width, target.height);
+    }
+
+    /* Drawn into the framebuffer with a stencil buffer, so sprites keep the clip they were
+    drawn with, masks included */
+    fn draw_normals(&self, renderer: &Renderer, sampler: Option<&WebGlSampler>) {
+        let target = &self.targets.normal;
+        renderer.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&target.frame_buffer_store),
+        );
+        renderer.set_viewport(0, 0, target.width, target.height);
+        let [x, y, z] = FLAT_NORMAL.map(|component| component * 0.5 + 0.5);
+        renderer.clear_color(x, y, z, 1.0);
+
+        /* Normal maps are uploaded with straight alpha, flat normals output it */
+        renderer.set_alpha_blend(false);
+        renderer.use_sampler(0, sampler);
+        let mut clip = None;
+        for draw in &self.normal_draws {
+            if !camera::same_clip(&clip, &draw.clip) {
+                renderer.set_clip(draw.clip.as_deref());
+                clip = draw.clip.clone();
+            }
+
+            renderer.use_program(&draw.program);
+            renderer.use_texture(&draw.texture);
+            renderer.draw_quads(
+                &renderer.quads_buffer,
+                &draw.program,
+                &draw.vertices,
+                draw.count,
+            );
+        }
+        renderer.set_clip(None);
+        renderer.use_sampler(0, None);
+
+        renderer.resolve_target(target);
+    }
+
+    /* Lights are drawn into the target's framebuffer rather than straight into its texture,
+    since that is where the stencil buffer for shadows is */
+    fn draw_lights(&self, renderer: &Renderer, view: &Mat3) {
+        let target = &self.targets.light;
+        renderer.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&target.frame_buffer_store),
+        );
+        renderer.set_viewport(0, 0, target.width, target.height);
+        renderer.clear_color(self.ambient.r, self.ambient.g, self.ambient.b, 1.0);
+        renderer.set_additive_blend();
+
+        let program = &self.light_program;
+        renderer.use_program(program);
+        if let Some(location) = program.uniform_location("target_size") {
+            renderer
+                .context
+                .uniform2f(Some(&location), target.width as f32, target.height as f32);
+        }
+
+        /* Far enough that directional shadows run off the view whatever the occluder's position */
+        let view_bounds = match view.invert() {
+            Some(inverse) => CLIP_RECT.transform(&inverse),
+            None => Rect::default(),
+        };
+        let view_extent = view_bounds.width + view_bounds.height;
+
+        for light in &self.lights {
+            if !Lighting::on_screen(light, view) {
+                continue;
+            }
+
+            let occluders = match light.casts_shadows {
+                true => self.occluders_near(light),
+                false => Vec::new(),
+            };
+            let samples = match occluders.is_empty() {
+                true => vec![*light],
+                false => shadow::sample_lights(light, self.shadow_samples),
+            };
+
+            for sample in &samples {
+                let Some(vertices) = light_quad(sample, view) else {
+                    continue;
+                };
+
+                let shadowed = !occluders.is_empty();
+                if shadowed {
+                    let extent = match sample.kind {
+                        LightKind::Directional { .. } => view_extent,
+                        _ => sample.radius * 2.0,
+                    };
+                    self.draw_shadows(renderer, view, sample, &occluders, extent);
+                }
+
+                renderer.use_program(program);
+                renderer.use_texture(&self.targets.normal.texture);
+                Lighting::set_light_uniforms(&renderer.context, program, sample);
+                renderer.draw_quads(&renderer.quads_buffer, program, &vertices, 1);
+
+                if shadowed {
+                    renderer.set_stencil_test(false);
+                }
+            }
+        }
+
+        renderer.resolve_target(target);
+    }
+
+    /* Lights entirely off screen cost nothing but the check */
+    fn on_screen(light: &Light, view: &Mat3) -> bool {
+        let Some(vertices) = light_quad(light, view) else {
+            return false;
+        };
+
+        let positions = [0, 1, 2, 3].map(|corner| {
+            let vertex = &vertices[corner * render::VERTEX_FLOATS..];
+            Vec2::new(vertex[0], vertex[1])
+        });
+        Rect::from_points(&positions).intersects(&CLIP_RECT)
+    }
+
+    fn occluders_near(&self, light: &Light) -> Vec<&Occluder> {
+        let reach = light.bounds().map(|bounds| {
+            let grow = light.source_radius;
+            Rect::new(
+                bounds.x - grow,
+                bounds.y - grow,
+                bounds.width + grow * 2.0,
+                bounds.height + grow * 2.0,
+            )
+        });
+
+        self.occluders
+            .iter()
+            .filter(|occluder| match reach {
+                Some(reach) => occluder.bounds().intersects(&reach),
+                None => true,
+            })
+            .collect()
+    }
+
+    /* Marks the light's shadows in the stencil buffer and leaves the stencil test passing
+    only outside of them */
+    fn draw_shadows(
+        &self,
+        renderer: &Renderer,
+        view: &Mat3,
+        light: &Light,
+        occluders: &[&Occluder],
+        extent: f32,
+    ) {
+        let (Some(program), Some(texture)) = (&renderer.base_program, &renderer.white_texture)
+        else {
+            return;
+        };
+
+        let mut vertices = Vec::new();
+        for occluder in occluders {
+            for quad in shadow::shadow_quads(&occluder.points, light, extent) {
+                let mut positions = [0.0; 12];
+                for (corner, point) in quad.iter().enumerate() {
+                    let point = view.transform_point(*point);
+                    positions[corner * 3] = point.x;
+                    positions[corner * 3 + 1] = point.y;
+                }
+                vertices.extend_from_slice(&render::quad_vertices(
+                    &positions,
+                    Rect::UNIT,
+                    Color::WHITE,
+                ));
+            }
+        }
+
+        renderer.set_stencil_test(true);
+        renderer.clear_stencil();
+        renderer.begin_stencil_write(WebGl2RenderingContext::REPLACE);
+
+        renderer.use_program(program);
+        renderer.use_texture(texture);
+        renderer.draw_quads(
+            &renderer.quads_buffer,
+            program,
+            &vertices,
+            vertices.len() / QUAD_VERTEX_FLOATS,
+        );
+
+        renderer.end_stencil_write(0, 0xFF);
+    }
+
+    fn set_light_uniforms(context: &WebGl2RenderingContext, program: &Program, light: &Light) {
+        let direction = light.direction();
+        let (outer, inner) = light.cone();
+        let [red, green, blue] = light.radiance();
+
+        if let Some(location) = program.uniform_location("light_kind") {
+            context.uniform1i(Some(&location), light.kind_index());
+        }
+        if let Some(location) = program.uniform_location("light_position") {
+            context.uniform2f(Some(&location), light.position.x, light.position.y);
+        }
+        if let Some(location) = program.uniform_location("light_color") {
+            context.uniform3f(Some(&location), red, green, blue);
+        }
+        if let Some(location) = program.uniform_location("light_radius") {
+            context.uniform1f(Some(&location), light.radius);
+        }
+        if let Some(location) = program.uniform_location("light_falloff") {
+            context.uniform1f(Some(&location), light.falloff);
+        }
+        if let Some(location) = program.uniform_location("light_height") {
+            context.uniform1f(Some(&location), light.height);
+        }
+        if let Some(location) = program.uniform_location("light_direction") {
+            context.uniform2f(Some(&location), direction.x, direction.y);
+        }
+        if let Some(location) = program.uniform_location("light_cone") {
+            context.uniform2f(Some(&location), outer, inner);
+        }
+    }
+
+    fn compose(&self, renderer: &Renderer, source: &WebGlTexture) {
+        Lighting::bind_target(renderer, &self.targets.lit);
+        renderer.clear_color(0.0, 0.0, 0.0, 0.0);
+
+        let program = &self.compose_program;
+        renderer.set_alpha_blend(true);
+        renderer.use_program(program);
+        renderer.use_texture_unit(1, &self.targets.light.texture);
+        renderer.use_texture(source);
+        if let Some(location) = program.uniform_location("light_sampler") {
+            renderer.context.uniform1i(Some(&location), 1);
+        }
+
+        let vertices = render::quad_vertices(&BASE_QUAD_VERTS, Rect::UNIT, Color::WHITE);
+        renderer.draw_quads(&renderer.quads_buffer, program, &vertices, 1);
+    }
+}
@@ -0,0 +1,558 @@
+#![allow(unused)]
+
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub, SubAssign};
+
+pub const DEG_TO_RADIANS: f32 = (std::f64::consts::PI / 180.0) as f32;
+pub const RADIANS_TO_DEG: f32 = (180.0 / std::f64::consts::PI) as f32;
+
+pub fn to_radians(degrees: f32) -> f32 {
+    degrees * DEG_TO_RADIANS
+}
+
+pub fn to_degrees(radians: f32) -> f32 {
+    radians * RADIANS_TO_DEG
+}
+
+/* Wraps an angle in degrees into (-180, 180] */
+pub fn wrap_degrees(degrees: f32) -> f32 {
+    let wrapped = (degrees + 180.0).rem_euclid(360.0) - 180.0;
+    if wrapped == -180.0 {
+        180.0
+    } else {
+        wrapped
+    }
+}
+
+pub fn lerp(from: f32, to: f32, amount: f32) -> f32 {
+    from + (to - from) * amount
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vec2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vec2 {
+    pub const ZERO: Vec2 = Vec2 { x: 0.0, y: 0.0 };
+    pub const ONE: Vec2 = Vec2 { x: 1.0, y: 1.0 };
+
+    pub const fn new(x: f32, y: f32) -> Vec2 {
+        Vec2 { x, y }
+    }
+
+    pub fn dot(self, other: Vec2) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    /* z component of the 3D cross product, positive when `other` is counter-clockwise of `self` */
+    pub fn cross(self, other: Vec2) -> f32 {
+        self.x * other.y - self.y * other.x
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn length_squared(self) -> f32 {
+        self.dot(self)
+    }
+
+    pub fn normalize(self) -> Vec2 {
+        let length = self.length();
+        if length == 0.0 {
+            Vec2::ZERO
+        } else {
+            self / length
+        }
+    }
+
+    pub fn rotate(self, radians: f32) -> Vec2 {
+        let cos_theta = radians.cos();
+        let sin_theta = radians.sin();
+        Vec2::new(
+            self.x * cos_theta - self.y * sin_theta,
+            self.x * sin_theta + self.y * cos_theta,
+        )
+    }
+
+    pub fn lerp(self, to: Vec2, amount: f32) -> Vec2 {
+        self + (to - self) * amount
+    }
+
+    pub fn min(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x.min(other.x), self.y.min(other.y))
+    }
+
+    pub fn max(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x.max(other.x), self.y.max(other.y))
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+    fn add(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl AddAssign for Vec2 {
+    fn add_assign(&mut self, other: Vec2) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+    fn sub(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl SubAssign for Vec2 {
+    fn sub_assign(&mut self, other: Vec2) {
+        *self = *self - other;
+    }
+}
+
+impl Mul<f32> for Vec2 {
+    type Output = Vec2;
+    fn mul(self, scalar: f32) -> Vec2 {
+        Vec2::new(self.x * scalar, self.y * scalar)
+    }
+}
+
+impl Mul<Vec2> for Vec2 {
+    type Output = Vec2;
+    fn mul(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x * other.x, self.y * other.y)
+    }
+}
+
+impl MulAssign<f32> for Vec2 {
+    fn mul_assign(&mut self, scalar: f32) {
+        *self = *self * scalar;
+    }
+}
+
+impl Div<f32> for Vec2 {
+    type Output = Vec2;
+    fn div(self, scalar: f32) -> Vec2 {
+        Vec2::new(self.x / scalar, self.y / scalar)
+    }
+}
+
+impl Neg for Vec2 {
+    type Output = Vec2;
+    fn neg(self) -> Vec2 {
+        Vec2::new(-self.x, -self.y)
+    }
+}
+
+/* 2D affine transform, column-major like GLSL's mat3 so it can be uploaded as is */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat3 {
+    pub cols: [[f32; 3]; 3],
+}
+
+impl Mat3 {
+    pub const IDENTITY: Mat3 = Mat3 {
+        cols: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    pub fn translation(offset: Vec2) -> Mat3 {
+        Mat3 {
+            cols: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [offset.x, offset.y, 1.0]],
+        }
+    }
+
+    pub fn rotation(radians: f32) -> Mat3 {
+        let cos_theta = radians.cos();
+        let sin_theta = radians.sin();
+        Mat3 {
+            cols: [
+                [cos_theta, sin_theta, 0.0],
+                [-sin_theta, cos_theta, 0.0],
+                [0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    pub fn scale(scale: Vec2) -> Mat3 {
+        Mat3 {
+            cols: [[scale.x, 0.0, 0.0], [0.0, scale.y, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    /* Applies `self` after `other` */
+    pub fn compose(&self, other: &Mat3) -> Mat3 {
+        let mut cols = [[0.0; 3]; 3];
+        for (col, out) in cols.iter_mut().enumerate() {
+            for (row, value) in out.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.cols[k][row] * other.cols[col][k]).sum();
+            }
+        }
+        Mat3 { cols }
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let [a, b, _] = self.cols[0];
+        let [c, d, _] = self.cols[1];
+        a * d - b * c
+    }
+
+    pub fn invert(&self) -> Option<Mat3> {
+        /* Relative to the axes' lengths, so small but valid scales like a zoomed out view matrix
+        (about 1e-7 at 4K) still invert and only truly collapsed axes are rejected */
+        let determinant = self.determinant();
+        let axes = Vec2::new(self.cols[0][0], self.cols[0][1]).length()
+            * Vec2::new(self.cols[1][0], self.cols[1][1]).length();
+        if !determinant.is_finite() || determinant.abs() <= axes * f32::EPSILON {
+            return None;
+        }
+
+        let [a, b, _] = self.cols[0];
+        let [c, d, _] = self.cols[1];
+        let [tx, ty, _] = self.cols[2];
+
+        let inverse_a = d / determinant;
+        let inverse_b = -b / determinant;
+        let inverse_c = -c / determinant;
+        let inverse_d = a / determinant;
+
+        Some(Mat3 {
+            cols: [
+                [inverse_a, inverse_b, 0.0],
+                [inverse_c, inverse_d, 0.0],
+                [
+                    -(inverse_a * tx + inverse_c * ty),
+                    -(inverse_b * tx + inverse_d * ty),
+                    1.0,
+                ],
+            ],
+        })
+    }
+
+    pub fn transform_point(&self, point: Vec2) -> Vec2 {
+        Vec2::new(
+            self.cols[0][0] * point.x + self.cols[1][0] * point.y + self.cols[2][0],
+            self.cols[0][1] * point.x + self.cols[1][1] * point.y + self.cols[2][1],
+        )
+    }
+
+    /* Like transform_point but ignores translation */
+    pub fn transform_vector(&self, vector: Vec2) -> Vec2 {
+        Vec2::new(
+            self.cols[0][0] * vector.x + self.cols[1][0] * vector.y,
+            self.cols[0][1] * vector.x + self.cols[1][1] * vector.y,
+        )
+    }
+
+    pub fn to_cols_array(self) -> [f32; 9] {
+        let [a, b, c] = self.cols;
+        [a[0], a[1], a[2], b[0], b[1], b[2], c[0], c[1], c[2]]
+    }
+}
+
+impl Default for Mat3 {
+    fn default() -> Mat3 {
+        Mat3::IDENTITY
+    }
+}
+
+impl Mul for Mat3 {
+    type Output = Mat3;
+    fn mul(self, other: Mat3) -> Mat3 {
+        self.compose(&other)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Rect {
+    /* The whole texture in normalized coordinates */
+    pub const UNIT: Rect = Rect {
+        x: 0.0,
+        y: 0.0,
+        width: 1.0,
+        height: 1.0,
+    };
+
+    pub const fn new(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /* Smallest rect containing every point */
+    pub fn from_points(points: &[Vec2]) -> Rect {
+        let Some(first) = points.first() else {
+            return Rect::default();
+        };
+
+        let (min, max) = points.iter().fold((*first, *first), |(min, max), point| {
+            (min.min(*point), max.max(*point))
+        });
+        Rect::new(min.x, min.y, max.x - min.x, max.y - min.y)
+    }
+
+    pub fn min(&self) -> Vec2 {
+        Vec2::new(self.x, self.y)
+    }
+
+    pub fn max(&self) -> Vec2 {
+        Vec2::new(self.x + self.width, self.y + self.height)
+    }
+
+    pub fn center(&self) -> Vec2 {
+        Vec2::new(self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    pub fn corners(&self) -> [Vec2; 4] {
+        let min = self.min();
+        let max = self.max();
+        [min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)]
+    }
+
+    pub fn contains(&self, point: Vec2) -> bool {
+        point.x >= self.x
+            && point.y >= self.y
+            && point.x <= self.x + self.width
+            && point.y <= self.y + self.height
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x <= other.x + other.width
+            && other.x <= self.x + self.width
+            && self.y <= other.y + other.height
+            && other.y <= self.y + self.height
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        let min = self.min().min(other.min());
+        let max = self.max().max(other.max());
+        Rect::new(min.x, min.y, max.x - min.x, max.y - min.y)
+    }
+
+    /* Overlapping area, zero sized when the rects don't touch */
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let min = self.min().max(other.min());
+        let max = self.max().min(other.max()).max(min);
+        Rect::new(min.x, min.y, max.x - min.x, max.y - min.y)
+    }
+
+    /* Bounds of this rect after being transformed, e.g. by a rotation */
+    pub fn transform(&self, matrix: &Mat3) -> Rect {
+        let corners = self.corners().map(|corner| matrix.transform_point(corner));
+        Rect::from_points(&corners)
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.x, self.y, self.width, self.height]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Color {
+    pub const WHITE: Color = Color::new(1.0, 1.0, 1.0, 1.0);
+    pub const BLACK: Color = Color::new(0.0, 0.0, 0.0, 1.0);
+    pub const TRANSPARENT: Color = Color::new(0.0, 0.0, 0.0, 0.0);
+
+    pub const fn new(r: f32, g: f32, b: f32, a: f32) -> Color {
+        Color { r, g, b, a }
+    }
+
+    pub const fn rgb(r: f32, g: f32, b: f32) -> Color {
+        Color::new(r, g, b, 1.0)
+    }
+
+    /* 0xRRGGBB */
+    pub fn from_hex(hex: u32) -> Color {
+        Color::rgb(
+            ((hex >> 16) & 0xff) as f32 / 255.0,
+            ((hex >> 8) & 0xff) as f32 / 255.0,
+            (hex & 0xff) as f32 / 255.0,
+        )
+    }
+
+    pub fn with_alpha(self, a: f32) -> Color {
+        Color { a, ..self }
+    }
+
+    pub fn premultiplied(self) -> Color {
+        Color::new(self.r * self.a, self.g * self.a, self.b * self.a, self.a)
+    }
+
+    pub fn lerp(self, to: Color, amount: f32) -> Color {
+        Color::new(
+            lerp(self.r, to.r, amount),
+            lerp(self.g, to.g, amount),
+            lerp(self.b, to.b, amount),
+            lerp(self.a, to.a, amount),
+        )
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+}
+
+impl Default for Color {
+    fn default() -> Color {
+        Color::WHITE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        let tolerance = 1e-3 * (1.0 + expected.length());
+        assert!(
+            (actual - expected).length() <= tolerance,
+            "{:?} is not near {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /* Same shape as Camera::view_matrix */
+    fn view(width: f32, height: f32, zoom: f32, rotation: f32, center: Vec2) -> Mat3 {
+        Mat3::scale(Vec2::new(1.0 / width, -1.0 / height))
+            * Mat3::rotation(to_radians(rotation))
+            * Mat3::scale(Vec2::new(zoom, zoom))
+            * Mat3::translation(-center)
+    }
+
+    #[test]
+    fn transform_point_applies_translation_and_vector_does_not() {
+        let matrix = Mat3::translation(Vec2::new(3.0, -2.0)) * Mat3::scale(Vec2::new(2.0, 4.0));
+        assert_near(
+            matrix.transform_point(Vec2::new(1.0, 1.0)),
+            Vec2::new(5.0, 2.0),
+        );
+        assert_near(
+            matrix.transform_vector(Vec2::new(1.0, 1.0)),
+            Vec2::new(2.0, 4.0),
+        );
+    }
+
+    #[test]
+    fn rotation_is_counter_clockwise() {
+        let rotated = Mat3::rotation(to_radians(90.0)).transform_point(Vec2::new(1.0, 0.0));
+        assert_near(rotated, Vec2::new(0.0, 1.0));
+    }
+
+    #[test]
+    fn compose_applies_the_right_matrix_first() {
+        let translate = Mat3::translation(Vec2::new(10.0, 0.0));
+        let scale = Mat3::scale(Vec2::new(2.0, 2.0));
+        let point = Vec2::new(1.0, 1.0);
+
+        assert_near(
+            (translate * scale).transform_point(point),
+            Vec2::new(12.0, 2.0),
+        );
+        assert_near(
+            (scale * translate).transform_point(point),
+            Vec2::new(22.0, 2.0),
+        );
+        assert_near(
+            (translate * scale).transform_point(point),
+            translate.transform_point(scale.transform_point(point)),
+        );
+        assert_eq!(Mat3::IDENTITY * translate, translate);
+    }
+
+    #[test]
+    fn invert_round_trips() {
+        let matrix = Mat3::translation(Vec2::new(40.0, -12.0))
+            * Mat3::rotation(to_radians(30.0))
+            * Mat3::scale(Vec2::new(3.0, -0.5));
+        let inverse = matrix.invert().unwrap();
+
+        for point in [Vec2::ZERO, Vec2::new(5.0, 7.0), Vec2::new(-300.0, 120.0)] {
+            assert_near(
+                inverse.transform_point(matrix.transform_point(point)),
+                point,
+            );
+        }
+    }
+
+    #[test]
+    fn invert_handles_zoomed_out_views() {
+        for (width, height) in [(800.0, 600.0), (1920.0, 1080.0), (3840.0, 2160.0)] {
+            for zoom in [0.05, 0.25, 1.0, 8.0] {
+                let matrix = view(width, height, zoom, 35.0, Vec2::new(120.0, -40.0));
+                let inverse = matrix
+                    .invert()
+                    .unwrap_or_else(|| panic!("{}x{} at zoom {} is singular", width, height, zoom));
+
+                let world = Vec2::new(500.0, 250.0);
+                assert_near(
+                    inverse.transform_point(matrix.transform_point(world)),
+                    world,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invert_rejects_collapsed_axes() {
+        assert_eq!(Mat3::scale(Vec2::new(0.0, 1.0)).invert(), None);
+        assert_eq!(Mat3::scale(Vec2::new(f32::NAN, 1.0)).invert(), None);
+
+        /* Both axes pointing the same way */
+        let parallel = Mat3 {
+            cols: [[1.0, 2.0, 0.0], [2.0, 4.0, 0.0], [0.0, 0.0, 1.0]],
+        };
+        assert_eq!(parallel.invert(), None);
+    }
+
+    #[test]
+    fn rect_transform_bounds_rotated_corners() {
+        let bounds = Rect::new(-1.0, -1.0, 2.0, 2.0).transform(&Mat3::rotation(to_radians(45.0)));
+        let half = 2.0_f32.sqrt();
+        assert_near(bounds.min(), Vec2::new(-half, -half));
+        assert_near(bounds.max(), Vec2::new(half, half));
+    }
+
+    #[test]
+    fn rect_intersection_and_union() {
+        let first = Rect::new(0.0, 0.0, 10.0, 10.0);
+        let second = Rect::new(5.0, -5.0, 10.0, 10.0);
+
+        assert!(first.intersects(&second));
+        assert_eq!(first.intersection(&second), Rect::new(5.0, 0.0, 5.0, 5.0));
+        assert_eq!(first.union(&second), Rect::new(0.0, -5.0, 15.0, 15.0));
+
+        let apart = Rect::new(20.0, 20.0, 1.0, 1.0);
+        assert!(!first.intersects(&apart));
+        assert_eq!(first.intersection(&apart).width, 0.0);
+    }
+
+    #[test]
+    fn wrap_degrees_stays_in_range() {
+        assert_eq!(wrap_degrees(190.0), -170.0);
+        assert_eq!(wrap_degrees(-180.0), 180.0);
+        assert_eq!(wrap_degrees(540.0), 180.0);
+        assert_eq!(wrap_degrees(45.0), 45.0);
+    }
+}
@@ -0,0 +1,589 @@
+#![allow(unused)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use web_sys::{WebGl2RenderingContext, WebGlTexture};
+
+use crate::assets::{Assets, Image};
+use crate::math::{Color, Rect};
+use crate::program::Program;
+use crate::render::{self, PostProcessTarget, Renderer, BASE_QUAD_VERTS};
+use crate::texture::TextureOptions;
+
+pub const BLOOM_FRAGMENT_SHADER: &str = include_str!("shaders/bloom.frag");
+pub const BLUR_FRAGMENT_SHADER: &str = include_str!("shaders/blur.frag");
+pub const VIGNETTE_FRAGMENT_SHADER: &str = include_str!("shaders/vignette.frag");
+pub const CRT_FRAGMENT_SHADER: &str = include_str!("shaders/crt.frag");
+pub const PIXELATE_FRAGMENT_SHADER: &str = include_str!("shaders/pixelate.frag");
+pub const CHROMATIC_FRAGMENT_SHADER: &str = include_str!("shaders/chromatic.frag");
+pub const COLOR_GRADE_FRAGMENT_SHADER: &str = include_str!("shaders/color_grade.frag");
+pub const TONE_FRAGMENT_SHADER: &str = include_str!("shaders/tone.frag");
+pub const GRAIN_FRAGMENT_SHADER: &str = include_str!("shaders/grain.frag");
+
+/* Size of the weights array in shaders/blur.frag, the center tap included */
+pub const MAX_BLUR_TAPS: usize = 32;
+
+/* Rec. 709 luma */
+const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+const GRAYSCALE: [[f32; 3]; 3] = [LUMA; 3];
+/* Rows of the usual sepia matrix */
+const SEPIA: [[f32; 3]; 3] = [
+    [0.393, 0.769, 0.189],
+    [0.349, 0.686, 0.168],
+    [0.272, 0.534, 0.131],
+];
+
+/* Separable Gaussian blur, run as a horizontal then a vertical pass */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Blur {
+    /* Furthest tap in target pixels, capped at MAX_BLUR_TAPS - 1 */
+    pub radius: f32,
+    /* Spread of the Gaussian in target pixels, about half the radius looks smooth */
+    pub sigma: f32,
+}
+
+impl Blur {
+    pub fn new(radius: f32) -> Blur {
+        Blur {
+            radius,
+            sigma: radius / 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Bloom {
+    /* Brightness (0 to 1 per channel) above which colors glow */
+    pub threshold: f32,
+    pub intensity: f32,
+    /* How far the glow reaches in target pixels */
+    pub radius: f32,
+    /* Rays sampled around each pixel and samples along each ray, more is smoother and slower */
+    pub directions: i32,
+    pub quality: i32,
+}
+
+impl Bloom {
+    pub fn new() -> Bloom {
+        Bloom {
+            threshold: 0.7,
+            intensity: 2.0,
+            radius: 40.0,
+            directions: 16,
+            quality: 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Vignette {
+    /* 0 leaves the edges alone, 1 fades them fully to `color` */
+    pub strength: f32,
+    /* Distance from the center, where 1 is the middle of an edge, that the fade starts at */
+    pub radius: f32,
+    /* Distance the fade takes to reach full strength */
+    pub softness: f32,
+    pub color: Color,
+}
+
+impl Vignette {
+    pub fn new() -> Vignette {
+        Vignette {
+            strength: 0.6,
+            radius: 0.6,
+            softness: 0.8,
+            color: Color::BLACK,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Crt {
+    /* How much the image bulges, 0 is flat */
+    pub curvature: f32,
+    /* How dark the scanlines get, 0 to 1 */
+    pub scanline_intensity: f32,
+    /* Scanlines over the height of the view, 0 gives one for every two target rows */
+    pub scanline_count: f32,
+}
+
+impl Crt {
+    pub fn new() -> Crt {
+        Crt {
+            curvature: 0.08,
+            scanline_intensity: 0.25,
+            scanline_count: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pixelate {
+    /* Width of a block in target pixels */
+    pub pixel_size: f32,
+}
+
+impl Pixelate {
+    pub fn new(pixel_size: f32) -> Pixelate {
+        Pixelate { pixel_size }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChromaticAberration {
+    /* How far red and blue are pulled apart at the edges of the view, in target pixels */
+    pub offset: f32,
+}
+
+impl ChromaticAberration {
+    pub fn new(offset: f32) -> ChromaticAberration {
+        ChromaticAberration { offset }
+    }
+}
+
+/* Remaps colors through a lookup table image: `size` slices of `size` x `size` pixels side by side,
+blue picking the slice, red going across it and green going down */
+#[derive(Clone)]
+pub struct ColorGrade {
+    pub lut: Rc<RefCell<Image>>,
+    pub size: f32,
+    /* Blend between the original (0) and graded (1) colors */
+    pub amount: f32,
+}
+
+impl ColorGrade {
+    pub fn new(lut: Rc<RefCell<Image>>) -> ColorGrade {
+        let size = lut.borrow().height as f32;
+        ColorGrade {
+            lut,
+            size,
+            amount: 1.0,
+        }
+    }
+
+    /* LUTs hold colors to look up rather than to show, so they skip premultiplying and mipmaps */
+    pub async fn load(path: &str) -> Option<ColorGrade> {
+        let options = TextureOptions {
+            mipmaps: false,
+            ..TextureOptions::new()
+        }
+        .with_premultiplied_alpha(false);
+
+        Assets::load_image_with(path, options)
+            .await
+            .map(ColorGrade::new)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Grayscale {
+    pub amount: f32,
+}
+
+impl Grayscale {
+    pub fn new(amount: f32) -> Grayscale {
+        Grayscale { amount }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sepia {
+    pub amount: f32,
+}
+
+impl Sepia {
+    pub fn new(amount: f32) -> Sepia {
+        Sepia { amount }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FilmGrain {
+    /* How far the noise pushes each channel, 0 to 1 */
+    pub intensity: f32,
+    /* Size of a grain in target pixels */
+    pub size: f32,
+}
+
+impl FilmGrain {
+    pub fn new(intensity: f32) -> FilmGrain {
+        FilmGrain {
+            intensity,
+            size: 1.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum PostEffect {
+    Blur(Blur),
+    Bloom(Bloom),
+    Vignette(Vignette),
+    Crt(Crt),
+    Pixelate(Pixelate),
+    ChromaticAberration(ChromaticAberration),
+    ColorGrade(ColorGrade),
+    Grayscale(Grayscale),
+    Sepia(Sepia),
+    FilmGrain(FilmGrain),
+}
+
+impl PostEffect {
+    pub fn fragment_source(&self) -> &'static str {
+        match self {
+            PostEffect::Blur(_) => BLUR_FRAGMENT_SHADER,
+            PostEffect::Bloom(_) => BLOOM_FRAGMENT_SHADER,
+            PostEffect::Vignette(_) => VIGNETTE_FRAGMENT_SHADER,
+            PostEffect::Crt(_) => CRT_FRAGMENT_SHADER,
+            PostEffect::Pixelate(_) => PIXELATE_FRAGMENT_SHADER,
+            PostEffect::ChromaticAberration(_) => CHROMATIC_FRAGMENT_SHADER,
+            PostEffect::ColorGrade(_) => COLOR_GRADE_FRAGMENT_SHADER,
+            PostEffect::Grayscale(_) | PostEffect::Sepia(_) => TONE_FRAGMENT_SHADER,
+            PostEffect::FilmGrain(_) => GRAIN_FRAGMENT_SHADER,
+        }
+    }
+
+    /* Full screen draws the effect takes */
+    pub fn passes(&self) -> usize {
+        match self {
+            PostEffect::Blur(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /* `texel_size` is one target pixel in texture coordinates */
+    fn set_uniforms(
+        &self,
+        renderer: &Renderer,
+        program: &Program,
+        pass: usize,
+        texel_size: [f32; 2],
+    ) {
+        let context = &renderer.context;
+        let float = |name: &str, value: f32| {
+            if let Some(location) = program.uniform_location(name) {
+                context.uniform1f(Some(&location), value);
+            }
+        };
+        let int = |name: &str, value: i32| {
+            if let Some(location) = program.uniform_location(name) {
+                context.uniform1i(Some(&location), value);
+            }
+        };
+
+        match self {
+            PostEffect::Blur(blur) => {
+                let (weights, spacing) = gaussian_weights(blur.radius, blur.sigma);
+                let step = match pass {
+                    0 => [texel_size[0] * spacing, 0.0],
+                    _ => [0.0, texel_size[1] * spacing],
+                };
+                if let Some(location) = program.uniform_location("blur_step") {
+                    context.uniform2f(Some(&location), step[0], step[1]);
+                }
+                if let Some(location) = program.uniform_location("weights") {
+                    context.uniform1fv_with_f32_array(Some(&location), &weights);
+                }
+                int("taps", weights.len() as i32);
+            }
+            PostEffect::Bloom(bloom) => {
+                float("threshold", bloom.threshold);
+                float("intensity", bloom.intensity);
+                float("radius", bloom.radius);
+                int("directions", bloom.directions);
+                int("quality", bloom.quality);
+            }
+            PostEffect::Vignette(vignette) => {
+                float("strength", vignette.strength);
+                float("radius", vignette.radius);
+                float("softness", vignette.softness);
+                if let Some(location) = program.uniform_location("vignette_color") {
+                    let color = vignette.color;
+                    context.uniform3f(Some(&location), color.r, color.g, color.b);
+                }
+            }
+            PostEffect::Crt(crt) => {
+                float("curvature", crt.curvature);
+                float("scanline_intensity", crt.scanline_intensity);
+                float("scanline_count", crt.scanline_count);
+            }
+            PostEffect::Pixelate(pixelate) => float("pixel_size", pixelate.pixel_size),
+            PostEffect::ChromaticAberration(aberration) => float("offset", aberration.offset),
+            PostEffect::ColorGrade(grade) => {
+                renderer.use_texture_unit(1, &grade.lut.borrow().webl_gl_texture);
+                int("lut_sampler", 1);
+                float("lut_size", grade.size);
+                float("amount", grade.amount);
+            }
+            PostEffect::Grayscale(grayscale) => {
+                set_tone_matrix(context, program, &tone_matrix(&GRAYSCALE, grayscale.amount))
+            }
+            PostEffect::Sepia(sepia) => {
+                set_tone_matrix(context, program, &tone_matrix(&SEPIA, sepia.amount))
+            }
+            PostEffect::FilmGrain(grain) => {
+                float("intensity", grain.intensity);
+                float("grain_size", grain.size);
+            }
+        }
+    }
+}
+
+/* Normalized weights for the center tap and each tap to one side of it, and the spacing between
+taps in pixels, which grows past 1 when the radius needs more taps than the shader has */
+pub fn gaussian_weights(radius: f32, sigma: f32) -> (Vec<f32>, f32) {
+    if radius <= 0.0 || sigma <= 0.0 {
+        return (vec![1.0], 1.0);
+    }
+
+    let taps = (radius.ceil() as usize + 1).min(MAX_BLUR_TAPS);
+    let spacing = (radius / (taps - 1).max(1) as f32).max(1.0);
+
+    let mut weights = (0..taps)
+        .map(|tap| {
+            let distance = tap as f32 * spacing;
+            (-(distance * distance) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect::<Vec<_>>();
+
+    /* Every tap but the center is sampled on both sides */
+    let total = weights[0] + weights[1..].iter().sum::<f32>() * 2.0;
+    for weight in &mut weights {
+        *weight /= total;
+    }
+
+    (weights, spacing)
+}
+
+/* Blends the identity with `rows` by `amount`, column-major for a GLSL mat3 */
+pub fn tone_matrix(rows: &[[f32; 3]; 3], amount: f32) -> [f32; 9] {
+    let amount = amount.clamp(0.0, 1.0);
+    let mut matrix = [0.0; 9];
+    for row in 0..3 {
+        for column in 0..3 {
+            let identity = if row == column { 1.0 } else { 0.0 };
+            matrix[column * 3 + row] = identity + (rows[row][column] - identity) * amount;
+        }
+    }
+    matrix
+}
+
+fn set_tone_matrix(context: &WebGl2RenderingContext, program: &Program, matrix: &[f32; 9]) {
+    if let Some(location) = program.uniform_location("tone_matrix") {
+        context.uniform_matrix3fv_with_f32_array(Some(&location), false, matrix);
+    }
+}
+
+/* An effect in a chain, with the program linked for it when it was added. Tweaking its
+parameters is fine, a different kind of effect has to be added again */
+pub struct AttachedEffect {
+    pub effect: PostEffect,
+    pub program: Rc<Program>,
+}
+
+/* A camera's chain of effects, each drawing the previous one's output into the other of two
+targets the size of the camera's target */
+pub struct PostEffects {
+    /* Run in order, reorder or remove them freely */
+    pub effects: Vec<AttachedEffect>,
+    /* Seconds since the chain was created, for animated effects like FilmGrain */
+    pub time: f32,
+
+    pub targets: [PostProcessTarget; 2],
+}
+
+impl PostEffects {
+    /* `filter` is how the output is sampled when drawn to the screen, see PostProcessTarget::new */
+    pub fn new(renderer: &Renderer, width: i32, height: i32, filter: u32) -> PostEffects {
+        PostEffects {
+            effects: Vec::new(),
+            time: 0.0,
+
+            targets: PostEffects::create_targets(renderer, width, height, filter),
+        }
+    }
+
+    fn create_targets(
+        renderer: &Renderer,
+        width: i32,
+        height: i32,
+        filter: u32,
+    ) -> [PostProcessTarget; 2] {
+        [0, 1].map(|_| renderer.create_render_target(width, height, filter, false))
+    }
+
+    /* Recreates the targets, also how they come back after the context is restored */
+    pub fn resize(&mut self, renderer: &Renderer, width: i32, height: i32, filter: u32) {
+        self.targets = PostEffects::create_targets(renderer, width, height, filter);
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        self.time += delta_time;
+    }
+
+    /* Appends `effect`, linking its program now rather than every frame */
+    pub fn add(&mut self, renderer: &Renderer, effect: PostEffect) -> &mut AttachedEffect {
+        let program = renderer.create_program(None, Some(effect.fragment_source()));
+        self.effects.push(AttachedEffect { effect, program });
+        self.effects.last_mut().unwrap()
+    }
+
+    fn pass_count(&self) -> usize {
+        self.effects
+            .iter()
+            .map(|attached| attached.effect.passes())
+            .sum()
+    }
+
+    /* Target holding the last pass, None without effects */
+    pub fn output(&self) -> Option<&PostProcessTarget> {
+        let passes = self.pass_count();
+        (passes > 0).then(|| &self.targets[(passes - 1) % 2])
+    }
+
+    /* Runs every effect in order over `source`, returning the texture the last one drew into.
+    Leaves the last target bound */
+    pub fn render<'a>(&'a self, renderer: &Renderer, source: &'a WebGlTexture) -> &'a WebGlTexture {
+        let mut texture = source;
+        let mut index = 0;
+
+        for attached in &self.effects {
+            for pass in 0..attached.effect.passes() {
+                let target = &self.targets[index % 2];
+                self.draw_pass(
+                    renderer,
+                    &attached.effect,
+                    &attached.program,
+                    pass,
+                    texture,
+                    target,
+                );
+
+                texture = &target.texture;
+                index += 1;
+            }
+        }
+
+        texture
+    }
+
+    fn draw_pass(
+        &self,
+        renderer: &Renderer,
+        effect: &PostEffect,
+        program: &Program,
+        pass: usize,
+        source: &WebGlTexture,
+        target: &PostProcessTarget,
+    ) {
+        renderer.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&target.frame_buffer_draw),
+        );
+        renderer.set_viewport(0, 0, target.width, target.height);
+        /* Blending over the previous frame's pass would smear it in */
+        renderer.clear_color(0.0, 0.0, 0.0, 0.0);
+
+        renderer.set_alpha_blend(true);
+        renderer.use_program(program);
+
+        let texel_size = [1.0 / target.width as f32, 1.0 / target.height as f32];
+        let context = &renderer.context;
+        if let Some(location) = program.uniform_location("texel_size") {
+            context.uniform2f(Some(&location), texel_size[0], texel_size[1]);
+        }
+        if let Some(location) = program.uniform_location("time") {
+            context.uniform1f(Some(&location), self.time);
+        }
+        effect.set_uniforms(renderer, program, pass, texel_size);
+        renderer.use_texture(source);
+
+        let vertices = render::quad_vertices(&BASE_QUAD_VERTS, Rect::UNIT, Color::WHITE);
+        renderer.draw_quads(&renderer.quads_buffer, program, &vertices, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(weights: &[f32]) -> f32 {
+        weights[0] + weights[1..].iter().sum::<f32>() * 2.0
+    }
+
+    #[test]
+    fn gaussian_weights_sum_to_one_across_both_sides() {
+        for (radius, sigma) in [(1.0, 0.5), (4.0, 2.0), (9.5, 3.0), (200.0, 60.0)] {
+            let (weights, _) = gaussian_weights(radius, sigma);
+            assert!((total(&weights) - 1.0).abs() < 1e-5, "{} {}", radius, sigma);
+            assert!(weights.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+    }
+
+    #[test]
+    fn no_blur_is_a_single_full_tap() {
+        assert_eq!(gaussian_weights(0.0, 2.0), (vec![1.0], 1.0));
+        assert_eq!(gaussian_weights(4.0, 0.0), (vec![1.0], 1.0));
+        assert_eq!(gaussian_weights(-3.0, 1.0), (vec![1.0], 1.0));
+    }
+
+    #[test]
+    fn small_radii_sample_every_pixel() {
+        let (weights, spacing) = gaussian_weights(4.0, 2.0);
+        assert_eq!(weights.len(), 5);
+        assert_eq!(spacing, 1.0);
+
+        /* A fractional radius still reaches past it */
+        let (weights, spacing) = gaussian_weights(2.5, 1.0);
+        assert_eq!(weights.len(), 4);
+        assert_eq!(spacing, 1.0);
+    }
+
+    #[test]
+    fn large_radii_spread_the_capped_taps() {
+        let (weights, spacing) = gaussian_weights(93.0, 30.0);
+        assert_eq!(weights.len(), MAX_BLUR_TAPS);
+        assert_eq!(spacing, 3.0);
+        assert_eq!((weights.len() - 1) as f32 * spacing, 93.0);
+    }
+
+    #[test]
+    fn tone_matrix_at_zero_is_the_identity() {
+        assert_eq!(
+            tone_matrix(&SEPIA, 0.0),
+            [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]
+        );
+        /* Clamped below */
+        assert_eq!(tone_matrix(&SEPIA, -1.0), tone_matrix(&SEPIA, 0.0));
+    }
+
+    #[test]
+    fn tone_matrix_at_one_is_the_rows_column_major() {
+        let matrix = tone_matrix(&SEPIA, 1.0);
+        for row in 0..3 {
+            for column in 0..3 {
+                assert!((matrix[column * 3 + row] - SEPIA[row][column]).abs() < 1e-6);
+            }
+        }
+        /* Clamped above */
+        assert_eq!(tone_matrix(&SEPIA, 2.0), matrix);
+    }
+
+    #[test]
+    fn tone_matrix_blends_halfway() {
+        let matrix = tone_matrix(&GRAYSCALE, 0.5);
+        assert!((matrix[0] - (1.0 + LUMA[0]) / 2.0).abs() < 1e-6);
+        assert!((matrix[3] - LUMA[1] / 2.0).abs() < 1e-6);
+        assert!((matrix[1] - LUMA[0] / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn grayscale_keeps_white_white() {
+        let matrix = tone_matrix(&GRAYSCALE, 1.0);
+        for row in 0..3 {
+            let sum: f32 = (0..3).map(|column| matrix[column * 3 + row]).sum();
+            assert!((sum - 1.0).abs() < 1e-6);
+        }
+    }
+}
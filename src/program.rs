@@ -0,0 +1,233 @@
+#![allow(unused)]
+
+use std::cell::{OnceCell, Ref, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use web_sys::{WebGl2RenderingContext, WebGlProgram, WebGlUniformLocation};
+
+#[derive(Clone)]
+pub struct AttributeInfo {
+    pub location: u32,
+    pub gl_type: u32,
+    pub size: i32,
+}
+
+#[derive(Clone)]
+pub struct UniformInfo {
+    pub location: WebGlUniformLocation,
+    pub gl_type: u32,
+    pub size: i32,
+}
+
+/* A linked program with its active attributes and uniforms looked up once at creation */
+pub struct Program {
+    /* source_key of the sources, hashed once so caches don't hash the shader text again */
+    pub key: u64,
+
+    /* Kept so the program can be linked again after the context is lost */
+    pub vertex_source: String,
+    pub fragment_source: String,
+
+    /* Replaced in place on relink, so every Rc<Program> handed out stays usable */
+    pub linked: RefCell<LinkedProgram>,
+
+    /* This fragment stage behind the instanced vertex shader, see Renderer::instanced_program */
+    pub instanced_variant: OnceCell<Rc<Program>>,
+}
+
+/* Everything that belongs to one GL context */
+pub struct LinkedProgram {
+    pub program: WebGlProgram,
+    pub layout_key: u64,
+    /* Reads per-sprite data through divisor 1 attributes */
+    pub instanced: bool,
+
+    pub attributes: HashMap<String, AttributeInfo>,
+    pub uniforms: HashMap<String, UniformInfo>,
+}
+
+impl LinkedProgram {
+    pub fn new(context: &WebGl2RenderingContext, program: WebGlProgram) -> LinkedProgram {
+        /* A lost context links to a null stand-in (see render::created), which can't be queried */
+        let (attributes, uniforms) = match context.is_context_lost() {
+            true => (HashMap::new(), HashMap::new()),
+            false => (
+                Program::reflect_attributes(context, &program),
+                Program::reflect_uniforms(context, &program),
+            ),
+        };
+        let layout_key = Program::attribute_layout_key(&attributes);
+        let instanced = attributes.contains_key("instance_position");
+
+        LinkedProgram {
+            program,
+            layout_key,
+            instanced,
+
+            attributes,
+            uniforms,
+        }
+    }
+}
+
+impl Program {
+    pub fn new(
+        context: &WebGl2RenderingContext,
+        program: WebGlProgram,
+        vertex_source: &str,
+        fragment_source: &str,
+    ) -> Program {
+        Program {
+            key: Program::source_key(vertex_source, fragment_source),
+
+            vertex_source: vertex_source.to_string(),
+            fragment_source: fragment_source.to_string(),
+
+            linked: RefCell::new(LinkedProgram::new(context, program)),
+
+            instanced_variant: OnceCell::new(),
+        }
+    }
+
+    /* Swaps in a program linked from the same sources on a restored context */
+    pub fn relink(&self, context: &WebGl2RenderingContext, program: WebGlProgram) {
+        *self.linked.borrow_mut() = LinkedProgram::new(context, program);
+    }
+
+    pub fn gl_program(&self) -> WebGlProgram {
+        self.linked.borrow().program.clone()
+    }
+
+    pub fn layout_key(&self) -> u64 {
+        self.linked.borrow().layout_key
+    }
+
+    pub fn source_key(vertex_source: &str, fragment_source: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        vertex_source.hash(&mut hasher);
+        fragment_source.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /* Programs sharing attribute names and locations can share a vertex array object */
+    fn attribute_layout_key(attributes: &HashMap<String, AttributeInfo>) -> u64 {
+        let mut layout: Vec<(&String, u32, u32)> = attributes
+            .iter()
+            .map(|(name, attribute)| (name, attribute.location, attribute.gl_type))
+            .collect();
+        layout.sort();
+
+        let mut hasher = DefaultHasher::new();
+        layout.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn is_instanced(&self) -> bool {
+        self.linked.borrow().instanced
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<AttributeInfo> {
+        self.linked.borrow().attributes.get(name).cloned()
+    }
+
+    pub fn attribute_location(&self, name: &str) -> Option<u32> {
+        self.attribute(name).map(|attribute| attribute.location)
+    }
+
+    pub fn uniform(&self, name: &str) -> Option<UniformInfo> {
+        self.linked.borrow().uniforms.get(name).cloned()
+    }
+
+    pub fn uniform_location(&self, name: &str) -> Option<WebGlUniformLocation> {
+        self.uniform(name).map(|uniform| uniform.location)
+    }
+
+    fn reflect_attributes(
+        context: &WebGl2RenderingContext,
+        program: &WebGlProgram,
+    ) -> HashMap<String, AttributeInfo> {
+        let count = context
+            .get_program_parameter(program, WebGl2RenderingContext::ACTIVE_ATTRIBUTES)
+            .as_f64()
+            .unwrap_or(0.0) as u32;
+
+        let mut attributes = HashMap::new();
+        for index in 0..count {
+            if let Some(info) = context.get_active_attrib(program, index) {
+                let name = info.name();
+                let location = context.get_attrib_location(program, &name);
+
+                /* Built-ins like gl_VertexID are reported as active but have no location */
+                if location < 0 {
+                    continue;
+                }
+
+                attributes.insert(
+                    name,
+                    AttributeInfo {
+                        location: location as u32,
+                        gl_type: info.type_(),
+                        size: info.size(),
+                    },
+                );
+            }
+        }
+
+        attributes
+    }
+
+    fn reflect_uniforms(
+        context: &WebGl2RenderingContext,
+        program: &WebGlProgram,
+    ) -> HashMap<String, UniformInfo> {
+        let count = context
+            .get_program_parameter(program, WebGl2RenderingContext::ACTIVE_UNIFORMS)
+            .as_f64()
+            .unwrap_or(0.0) as u32;
+
+        let mut uniforms = HashMap::new();
+        for index in 0..count {
+            if let Some(info) = context.get_active_uniform(program, index) {
+                let name = info.name();
+                let Some(location) = context.get_uniform_location(program, &name) else {
+                    continue;
+                };
+
+                /* Arrays are reported as "name[0]", store them under the plain name as well */
+                let plain_name = name.strip_suffix("[0]").map(String::from);
+
+                let uniform = UniformInfo {
+                    location,
+                    gl_type: info.type_(),
+                    size: info.size(),
+                };
+
+                if let Some(plain_name) = plain_name {
+                    uniforms.insert(
+                        plain_name,
+                        UniformInfo {
+                            location: uniform.location.clone(),
+                            gl_type: uniform.gl_type,
+                            size: uniform.size,
+                        },
+                    );
+                }
+                uniforms.insert(name, uniform);
+            }
+        }
+
+        uniforms
+    }
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Program) -> bool {
+        /* The key is only a hash, the sources settle collisions */
+        self.key == other.key
+            && self.vertex_source == other.vertex_source
+            && self.fragment_source == other.fragment_source
+    }
+}
@@ -0,0 +1,563 @@
+#![allow(unused)]
+
+use std::borrow::Cow;
+
+use crate::decode::DecodedImage;
+
+/* Colors are bucketed to 5 bits per channel for the histogram and the nearest color cache */
+const CHANNEL_BITS: u32 = 5;
+const BUCKETS: usize = 1 << (CHANNEL_BITS * 3);
+/* Index 255 is kept for transparent pixels */
+const MAX_COLORS: usize = 255;
+const TRANSPARENT_INDEX: u8 = 255;
+const ALPHA_THRESHOLD: u8 = 128;
+
+/* Captures frames from the main loop at a fixed rate until it has enough to encode */
+pub struct Recorder {
+    pub frame_count: usize,
+    /* Seconds between captures */
+    pub interval: f32,
+    pub dither: bool,
+
+    pub frames: Vec<DecodedImage>,
+    pub elapsed: f32,
+}
+
+impl Recorder {
+    pub fn new(frame_count: usize, framerate: f32, dither: bool) -> Recorder {
+        let interval = 1.0 / framerate.max(1.0);
+        Recorder {
+            frame_count,
+            interval,
+            dither,
+
+            frames: Vec::with_capacity(frame_count),
+            /* The first frame is captured right away */
+            elapsed: interval,
+        }
+    }
+
+    /* Advances the clock, returns true when this frame should be captured */
+    pub fn due(&mut self, delta_time: f32) -> bool {
+        if self.finished() {
+            return false;
+        }
+
+        self.elapsed += delta_time;
+        if self.elapsed < self.interval {
+            return false;
+        }
+
+        /* Don't try to catch up after a long frame, just capture this one */
+        self.elapsed = (self.elapsed - self.interval).min(self.interval);
+        true
+    }
+
+    pub fn push(&mut self, frame: DecodedImage) {
+        if !self.finished() {
+            self.frames.push(frame);
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frames.len() >= self.frame_count
+    }
+
+    /* Hands the frames over to be encoded a step at a time */
+    pub fn into_encoder(self) -> Result<GifEncoder, String> {
+        GifEncoder::new(self.frames, self.interval, self.dither)
+    }
+}
+
+/* One palette shared by every frame so colors don't flicker between frames */
+pub struct Palette {
+    pub colors: Vec<[u8; 3]>,
+    /* Nearest palette index for each 5-bit bucket, filled on demand */
+    cache: Vec<Option<u8>>,
+}
+
+impl Palette {
+    /* Median cut over the opaque pixels of every frame */
+    pub fn median_cut(frames: &[DecodedImage], max_colors: usize) -> Palette {
+        let mut histogram = vec![0u32; BUCKETS];
+        for frame in frames {
+            count_colors(&mut histogram, frame);
+        }
+        Palette::from_histogram(&histogram, max_colors)
+    }
+
+    /* Median cut over pixel counts per 5-bit bucket, see count_colors */
+    pub fn from_histogram(histogram: &[u32], max_colors: usize) -> Palette {
+        let colors = histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| (unbucket(index), *count))
+            .collect::<Vec<_>>();
+
+        let mut boxes = vec![colors];
+        while boxes.len() < max_colors.clamp(1, MAX_COLORS) {
+            /* Split the box that covers the most pixels over the widest range */
+            let Some((index, _)) = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, colors)| colors.len() > 1)
+                .map(|(index, colors)| {
+                    let (_, range) = widest_channel(colors);
+                    let count = colors.iter().map(|(_, count)| *count as u64).sum::<u64>();
+                    (index, range as u64 * count)
+                })
+                .max_by_key(|(_, score)| *score)
+            else {
+                break;
+            };
+
+            let mut colors = boxes.swap_remove(index);
+            let (channel, _) = widest_channel(&colors);
+            colors.sort_unstable_by_key(|(color, _)| color[channel]);
+
+            let total = colors.iter().map(|(_, count)| *count as u64).sum::<u64>();
+            let mut running = 0;
+            let mut split = colors.len() / 2;
+            for (position, (_, count)) in colors.iter().enumerate() {
+                running += *count as u64;
+                if running * 2 >= total {
+                    split = (position + 1).clamp(1, colors.len() - 1);
+                    break;
+                }
+            }
+
+            let upper = colors.split_off(split);
+            boxes.push(colors);
+            boxes.push(upper);
+        }
+
+        let colors = boxes
+            .iter()
+            .filter(|colors| !colors.is_empty())
+            .map(|colors| {
+                let total = colors.iter().map(|(_, count)| *count as u64).sum::<u64>();
+                let mut sum = [0u64; 3];
+                for (color, count) in colors {
+                    for channel in 0..3 {
+                        sum[channel] += color[channel] as u64 * *count as u64;
+                    }
+                }
+                sum.map(|channel| (channel / total) as u8)
+            })
+            .collect();
+
+        Palette {
+            colors,
+            cache: vec![None; BUCKETS],
+        }
+    }
+
+    pub fn nearest(&mut self, red: u8, green: u8, blue: u8) -> u8 {
+        let key = bucket(red, green, blue);
+        if let Some(index) = self.cache[key] {
+            return index;
+        }
+
+        let color = unbucket(key);
+        let index = self
+            .colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, candidate)| {
+                (0..3)
+                    .map(|channel| {
+                        let difference = candidate[channel] as i32 - color[channel] as i32;
+                        difference * difference
+                    })
+                    .sum::<i32>()
+            })
+            .map_or(0, |(index, _)| index as u8);
+
+        self.cache[key] = Some(index);
+        index
+    }
+
+    /* Palette indices for a frame, with Floyd-Steinberg error diffusion when dithering */
+    pub fn map(&mut self, frame: &DecodedImage, dither: bool) -> Vec<u8> {
+        let width = frame.width as usize;
+        let height = frame.height as usize;
+        let mut indices = vec![TRANSPARENT_INDEX; width * height];
+
+        /* Error carried into the current and next row */
+        let mut current = vec![[0i32; 3]; width + 2];
+        let mut next = vec![[0i32; 3]; width + 2];
+
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (y * width + x) * 4;
+                let pixel = &frame.pixels[offset..offset + 4];
+                if pixel[3] < ALPHA_THRESHOLD {
+                    continue;
+                }
+
+                let error = current[x + 1];
+                let wanted = [0, 1, 2].map(|channel| {
+                    let value = pixel[channel] as i32;
+                    if dither {
+                        (value + error[channel] / 16).clamp(0, 255)
+                    } else {
+                        value
+                    }
+                });
+
+                let index = self.nearest(wanted[0] as u8, wanted[1] as u8, wanted[2] as u8);
+                indices[y * width + x] = index;
+
+                if dither {
+                    let chosen = self.colors[index as usize];
+                    for channel in 0..3 {
+                        let difference = wanted[channel] - chosen[channel] as i32;
+                        current[x + 2][channel] += difference * 7;
+                        next[x][channel] += difference * 3;
+                        next[x + 1][channel] += difference * 5;
+                        next[x + 2][channel] += difference;
+                    }
+                }
+            }
+
+            std::mem::swap(&mut current, &mut next);
+            next.fill([0; 3]);
+        }
+
+        indices
+    }
+
+    /* Flat RGB table padded to 256 entries, the last one standing in for transparency */
+    pub fn to_gif_palette(&self) -> Vec<u8> {
+        let mut palette = vec![0; 256 * 3];
+        for (index, color) in self.colors.iter().enumerate() {
+            palette[index * 3..index * 3 + 3].copy_from_slice(color);
+        }
+        palette
+    }
+}
+
+enum EncodeStage {
+    /* Building the histogram, one frame per step */
+    Counting(Vec<u32>),
+    /* Mapping and compressing, one frame per step */
+    Writing(Palette, gif::Encoder<Vec<u8>>),
+    Done,
+}
+
+/* encode_gif split into steps of about a frame's worth of work, so it can run from the main loop
+without stalling it */
+pub struct GifEncoder {
+    pub frames: Vec<DecodedImage>,
+    pub width: u16,
+    pub height: u16,
+    /* Hundredths of a second, what GIF stores */
+    pub delay: u16,
+    pub dither: bool,
+
+    /* Next frame for the current stage */
+    pub next: usize,
+    stage: EncodeStage,
+}
+
+impl GifEncoder {
+    /* Every frame must be the same size, `delay` is in seconds */
+    pub fn new(frames: Vec<DecodedImage>, delay: f32, dither: bool) -> Result<GifEncoder, String> {
+        let Some(first) = frames.first() else {
+            return Err(String::from("No frames to encode"));
+        };
+        if frames
+            .iter()
+            .any(|frame| frame.width != first.width || frame.height != first.height)
+        {
+            return Err(String::from("Frames have different sizes"));
+        }
+
+        let width = u16::try_from(first.width).map_err(|_| String::from("Frames are too wide"))?;
+        let height =
+            u16::try_from(first.height).map_err(|_| String::from("Frames are too tall"))?;
+
+        Ok(GifEncoder {
+            frames,
+            width,
+            height,
+            delay: (delay * 100.0).round().max(1.0) as u16,
+            dither,
+
+            next: 0,
+            stage: EncodeStage::Counting(vec![0; BUCKETS]),
+        })
+    }
+
+    /* Does one step, returns the file once the last frame is written */
+    pub fn step(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let frame = self.frames.get(self.next);
+        self.next += 1;
+
+        match (&mut self.stage, frame) {
+            (EncodeStage::Counting(histogram), Some(frame)) => {
+                count_colors(histogram, frame);
+            }
+            (EncodeStage::Counting(histogram), None) => {
+                let palette = Palette::from_histogram(histogram, MAX_COLORS);
+                let mut encoder = gif::Encoder::new(
+                    Vec::new(),
+                    self.width,
+                    self.height,
+                    &palette.to_gif_palette(),
+                )
+                .map_err(gif_error)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(gif_error)?;
+
+                self.stage = EncodeStage::Writing(palette, encoder);
+                self.next = 0;
+            }
+            (EncodeStage::Writing(palette, encoder), Some(frame)) => {
+                let gif_frame = gif::Frame {
+                    width: self.width,
+                    height: self.height,
+                    delay: self.delay,
+                    /* Cleared so transparent pixels don't show the previous frame */
+                    dispose: gif::DisposalMethod::Background,
+                    transparent: Some(TRANSPARENT_INDEX),
+                    buffer: Cow::Owned(palette.map(frame, self.dither)),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&gif_frame).map_err(gif_error)?;
+            }
+            (EncodeStage::Writing(..), None) => {
+                let EncodeStage::Writing(_, encoder) =
+                    std::mem::replace(&mut self.stage, EncodeStage::Done)
+                else {
+                    unreachable!()
+                };
+                return encoder.into_inner().map(Some).map_err(gif_error);
+            }
+            (EncodeStage::Done, _) => return Err(String::from("GIF already encoded")),
+        }
+
+        Ok(None)
+    }
+}
+
+/* The whole file in one go, see GifEncoder to spread it over frames */
+pub fn encode_gif(frames: Vec<DecodedImage>, delay: f32, dither: bool) -> Result<Vec<u8>, String> {
+    let mut encoder = GifEncoder::new(frames, delay, dither)?;
+    loop {
+        if let Some(bytes) = encoder.step()? {
+            return Ok(bytes);
+        }
+    }
+}
+
+fn gif_error(error: gif::EncodingError) -> String {
+    format!("GIF encoding error: {}", error)
+}
+
+/* Adds the frame's opaque pixels to a histogram of 5-bit buckets */
+fn count_colors(histogram: &mut [u32], frame: &DecodedImage) {
+    for pixel in frame.pixels.chunks_exact(4) {
+        if pixel[3] >= ALPHA_THRESHOLD {
+            histogram[bucket(pixel[0], pixel[1], pixel[2])] += 1;
+        }
+    }
+}
+
+fn bucket(red: u8, green: u8, blue: u8) -> usize {
+    let shift = 8 - CHANNEL_BITS;
+    ((red as usize >> shift) << (CHANNEL_BITS * 2))
+        | ((green as usize >> shift) << CHANNEL_BITS)
+        | (blue as usize >> shift)
+}
+
+/* Center of a bucket in 8-bit color */
+fn unbucket(bucket: usize) -> [u8; 3] {
+    let shift = 8 - CHANNEL_BITS;
+    let mask = (1 << CHANNEL_BITS) - 1;
+    let expand = |value: usize| ((value << shift) | (1 << (shift - 1))) as u8;
+    [
+        expand((bucket >> (CHANNEL_BITS * 2)) & mask),
+        expand((bucket >> CHANNEL_BITS) & mask),
+        expand(bucket & mask),
+    ]
+}
+
+/* Channel with the largest spread in a box, and that spread */
+fn widest_channel(colors: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = colors.iter().fold((255, 0), |(min, max), (color, _)| {
+                (color[channel].min(min), color[channel].max(max))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode;
+
+    fn solid(width: u32, height: u32, pixel: [u8; 4]) -> DecodedImage {
+        DecodedImage::new(width, height, pixel.repeat((width * height) as usize))
+    }
+
+    fn palette(colors: Vec<[u8; 3]>) -> Palette {
+        Palette {
+            colors,
+            cache: vec![None; BUCKETS],
+        }
+    }
+
+    #[test]
+    fn buckets_round_trip_to_their_center() {
+        for value in [0u8, 7, 8, 100, 200, 255] {
+            let [red, green, blue] = unbucket(bucket(value, 255 - value, value / 2));
+            assert_eq!(red >> 3, value >> 3);
+            assert_eq!(green >> 3, (255 - value) >> 3);
+            assert_eq!(blue >> 3, (value / 2) >> 3);
+            assert_eq!(red & 7, 4);
+        }
+    }
+
+    #[test]
+    fn median_cut_keeps_few_colors_apart() {
+        let pixels = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]].concat();
+        let frame = DecodedImage::new(3, 1, pixels);
+
+        let mut colors = Palette::median_cut(&[frame], 16).colors;
+        colors.sort();
+        assert_eq!(colors, [[4, 4, 252], [4, 252, 4], [252, 4, 4]]);
+    }
+
+    #[test]
+    fn median_cut_weights_by_pixel_count() {
+        let mut pixels = [0, 0, 0, 255].repeat(3);
+        pixels.extend_from_slice(&[255, 255, 255, 255]);
+        let frame = DecodedImage::new(4, 1, pixels);
+
+        /* One color left for three black pixels and one white */
+        let colors = Palette::median_cut(&[frame], 1).colors;
+        assert_eq!(colors, [[66, 66, 66]]);
+    }
+
+    #[test]
+    fn median_cut_ignores_transparent_pixels_and_caps_colors() {
+        let transparent = solid(2, 2, [255, 0, 0, 10]);
+        let opaque = solid(2, 2, [0, 0, 255, 255]);
+        let colors = Palette::median_cut(&[transparent, opaque], 8).colors;
+        assert_eq!(colors, [[4, 4, 252]]);
+
+        let gradient: Vec<u8> = (0..=255u8)
+            .flat_map(|value| [value, 255 - value, value.wrapping_mul(7), 255])
+            .collect();
+        let frame = DecodedImage::new(256, 1, gradient);
+        assert_eq!(
+            Palette::median_cut(std::slice::from_ref(&frame), 16)
+                .colors
+                .len(),
+            16
+        );
+        assert!(Palette::median_cut(&[frame], 1000).colors.len() <= MAX_COLORS);
+    }
+
+    #[test]
+    fn maps_to_nearest_colors_and_transparency() {
+        let mut palette = palette(vec![[0, 0, 0], [255, 255, 255], [255, 0, 0]]);
+        let pixels = [
+            [10, 10, 10, 255],
+            [240, 250, 230, 255],
+            [200, 30, 20, 200],
+            [255, 255, 255, 0],
+        ]
+        .concat();
+        let frame = DecodedImage::new(4, 1, pixels);
+        assert_eq!(palette.map(&frame, false), [0, 1, 2, TRANSPARENT_INDEX]);
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_brightness() {
+        let frame = solid(16, 16, [128, 128, 128, 255]);
+        let mut palette = palette(vec![[0, 0, 0], [255, 255, 255]]);
+
+        let plain = palette.map(&frame, false);
+        assert!(plain.iter().all(|index| *index == plain[0]));
+
+        let dithered = palette.map(&frame, true);
+        let white = dithered.iter().filter(|index| **index == 1).count();
+        assert!((120..=136).contains(&white), "{} white pixels", white);
+    }
+
+    #[test]
+    fn encoded_gifs_decode_back() {
+        let red = solid(2, 2, [255, 0, 0, 255]);
+        let mut blue = solid(2, 2, [0, 0, 255, 255]);
+        blue.pixels[12..16].fill(0);
+
+        let bytes = encode_gif(vec![red, blue], 0.05, true).unwrap();
+        let gif = decode::decode_gif(&bytes).unwrap();
+
+        assert_eq!((gif.width, gif.height), (2, 2));
+        assert_eq!(gif.loop_count, None);
+        assert_eq!(gif.frames.len(), 2);
+        assert_eq!(gif.frames[0].delay, 0.05);
+
+        assert_eq!(gif.frames[0].image.pixel(1, 1), [252, 4, 4, 255]);
+        assert_eq!(gif.frames[1].image.pixel(0, 0), [4, 4, 252, 255]);
+        /* Disposed to the background rather than showing the red frame through */
+        assert_eq!(gif.frames[1].image.pixel(1, 1), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_mismatched_frames() {
+        assert_eq!(
+            encode_gif(Vec::new(), 0.1, false).unwrap_err(),
+            "No frames to encode"
+        );
+
+        let frames = vec![solid(2, 2, [0; 4]), solid(2, 1, [0; 4])];
+        assert_eq!(
+            encode_gif(frames, 0.1, false).unwrap_err(),
+            "Frames have different sizes"
+        );
+    }
+
+    #[test]
+    fn encodes_a_frame_per_step() {
+        let frames = vec![solid(2, 2, [255, 0, 0, 255]); 3];
+        let expected = encode_gif(frames.clone(), 0.1, false).unwrap();
+
+        let mut encoder = GifEncoder::new(frames, 0.1, false).unwrap();
+        /* Three frames counted, the palette cut, three frames written, then the trailer */
+        for _ in 0..7 {
+            assert_eq!(encoder.step(), Ok(None));
+        }
+        assert_eq!(encoder.step(), Ok(Some(expected)));
+        assert!(encoder.step().is_err());
+    }
+
+    #[test]
+    fn recorder_captures_at_its_framerate() {
+        let mut recorder = Recorder::new(3, 10.0, false);
+
+        assert!(recorder.due(0.0));
+        recorder.push(solid(1, 1, [0; 4]));
+        assert!(!recorder.due(0.05));
+        assert!(recorder.due(0.05));
+        recorder.push(solid(1, 1, [0; 4]));
+
+        /* A long frame captures once without building up a backlog */
+        assert!(recorder.due(1.0));
+        recorder.push(solid(1, 1, [0; 4]));
+        assert!(recorder.finished());
+        assert!(!recorder.due(1.0));
+
+        recorder.push(solid(1, 1, [0; 4]));
+        assert_eq!(recorder.frames.len(), 3);
+    }
+}
@@ -1,16 +1,29 @@
 #![allow(unused)]
 
 use crate::{console_log, log};
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ops::Range,
+    rc::Rc,
+};
 
 use js_sys::{Float32Array, Uint16Array};
-use wasm_bindgen::JsValue;
+use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{
     HtmlImageElement, WebGl2RenderingContext, WebGlBuffer, WebGlFramebuffer, WebGlProgram,
-    WebGlRenderbuffer, WebGlShader, WebGlTexture, WebGlVertexArrayObject,
+    WebGlRenderbuffer, WebGlSampler, WebGlShader, WebGlTexture, WebGlVertexArrayObject,
 };
 
+use crate::capture;
+use crate::clip::{self, Clip};
+use crate::decode::DecodedImage;
+use crate::ktx2::{self, Ktx2, SupportedFormats};
+use crate::math::{Color, Rect};
+use crate::program::Program;
 use crate::render;
+use crate::state::{GlState, StateStats};
+use crate::texture::{self, TextureOptions};
 
 pub const BASE_LEVEL: i32 = 0;
 
@@ -31,75 +44,226 @@ pub const BASE_QUAD_INDICES: [u16; 6] = [
     2, 3, 0, // second triangle
 ];
 
-pub const BASE_VERTEX_SHADER: &str = "#version 300 es
+pub const BASE_VERTEX_SHADER: &str = include_str!("shaders/base.vert");
+pub const BASE_FRAGMENT_SHADER: &str = include_str!("shaders/base.frag");
+pub const INSTANCED_VERTEX_SHADER: &str = include_str!("shaders/instanced.vert");
 
-    in vec3 position;
-    in vec2 vert_texture_coords;
-    out vec2 texture_coords;
+pub struct VertexAttribute {
+    pub name: &'static str,
+    pub size: i32,
+    pub offset: i32,
+}
 
-    void main() {
-        texture_coords = vec2(vert_texture_coords.x, vert_texture_coords.y);
-        gl_Position = vec4(position, 1.0);
-    }";
-pub const BASE_FRAGMENT_SHADER: &str = "#version 300 es
-    precision highp float;
+/* Interleaved x, y, u, v, r, g, b, a */
+pub const VERTEX_FLOATS: usize = 8;
+pub const QUAD_VERTEX_FLOATS: usize = VERTEX_FLOATS * 4;
+pub const QUAD_VERTEX_LAYOUT: [VertexAttribute; 3] = [
+    VertexAttribute {
+        name: "position",
+        size: 2,
+        offset: 0,
+    },
+    VertexAttribute {
+        name: "vert_texture_coords",
+        size: 2,
+        offset: 8,
+    },
+    VertexAttribute {
+        name: "vert_color",
+        size: 4,
+        offset: 16,
+    },
+];
 
-    in vec2 texture_coords;
-    uniform sampler2D texture_sampler;
-    out vec4 output_color;
+/* Per-sprite data for the instanced path (see shaders/instanced.vert) */
+pub const INSTANCE_FLOATS: usize = 17;
+pub const INSTANCE_LAYOUT: [VertexAttribute; 7] = [
+    VertexAttribute {
+        name: "instance_position",
+        size: 2,
+        offset: 0,
+    },
+    VertexAttribute {
+        name: "instance_size",
+        size: 2,
+        offset: 8,
+    },
+    VertexAttribute {
+        name: "instance_scale",
+        size: 2,
+        offset: 16,
+    },
+    VertexAttribute {
+        name: "instance_origin",
+        size: 2,
+        offset: 24,
+    },
+    VertexAttribute {
+        name: "instance_rotation",
+        size: 1,
+        offset: 32,
+    },
+    VertexAttribute {
+        name: "instance_uv_rect",
+        size: 4,
+        offset: 36,
+    },
+    VertexAttribute {
+        name: "instance_color",
+        size: 4,
+        offset: 52,
+    },
+];
 
-    void main() {
-        output_color = texture(texture_sampler, texture_coords);
-    }";
+/* Highest quad count a single batch can address with u16 indices */
+pub const MAX_BATCH_QUADS: usize = (u16::MAX as usize + 1) / 4;
+pub const VERTEX_RING_QUADS: usize = MAX_BATCH_QUADS * 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UploadMode {
+    /* How batches were submitted before the ring: the vertex buffer is reallocated with buffer_data
+    and the index list regenerated and uploaded for every batch. Kept for bench.rs to compare with */
+    Legacy,
+    /* Writes each batch into the next free range of a DYNAMIC_DRAW ring with buffer_sub_data */
+    Stream,
+}
 
 pub struct DrawBuffers {
+    /* Ring that batches of quad vertices or instance data are streamed into */
     pub vertex_buffer: WebGlBuffer,
-    pub uv_buffer: WebGlBuffer,
+    /* Single untransformed quad the instanced path expands every instance from */
+    pub quad_buffer: WebGlBuffer,
     pub index_buffer: WebGlBuffer,
+
+    pub upload_mode: Cell<UploadMode>,
+    /* Size and write cursor of the vertex ring in bytes */
+    pub ring_capacity: Cell<usize>,
+    pub ring_offset: Cell<usize>,
+    /* Quads the index buffer currently covers, Legacy batches shrink it to their own size */
+    pub index_quads: Cell<usize>,
+
+    /* One vertex array per attribute layout (see Program::layout_key) */
+    pub vertex_arrays: RefCell<HashMap<u64, WebGlVertexArrayObject>>,
 }
 
 impl DrawBuffers {
-    pub fn new(context: &WebGl2RenderingContext) -> DrawBuffers {
+    pub fn new(state: &mut GlState) -> DrawBuffers {
+        let context = &state.context;
+
         let vertex_buffer =
             DrawBuffers::create_buffer(context, WebGl2RenderingContext::ARRAY_BUFFER).unwrap();
-        let uv_buffer =
+        let quad_buffer =
             DrawBuffers::create_buffer(context, WebGl2RenderingContext::ARRAY_BUFFER).unwrap();
         let index_buffer =
             DrawBuffers::create_buffer(context, WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER)
                 .unwrap();
 
+        DrawBuffers::upload_buffer_f32(
+            state,
+            WebGl2RenderingContext::ARRAY_BUFFER,
+            &quad_buffer,
+            &quad_vertices(&BASE_QUAD_VERTS, Rect::UNIT, Color::WHITE),
+            WebGl2RenderingContext::STATIC_DRAW,
+        );
+
+        /* Every quad uses the same index pattern, so it is built once for the largest batch */
+        DrawBuffers::upload_buffer_u16(
+            state,
+            WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER,
+            &index_buffer,
+            &quad_indices(MAX_BATCH_QUADS),
+            WebGl2RenderingContext::STATIC_DRAW,
+        );
+
         DrawBuffers {
             vertex_buffer,
-            uv_buffer,
+            quad_buffer,
             index_buffer,
+
+            upload_mode: Cell::new(UploadMode::Stream),
+            ring_capacity: Cell::new(0),
+            ring_offset: Cell::new(0),
+            index_quads: Cell::new(MAX_BATCH_QUADS),
+
+            vertex_arrays: RefCell::new(HashMap::new()),
         }
     }
 
-    pub fn upload_vertices(&self, context: &WebGl2RenderingContext, vertices: &[f32]) {
-        DrawBuffers::upload_buffer_f32(
-            context,
-            WebGl2RenderingContext::ARRAY_BUFFER,
-            &self.vertex_buffer,
-            vertices,
-        );
+    /* Returns the byte offset of the uploaded vertices inside the vertex buffer */
+    pub fn upload_vertices(&self, state: &mut GlState, vertices: &[f32]) -> i32 {
+        match self.upload_mode.get() {
+            UploadMode::Legacy => {
+                DrawBuffers::upload_buffer_f32(
+                    state,
+                    WebGl2RenderingContext::ARRAY_BUFFER,
+                    &self.vertex_buffer,
+                    vertices,
+                    WebGl2RenderingContext::STATIC_DRAW,
+                );
+
+                /* The ring storage is gone, make the next streamed batch reallocate it */
+                self.ring_capacity.set(0);
+                self.ring_offset.set(0);
+                0
+            }
+            UploadMode::Stream => self.stream_vertices(state, vertices),
+        }
     }
 
-    pub fn upload_uvs(&self, context: &WebGl2RenderingContext, uvs: &[f32]) {
-        DrawBuffers::upload_buffer_f32(
-            context,
-            WebGl2RenderingContext::ARRAY_BUFFER,
-            &self.uv_buffer,
-            uvs,
-        );
-    }
+    /* Makes the index buffer cover `quads`. Only Legacy regenerates it per batch, streamed batches
+    restore the full list once after a Legacy one. The vertex array has to be bound */
+    pub fn prepare_indices(&self, state: &mut GlState, quads: usize) {
+        let quads = match self.upload_mode.get() {
+            UploadMode::Legacy => quads,
+            UploadMode::Stream if self.index_quads.get() < quads => MAX_BATCH_QUADS,
+            UploadMode::Stream => return,
+        };
 
-    pub fn upload_indices(&self, context: &WebGl2RenderingContext, indices: &[u16]) {
         DrawBuffers::upload_buffer_u16(
-            context,
+            state,
             WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER,
             &self.index_buffer,
-            indices,
+            &quad_indices(quads),
+            WebGl2RenderingContext::STATIC_DRAW,
+        );
+        self.index_quads.set(quads);
+    }
+
+    fn stream_vertices(&self, state: &mut GlState, vertices: &[f32]) -> i32 {
+        let bytes = std::mem::size_of_val(vertices);
+        let mut offset = self.ring_offset.get();
+
+        state.bind_buffer(
+            WebGl2RenderingContext::ARRAY_BUFFER,
+            Some(&self.vertex_buffer),
         );
+
+        if offset + bytes > self.ring_capacity.get() {
+            /* Orphan the old storage on wrap so we never write over a batch the GPU is still reading */
+            let capacity = (VERTEX_RING_QUADS * QUAD_VERTEX_FLOATS * 4).max(bytes);
+            state.context.buffer_data_with_i32(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                capacity as i32,
+                WebGl2RenderingContext::DYNAMIC_DRAW,
+            );
+
+            self.ring_capacity.set(capacity);
+            offset = 0;
+        }
+
+        unsafe {
+            let js_data = Float32Array::view(vertices);
+            state
+                .context
+                .buffer_sub_data_with_i32_and_array_buffer_view(
+                    WebGl2RenderingContext::ARRAY_BUFFER,
+                    offset as i32,
+                    &js_data,
+                );
+        }
+
+        self.ring_offset.set(offset + bytes);
+        offset as i32
     }
 
     fn create_buffer(
@@ -113,73 +277,136 @@ impl DrawBuffers {
     }
 
     fn upload_buffer_f32(
-        context: &WebGl2RenderingContext,
+        state: &mut GlState,
         buffer_type: u32,
         buffer: &WebGlBuffer,
         data: &[f32],
+        usage: u32,
     ) {
-        context.bind_buffer(buffer_type, Some(buffer));
+        state.bind_buffer(buffer_type, Some(buffer));
 
         unsafe {
             let js_data = Float32Array::view(data);
-            context.buffer_data_with_array_buffer_view(
-                buffer_type,
-                &js_data,
-                WebGl2RenderingContext::STATIC_DRAW,
-            );
+            state
+                .context
+                .buffer_data_with_array_buffer_view(buffer_type, &js_data, usage);
         }
-
-        context.bind_buffer(buffer_type, None);
     }
 
     fn upload_buffer_u16(
-        context: &WebGl2RenderingContext,
+        state: &mut GlState,
         buffer_type: u32,
         buffer: &WebGlBuffer,
         data: &[u16],
+        usage: u32,
     ) {
-        context.bind_buffer(buffer_type, Some(buffer));
+        state.bind_buffer(buffer_type, Some(buffer));
 
         unsafe {
             let js_data = Uint16Array::view(data);
-            context.buffer_data_with_array_buffer_view(
-                buffer_type,
-                &js_data,
-                WebGl2RenderingContext::STATIC_DRAW,
-            );
+            state
+                .context
+                .buffer_data_with_array_buffer_view(buffer_type, &js_data, usage);
         }
+    }
+}
+
+/* Interleaves untransformed quad corners with a UV rect and a flat color */
+pub fn quad_vertices(
+    positions: &[f32; 12],
+    uv_rect: Rect,
+    color: Color,
+) -> [f32; QUAD_VERTEX_FLOATS] {
+    let mut vertices = [0.0; QUAD_VERTEX_FLOATS];
+    for corner in 0..4 {
+        let vertex = &mut vertices[corner * VERTEX_FLOATS..(corner + 1) * VERTEX_FLOATS];
+        vertex[0] = positions[corner * 3];
+        vertex[1] = positions[corner * 3 + 1];
+        vertex[2] = uv_rect.x + BASE_QUAD_UVS[corner * 2] * uv_rect.width;
+        vertex[3] = uv_rect.y + BASE_QUAD_UVS[corner * 2 + 1] * uv_rect.height;
+        vertex[4..8].copy_from_slice(&color.to_array());
+    }
+    vertices
+}
+
+/* Blending always leaves premultiplied color in the target, which is what the canvas and
+post-process passes expect. Straight sources are multiplied by their alpha on the way in */
+pub fn blend_factors(premultiplied: bool) -> (u32, u32, u32, u32) {
+    let source_color = if premultiplied {
+        WebGl2RenderingContext::ONE
+    } else {
+        WebGl2RenderingContext::SRC_ALPHA
+    };
+
+    (
+        source_color,
+        WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA,
+        WebGl2RenderingContext::ONE,
+        WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA,
+    )
+}
+
+/* Splits `quads` into consecutive ranges small enough for every index to fit in a u16 */
+pub fn batch_ranges(quads: usize) -> impl Iterator<Item = Range<usize>> {
+    (0..quads)
+        .step_by(MAX_BATCH_QUADS)
+        .map(move |start| start..(start + MAX_BATCH_QUADS).min(quads))
+}
 
-        context.bind_buffer(buffer_type, None);
+pub fn quad_indices(count: usize) -> Vec<u16> {
+    let mut indices = Vec::with_capacity(BASE_QUAD_INDICES.len() * count);
+    for quad in 0..count {
+        let base = (quad * 4) as u16;
+        for &idx in BASE_QUAD_INDICES.iter() {
+            indices.push(idx + base);
+        }
     }
+    indices
 }
 
 /* Shout outs to the goats over on stack overflow: https://stackoverflow.com/questions/47934444/webgl-framebuffer-multisampling */
 pub struct PostProcessTarget {
+    pub width: i32,
+    pub height: i32,
+
     pub frame_buffer_store: WebGlFramebuffer,
     pub frame_buffer_draw: WebGlFramebuffer,
     pub render_buffer: WebGlRenderbuffer,
+    /* Depth and stencil for the framebuffer that is drawn into, used by clip masks */
+    pub depth_stencil_buffer: WebGlRenderbuffer,
     pub texture: WebGlTexture,
 }
 
 impl PostProcessTarget {
-    pub fn new(context: &WebGl2RenderingContext, width: i32, height: i32) -> PostProcessTarget {
-        let max_samples_supported = context
-            .get_parameter(WebGl2RenderingContext::MAX_SAMPLES)
-            .unwrap()
-            .as_f64()
-            .unwrap() as i32;
-
-        console_log!("Max MSAA samples supported: {}", max_samples_supported);
-
-        let samples = max_samples_supported.min(8);
+    /* `filter` is how the resolved texture is sampled when drawn to the screen */
+    pub fn new(
+        context: &WebGl2RenderingContext,
+        width: i32,
+        height: i32,
+        filter: u32,
+        multisample: bool,
+    ) -> PostProcessTarget {
+        let samples = if multisample {
+            let max_samples_supported = context
+                .get_parameter(WebGl2RenderingContext::MAX_SAMPLES)
+                .ok()
+                .and_then(|samples| samples.as_f64())
+                .unwrap_or(0.0) as i32;
+
+            console_log!("Max MSAA samples supported: {}", max_samples_supported);
+
+            max_samples_supported.min(8)
+        } else {
+            0
+        };
 
-        let frame_buffer_store = context.create_framebuffer().unwrap();
+        let frame_buffer_store = created(context.create_framebuffer());
         context.bind_framebuffer(
             WebGl2RenderingContext::FRAMEBUFFER,
             Some(&frame_buffer_store),
         );
 
-        let render_buffer = context.create_renderbuffer().unwrap();
+        let render_buffer = created(context.create_renderbuffer());
         context.bind_renderbuffer(WebGl2RenderingContext::RENDERBUFFER, Some(&render_buffer));
 
         context.renderbuffer_storage_multisample(
@@ -197,13 +424,33 @@ impl PostProcessTarget {
             Some(&render_buffer),
         );
 
-        let frame_buffer_draw = context.create_framebuffer().unwrap();
+        /* Has to match the color buffer's sample count */
+        let depth_stencil_buffer = created(context.create_renderbuffer());
+        context.bind_renderbuffer(
+            WebGl2RenderingContext::RENDERBUFFER,
+            Some(&depth_stencil_buffer),
+        );
+        context.renderbuffer_storage_multisample(
+            WebGl2RenderingContext::RENDERBUFFER,
+            samples,
+            WebGl2RenderingContext::DEPTH24_STENCIL8,
+            width,
+            height,
+        );
+        context.framebuffer_renderbuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            WebGl2RenderingContext::DEPTH_STENCIL_ATTACHMENT,
+            WebGl2RenderingContext::RENDERBUFFER,
+            Some(&depth_stencil_buffer),
+        );
+
+        let frame_buffer_draw = created(context.create_framebuffer());
         context.bind_framebuffer(
             WebGl2RenderingContext::FRAMEBUFFER,
             Some(&frame_buffer_draw),
         );
 
-        let texture = context.create_texture().unwrap();
+        let texture = created(context.create_texture());
         context.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
         context
             .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
@@ -222,12 +469,22 @@ impl PostProcessTarget {
         context.tex_parameteri(
             WebGl2RenderingContext::TEXTURE_2D,
             WebGl2RenderingContext::TEXTURE_MIN_FILTER,
-            WebGl2RenderingContext::LINEAR as i32,
+            filter as i32,
         );
         context.tex_parameteri(
             WebGl2RenderingContext::TEXTURE_2D,
             WebGl2RenderingContext::TEXTURE_MAG_FILTER,
-            WebGl2RenderingContext::LINEAR as i32,
+            filter as i32,
+        );
+        context.tex_parameteri(
+            WebGl2RenderingContext::TEXTURE_2D,
+            WebGl2RenderingContext::TEXTURE_WRAP_S,
+            WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
+        );
+        context.tex_parameteri(
+            WebGl2RenderingContext::TEXTURE_2D,
+            WebGl2RenderingContext::TEXTURE_WRAP_T,
+            WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
         );
 
         context.framebuffer_texture_2d(
@@ -243,9 +500,13 @@ impl PostProcessTarget {
         context.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
 
         PostProcessTarget {
+            width,
+            height,
+
             frame_buffer_store,
             frame_buffer_draw,
             render_buffer,
+            depth_stencil_buffer,
             texture,
         }
     }
@@ -256,7 +517,20 @@ pub struct Renderer {
     pub quads_buffer: DrawBuffers,
     pub post_process: PostProcessTarget,
 
-    pub base_program: Option<Rc<WebGlProgram>>,
+    pub base_program: Option<Rc<Program>>,
+    /* 1x1 white pixel for drawing flat colors through the base program */
+    pub white_texture: Option<WebGlTexture>,
+    /* Hard texel edges for pixel-perfect cameras, without touching the textures they draw */
+    pub nearest_sampler: WebGlSampler,
+    /* Highest TEXTURE_MAX_ANISOTROPY_EXT allowed, None without EXT_texture_filter_anisotropic */
+    pub max_anisotropy: Option<f32>,
+    pub max_texture_size: u32,
+    /* Compressed texture extensions that were enabled */
+    pub supported_formats: SupportedFormats,
+    /* Keyed by Program::source_key. Sources that failed to link map to the base program, so they
+    aren't compiled again every time they are asked for */
+    pub program_cache: RefCell<HashMap<u64, Rc<Program>>>,
+    pub state: RefCell<GlState>,
 }
 
 thread_local! {
@@ -265,44 +539,186 @@ thread_local! {
 
 impl Renderer {
     pub fn new(context: WebGl2RenderingContext, width: i32, height: i32) -> Renderer {
-        context.enable(WebGl2RenderingContext::BLEND);
-        context.blend_func(
-            WebGl2RenderingContext::SRC_ALPHA,
-            WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA,
+        let mut state = GlState::new(context.clone());
+        state.set_blend(true);
+        let (source_color, destination_color, source_alpha, destination_alpha) =
+            blend_factors(true);
+        state.set_blend_func_separate(
+            source_color,
+            destination_color,
+            source_alpha,
+            destination_alpha,
         );
+        state.set_viewport(0, 0, width, height);
 
-        let quads_buffer = DrawBuffers::new(&context);
-        let post_process = PostProcessTarget::new(&context, width, height);
+        let quads_buffer = DrawBuffers::new(&mut state);
+        let post_process = PostProcessTarget::new(
+            &context,
+            width,
+            height,
+            WebGl2RenderingContext::LINEAR,
+            true,
+        );
+
+        let max_anisotropy = Renderer::query_max_anisotropy(&context);
+        let supported_formats = Renderer::query_supported_formats(&context);
+        let max_texture_size = context
+            .get_parameter(WebGl2RenderingContext::MAX_TEXTURE_SIZE)
+            .ok()
+            .and_then(|size| size.as_f64())
+            .map_or(2048, |size| size as u32);
+
+        let nearest_sampler = Renderer::create_nearest_sampler(&context);
 
         let mut renderer = Renderer {
             context,
             quads_buffer,
             post_process,
             base_program: None,
+            white_texture: None,
+            nearest_sampler,
+            max_anisotropy,
+            max_texture_size,
+            supported_formats,
+            program_cache: RefCell::new(HashMap::new()),
+            state: RefCell::new(state),
         };
 
-        let base_program = Rc::new(renderer.create_base_program());
+        let base_program = renderer.create_program(None, None);
         renderer.base_program = Some(base_program);
 
+        let white_texture =
+            renderer.load_texture_rgba(1, 1, &[255, 255, 255, 255], &TextureOptions::nearest());
+        renderer.white_texture = Some(white_texture);
+
         renderer
     }
 
+    /* Rebuilds every GPU resource after the context was restored. The old programs are linked
+    again in place and adopted, so the Rc<Program>s held by sprites and cameras keep working */
+    pub fn restore(&self) -> Renderer {
+        let context = self.context.clone();
+        let width = context.drawing_buffer_width();
+        let height = context.drawing_buffer_height();
+
+        let mut renderer = Renderer::new(context, width, height);
+        if let Some(program) = renderer.base_program.take() {
+            renderer.context.delete_program(Some(&program.gl_program()));
+        }
+
+        let programs = self.program_cache.borrow();
+        /* Failed sources share the base program, which has an entry of its own */
+        let linked = programs
+            .iter()
+            .filter(|(key, program)| program.key == **key)
+            .map(|(_, program)| program);
+        for program in linked {
+            match renderer.link_program(&program.vertex_source, &program.fragment_source) {
+                Some(linked) => program.relink(&renderer.context, linked),
+                None => console_log!("Unable to relink program {}", program.key),
+            }
+        }
+
+        renderer.base_program = self.base_program.clone();
+        *renderer.program_cache.borrow_mut() = programs.clone();
+
+        renderer
+    }
+
+    fn create_nearest_sampler(context: &WebGl2RenderingContext) -> WebGlSampler {
+        let sampler = created(context.create_sampler());
+        for parameter in [
+            WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+            WebGl2RenderingContext::TEXTURE_MAG_FILTER,
+        ] {
+            context.sampler_parameteri(&sampler, parameter, WebGl2RenderingContext::NEAREST as i32);
+        }
+        sampler
+    }
+
+    fn query_max_anisotropy(context: &WebGl2RenderingContext) -> Option<f32> {
+        context
+            .get_extension("EXT_texture_filter_anisotropic")
+            .ok()
+            .flatten()?;
+
+        context
+            .get_parameter(texture::MAX_TEXTURE_MAX_ANISOTROPY_EXT)
+            .ok()?
+            .as_f64()
+            .map(|max| max as f32)
+    }
+
+    /* Enabling an extension is what makes its formats usable, so every known one is requested */
+    fn query_supported_formats(context: &WebGl2RenderingContext) -> SupportedFormats {
+        let extensions = ktx2::COMPRESSED_EXTENSIONS
+            .iter()
+            .filter(|name| context.get_extension(name).ok().flatten().is_some())
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+
+        console_log!("Compressed texture extensions: {:?}", extensions);
+
+        SupportedFormats::new(extensions)
+    }
+
     pub fn create_program(
         &self,
         vertex_source: Option<&str>,
         fragment_source: Option<&str>,
-    ) -> WebGlProgram {
+    ) -> Rc<Program> {
         let vertex_source = vertex_source.unwrap_or(BASE_VERTEX_SHADER);
         let fragment_source = fragment_source.unwrap_or(BASE_FRAGMENT_SHADER);
 
+        let key = Program::source_key(vertex_source, fragment_source);
+        let cached = self.program_cache.borrow().get(&key).cloned();
+        /* An entry of its own with other sources is a hash collision, those are linked uncached */
+        let collided = cached.as_ref().is_some_and(|program| {
+            program.key == key
+                && (program.vertex_source != vertex_source
+                    || program.fragment_source != fragment_source)
+        });
+        match cached {
+            Some(program) if !collided => return program,
+            Some(_) => console_log!("Program key {} collided, linking it uncached", key),
+            None => (),
+        }
+
+        let program = match self.link_program(vertex_source, fragment_source) {
+            Some(program) => Rc::new(Program::new(
+                &self.context,
+                program,
+                vertex_source,
+                fragment_source,
+            )),
+            None => self
+                .base_program
+                .clone()
+                .expect("Unable to link the base program"),
+        };
+
+        if !collided {
+            self.program_cache.borrow_mut().insert(key, program.clone());
+        }
+
+        program
+    }
+
+    fn link_program(&self, vertex_source: &str, fragment_source: &str) -> Option<WebGlProgram> {
+        /* Nothing compiles on a lost context. Standing in keeps the sources cached as their own
+        program, which restore relinks */
+        if self.context.is_context_lost() {
+            return Some(created(None));
+        }
+
         let vertex_shader = self
             .compile_vertex_shader(vertex_source)
-            .unwrap_or(self.compile_base_vertex_shader());
+            .unwrap_or_else(|| self.compile_base_vertex_shader());
         let fragment_shader = self
             .compile_fragment_shader(fragment_source)
-            .unwrap_or(self.compile_base_fragment_shader());
+            .unwrap_or_else(|| self.compile_base_fragment_shader());
 
-        let program = self.context.create_program().unwrap();
+        let program = self.context.create_program()?;
         self.context.attach_shader(&program, &vertex_shader);
         self.context.attach_shader(&program, &fragment_shader);
         self.context.link_program(&program);
@@ -317,22 +733,11 @@ impl Renderer {
                 .context
                 .get_program_info_log(&program)
                 .unwrap_or_else(|| "Unknown program linking error".into());
-            return self.create_base_program();
+            crate::console_log!("Program linking error: {}", error_log);
+            return None;
         }
 
-        program
-    }
-
-    fn create_base_program(&self) -> WebGlProgram {
-        let vertex_shader = self.compile_base_vertex_shader();
-        let fragment_shader = self.compile_base_fragment_shader();
-
-        let program = self.context.create_program().unwrap();
-        self.context.attach_shader(&program, &vertex_shader);
-        self.context.attach_shader(&program, &fragment_shader);
-        self.context.link_program(&program);
-
-        program
+        Some(program)
     }
 
     fn compile_base_vertex_shader(&self) -> WebGlShader {
@@ -379,11 +784,15 @@ impl Renderer {
         Some(shader)
     }
 
-    pub fn load_texture_image(&self, image: &HtmlImageElement) -> WebGlTexture {
-        let texture = self.context.create_texture().unwrap();
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
+    pub fn load_texture_image(
+        &self,
+        image: &HtmlImageElement,
+        options: &TextureOptions,
+    ) -> WebGlTexture {
+        let texture = created(self.context.create_texture());
+        self.use_texture(&texture);
 
+        self.set_unpack_premultiply(options.premultiply_alpha);
         self.context
             .tex_image_2d_with_u32_and_u32_and_html_image_element(
                 WebGl2RenderingContext::TEXTURE_2D,
@@ -393,16 +802,178 @@ impl Renderer {
                 WebGl2RenderingContext::UNSIGNED_BYTE,
                 image,
             );
+        self.set_unpack_premultiply(false);
+        self.set_texture_options(&texture, options);
+
+        texture
+    }
 
+    /* Creates an offscreen target, PostProcessTarget::new leaves the texture and framebuffers unbound */
+    pub fn create_render_target(
+        &self,
+        width: i32,
+        height: i32,
+        filter: u32,
+        multisample: bool,
+    ) -> PostProcessTarget {
+        let target = PostProcessTarget::new(&self.context, width, height, filter, multisample);
+
+        let mut state = self.state.borrow_mut();
+        let unit = state.active_unit as usize;
+        state.textures[unit] = None;
+        state.read_framebuffer = None;
+        state.draw_framebuffer = None;
+
+        target
+    }
+
+    /* Uploads tightly packed 8-bit RGBA pixels */
+    pub fn load_texture_rgba(
+        &self,
+        width: i32,
+        height: i32,
+        pixels: &[u8],
+        options: &TextureOptions,
+    ) -> WebGlTexture {
+        let texture = created(self.context.create_texture());
+        self.use_texture(&texture);
+
+        self.set_unpack_premultiply(options.premultiply_alpha);
         self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+            .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                WebGl2RenderingContext::TEXTURE_2D,
+                BASE_LEVEL,
+                WebGl2RenderingContext::RGBA as i32,
+                width,
+                height,
+                0,
+                WebGl2RenderingContext::RGBA,
+                WebGl2RenderingContext::UNSIGNED_BYTE,
+                Some(pixels),
+            )
+            .unwrap();
+        self.set_unpack_premultiply(false);
+        self.set_texture_options(&texture, options);
 
         texture
     }
 
+    /* Uploads every level of a parsed KTX2 file, the caller checks the format is supported first.
+    Mipmaps come from the file since compressed textures can't be generated on the GPU */
+    pub fn load_texture_ktx2(
+        &self,
+        ktx2: &Ktx2,
+        options: &TextureOptions,
+    ) -> Result<(WebGlTexture, TextureOptions), String> {
+        let format = &ktx2.format;
+        if !self.supported_formats.supports(format) {
+            return Err(format!(
+                "{} is not available",
+                format.extension().unwrap_or("Texture format")
+            ));
+        }
+
+        let texture = created(self.context.create_texture());
+        self.use_texture(&texture);
+
+        for (level, data) in ktx2.levels.iter().enumerate() {
+            if format.is_compressed() {
+                self.context.compressed_tex_image_2d_with_u8_array(
+                    WebGl2RenderingContext::TEXTURE_2D,
+                    level as i32,
+                    format.gl_internal_format,
+                    data.width as i32,
+                    data.height as i32,
+                    0,
+                    data.data,
+                );
+            } else {
+                self.context
+                    .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                        WebGl2RenderingContext::TEXTURE_2D,
+                        level as i32,
+                        format.gl_internal_format as i32,
+                        data.width as i32,
+                        data.height as i32,
+                        0,
+                        WebGl2RenderingContext::RGBA,
+                        WebGl2RenderingContext::UNSIGNED_BYTE,
+                        Some(data.data),
+                    )
+                    .map_err(|_| String::from("Unable to upload KTX2 level"))?;
+            }
+        }
+
+        let options = TextureOptions {
+            mipmaps: ktx2.levels.len() > 1,
+            premultiply_alpha: ktx2.premultiplied,
+            ..*options
+        };
+        self.context.tex_parameteri(
+            WebGl2RenderingContext::TEXTURE_2D,
+            WebGl2RenderingContext::TEXTURE_MAX_LEVEL,
+            ktx2.levels.len() as i32 - 1,
+        );
+        self.set_sampling(&options);
+
+        Ok((texture, options))
+    }
+
+    /* Has the browser multiply color by alpha during the next uploads */
+    fn set_unpack_premultiply(&self, premultiply: bool) {
+        self.context.pixel_storei(
+            WebGl2RenderingContext::UNPACK_PREMULTIPLY_ALPHA_WEBGL,
+            premultiply as i32,
+        );
+    }
+
+    /* Applies sampling parameters to an uploaded texture, generating mipmaps from level 0 if asked */
+    pub fn set_texture_options(&self, texture: &WebGlTexture, options: &TextureOptions) {
+        self.use_texture(texture);
+        self.set_sampling(options);
+
+        if options.mipmaps {
+            self.context
+                .generate_mipmap(WebGl2RenderingContext::TEXTURE_2D);
+        }
+    }
+
+    /* Filter, wrap and anisotropy parameters for the texture bound to unit 0 */
+    fn set_sampling(&self, options: &TextureOptions) {
+        let context = &self.context;
+        let parameters = [
+            (
+                WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+                options.min_filter_gl(),
+            ),
+            (
+                WebGl2RenderingContext::TEXTURE_MAG_FILTER,
+                options.mag_filter_gl(),
+            ),
+            (
+                WebGl2RenderingContext::TEXTURE_WRAP_S,
+                options.wrap_s.gl_enum(),
+            ),
+            (
+                WebGl2RenderingContext::TEXTURE_WRAP_T,
+                options.wrap_t.gl_enum(),
+            ),
+        ];
+        for (parameter, value) in parameters {
+            context.tex_parameteri(WebGl2RenderingContext::TEXTURE_2D, parameter, value as i32);
+        }
+
+        if let Some(max_anisotropy) = self.max_anisotropy {
+            context.tex_parameterf(
+                WebGl2RenderingContext::TEXTURE_2D,
+                texture::TEXTURE_MAX_ANISOTROPY_EXT,
+                options.anisotropy.clamp(1.0, max_anisotropy),
+            );
+        }
+    }
+
     pub fn set_texture_filtering(&self, texture: &WebGlTexture, antialiasing: bool) {
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+        self.use_texture(texture);
 
         let filter = if (antialiasing) {
             WebGl2RenderingContext::LINEAR
@@ -420,100 +991,391 @@ impl Renderer {
             WebGl2RenderingContext::TEXTURE_MAG_FILTER,
             filter as i32,
         );
+    }
+
+    /* Binds the vertex array matching the program's layout, recording it the first time */
+    pub fn bind_vertex_array(&self, buffers: &DrawBuffers, program: &Program) {
+        let cached = buffers
+            .vertex_arrays
+            .borrow()
+            .get(&program.layout_key())
+            .cloned();
+
+        if let Some(vertex_array) = cached {
+            self.state
+                .borrow_mut()
+                .bind_vertex_array(Some(&vertex_array), Some(&buffers.index_buffer));
+            return;
+        }
+
+        let vertex_array = created(self.context.create_vertex_array());
+        let mut state = self.state.borrow_mut();
+        state.bind_vertex_array(Some(&vertex_array), None);
+        state.bind_buffer(
+            WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER,
+            Some(&buffers.index_buffer),
+        );
+
+        if program.is_instanced() {
+            state.bind_buffer(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                Some(&buffers.quad_buffer),
+            );
+            self.point_attributes(program, &QUAD_VERTEX_LAYOUT, VERTEX_FLOATS, 0, 0);
+
+            state.bind_buffer(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                Some(&buffers.vertex_buffer),
+            );
+            self.point_attributes(program, &INSTANCE_LAYOUT, INSTANCE_FLOATS, 0, 1);
+        } else {
+            state.bind_buffer(
+                WebGl2RenderingContext::ARRAY_BUFFER,
+                Some(&buffers.vertex_buffer),
+            );
+            self.point_attributes(program, &QUAD_VERTEX_LAYOUT, VERTEX_FLOATS, 0, 0);
+        }
 
+        buffers
+            .vertex_arrays
+            .borrow_mut()
+            .insert(program.layout_key(), vertex_array);
+    }
+
+    /* Points the program's attributes from `layout` at the bound ARRAY_BUFFER, starting at `offset` bytes */
+    fn point_attributes(
+        &self,
+        program: &Program,
+        layout: &[VertexAttribute],
+        stride_floats: usize,
+        offset: i32,
+        divisor: u32,
+    ) {
+        for attribute in layout {
+            if let Some(location) = program.attribute_location(attribute.name) {
+                self.context.enable_vertex_attrib_array(location);
+                self.context.vertex_attrib_pointer_with_i32(
+                    location,
+                    attribute.size,
+                    WebGl2RenderingContext::FLOAT,
+                    false,
+                    (stride_floats * 4) as i32,
+                    offset + attribute.offset,
+                );
+                self.context.vertex_attrib_divisor(location, divisor);
+            }
+        }
+    }
+
+    pub fn bind_frag_uniforms(&self, program: &Program, texture: &WebGlTexture) {
+        self.use_program(program);
+        self.use_texture(texture);
+
+        if let Some(texture_coords_uniform) = program.uniform_location("texture_sampler") {
+            self.context.uniform1i(Some(&texture_coords_uniform), 0);
+        }
+    }
+
+    pub fn use_program(&self, program: &Program) {
+        self.state
+            .borrow_mut()
+            .use_program(Some(&program.gl_program()));
+    }
+
+    /* Reads back a framebuffer (None for the canvas) as straight alpha RGBA, top row first.
+    The canvas is only readable before the frame is handed to the compositor */
+    pub fn read_pixels(
+        &self,
+        framebuffer: Option<&WebGlFramebuffer>,
+        width: i32,
+        height: i32,
+    ) -> Result<DecodedImage, String> {
+        self.bind_framebuffer(WebGl2RenderingContext::READ_FRAMEBUFFER, framebuffer);
+
+        let mut pixels = vec![0; width as usize * height as usize * 4];
         self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+            .read_pixels_with_opt_u8_array(
+                0,
+                0,
+                width,
+                height,
+                WebGl2RenderingContext::RGBA,
+                WebGl2RenderingContext::UNSIGNED_BYTE,
+                Some(&mut pixels),
+            )
+            .map_err(|_| String::from("Unable to read pixels"))?;
+
+        capture::flip_rows(&mut pixels, width as u32, height as u32);
+        capture::unpremultiply(&mut pixels);
+
+        Ok(DecodedImage::new(width as u32, height as u32, pixels))
     }
 
-    pub fn bind_vert_attribs(&self, buffers: &DrawBuffers, program: &WebGlProgram) {
-        self.context.bind_buffer(
-            WebGl2RenderingContext::ARRAY_BUFFER,
-            Some(&buffers.vertex_buffer),
+    /* The whole canvas as it was drawn this frame */
+    pub fn screenshot(&self) -> Result<DecodedImage, String> {
+        self.read_pixels(
+            None,
+            self.context.drawing_buffer_width(),
+            self.context.drawing_buffer_height(),
+        )
+    }
+
+    /* The resolved contents of an offscreen target, before its post-process shader */
+    pub fn capture_target(&self, target: &PostProcessTarget) -> Result<DecodedImage, String> {
+        self.read_pixels(Some(&target.frame_buffer_draw), target.width, target.height)
+    }
+
+    /* Picks the blend function for sources with premultiplied or straight alpha, see blend_factors */
+    pub fn set_alpha_blend(&self, premultiplied: bool) {
+        let (source_color, destination_color, source_alpha, destination_alpha) =
+            blend_factors(premultiplied);
+        self.state.borrow_mut().set_blend_func_separate(
+            source_color,
+            destination_color,
+            source_alpha,
+            destination_alpha,
         );
+    }
 
-        /* Uniform position (vec4) */
-        let position_attrib = self.context.get_attrib_location(program, "position") as u32;
-        self.context.enable_vertex_attrib_array(position_attrib);
-        self.context.vertex_attrib_pointer_with_i32(
-            position_attrib,
-            3, /* Uploading 3 floats (x, y, z) */
-            WebGl2RenderingContext::FLOAT,
-            false,
-            0,
-            0,
+    /* Limits the following draws to the clip, None draws everywhere again. Masks are rebuilt in
+    the stencil buffer each time, so draws sharing a clip should be submitted together */
+    pub fn set_clip(&self, clip: Option<&Clip>) {
+        let Some(clip) = clip else {
+            let mut state = self.state.borrow_mut();
+            state.set_scissor(None);
+            state.set_stencil_test(false);
+            return;
+        };
+
+        {
+            let mut state = self.state.borrow_mut();
+            let viewport = state.viewport;
+            state.set_scissor(clip.scissor.map(|ndc| clip::scissor_box(ndc, viewport)));
+            state.set_stencil_test(!clip.masks.is_empty());
+        }
+
+        if clip.masks.is_empty() {
+            return;
+        }
+
+        let (Some(program), Some(texture)) = (&self.base_program, &self.white_texture) else {
+            return;
+        };
+
+        self.clear_stencil();
+        self.begin_stencil_write(WebGl2RenderingContext::INVERT);
+
+        self.use_program(program);
+        self.use_texture(texture);
+
+        /* Each mask flips its own bit for every triangle covering a pixel, leaving it set inside */
+        let masks = &clip.masks[..clip.masks.len().min(clip::MAX_MASKS)];
+        for (level, mask) in masks.iter().enumerate() {
+            let vertices = clip::fan_quads(mask);
+            self.state.borrow_mut().set_stencil_mask(1 << level);
+            self.draw_quads(
+                &self.quads_buffer,
+                program,
+                &vertices,
+                vertices.len() / QUAD_VERTEX_FLOATS,
+            );
+        }
+
+        /* Then only draw where every mask's bit is set */
+        let bits = (1 << masks.len()) - 1;
+        self.end_stencil_write(bits, bits as u32);
+    }
+
+    pub fn clear_stencil(&self) {
+        self.context.clear_stencil(0);
+        self.state.borrow_mut().set_stencil_mask(0xFF);
+        self.context
+            .clear(WebGl2RenderingContext::STENCIL_BUFFER_BIT);
+    }
+
+    pub fn set_stencil_test(&self, enabled: bool) {
+        self.state.borrow_mut().set_stencil_test(enabled);
+    }
+
+    /* Following draws only apply `operation` to the stencil buffer (REPLACE writes 1), leaving
+    color alone */
+    pub fn begin_stencil_write(&self, operation: u32) {
+        let mut state = self.state.borrow_mut();
+        state.set_color_mask([false; 4]);
+        state.set_stencil_func(WebGl2RenderingContext::ALWAYS, 1, 0xFF);
+        state.set_stencil_op(
+            WebGl2RenderingContext::KEEP,
+            WebGl2RenderingContext::KEEP,
+            operation,
         );
+    }
 
-        self.context.bind_buffer(
-            WebGl2RenderingContext::ARRAY_BUFFER,
-            Some(&buffers.uv_buffer),
+    /* Following draws land where the stencil value under `mask` equals `reference` */
+    pub fn end_stencil_write(&self, reference: i32, mask: u32) {
+        let mut state = self.state.borrow_mut();
+        state.set_color_mask([true; 4]);
+        state.set_stencil_mask(0xFF);
+        state.set_stencil_func(WebGl2RenderingContext::EQUAL, reference, mask);
+        state.set_stencil_op(
+            WebGl2RenderingContext::KEEP,
+            WebGl2RenderingContext::KEEP,
+            WebGl2RenderingContext::KEEP,
         );
+    }
 
-        /* Uniform position (vec4) */
-        let texture_coords_attrib =
-            self.context
-                .get_attrib_location(program, "vert_texture_coords") as u32;
-        self.context
-            .enable_vertex_attrib_array(texture_coords_attrib);
-        self.context.vertex_attrib_pointer_with_i32(
-            texture_coords_attrib,
-            2, /* Uploading 2 floats (x, y) */
-            WebGl2RenderingContext::FLOAT,
-            false,
+    /* Copies what was drawn into the target's framebuffer (multisampled or not) into its texture */
+    pub fn resolve_target(&self, target: &PostProcessTarget) {
+        self.bind_framebuffer(
+            WebGl2RenderingContext::READ_FRAMEBUFFER,
+            Some(&target.frame_buffer_store),
+        );
+        self.bind_framebuffer(
+            WebGl2RenderingContext::DRAW_FRAMEBUFFER,
+            Some(&target.frame_buffer_draw),
+        );
+
+        self.context.blit_framebuffer(
+            0,
             0,
+            target.width,
+            target.height,
             0,
+            0,
+            target.width,
+            target.height,
+            WebGl2RenderingContext::COLOR_BUFFER_BIT,
+            WebGl2RenderingContext::NEAREST,
         );
+    }
 
-        self.context
-            .bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, None);
+    /* Adds the source on top of what is there, used to accumulate lights */
+    pub fn set_additive_blend(&self) {
+        self.state
+            .borrow_mut()
+            .set_blend_func(WebGl2RenderingContext::ONE, WebGl2RenderingContext::ONE);
     }
 
-    pub fn bind_frag_uniforms(&self, program: &WebGlProgram, texture: &WebGlTexture) {
-        self.context
-            .active_texture(WebGl2RenderingContext::TEXTURE0);
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+    pub fn use_texture(&self, texture: &WebGlTexture) {
+        self.use_texture_unit(0, texture);
+    }
 
-        let texture_coords_uniform = self
-            .context
-            .get_uniform_location(program, "texture_sampler")
-            .unwrap();
-        self.context.uniform1i(Some(&texture_coords_uniform), 0);
+    /* For shaders that sample more than one texture, the sampler uniform has to point at `unit` */
+    pub fn use_texture_unit(&self, unit: u32, texture: &WebGlTexture) {
+        self.state.borrow_mut().bind_texture(unit, Some(texture));
+    }
 
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+    pub fn use_sampler(&self, unit: u32, sampler: Option<&WebGlSampler>) {
+        self.state.borrow_mut().bind_sampler(unit, sampler);
     }
 
-    pub fn use_program(&self, program: &WebGlProgram) {
-        self.context.use_program(Some(program));
+    pub fn bind_framebuffer(&self, target: u32, framebuffer: Option<&WebGlFramebuffer>) {
+        self.state
+            .borrow_mut()
+            .bind_framebuffer(target, framebuffer);
     }
 
-    pub fn use_texture(&self, texture: &WebGlTexture) {
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+    pub fn set_viewport(&self, x: i32, y: i32, width: i32, height: i32) {
+        self.state.borrow_mut().set_viewport(x, y, width, height);
     }
 
-    pub fn draw_triangles(&self, count: i32) {
-        self.context.bind_buffer(
-            WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER,
-            Some(&self.quads_buffer.index_buffer),
+    /* Draws `count` quads worth of transformed vertices with the bound program and texture,
+    split into chunks the u16 index buffer can address */
+    pub fn draw_quads(
+        &self,
+        buffers: &DrawBuffers,
+        program: &Program,
+        vertices: &[f32],
+        count: usize,
+    ) {
+        self.bind_vertex_array(buffers, program);
+
+        for quads in batch_ranges(count) {
+            let chunk = &vertices[quads.start * QUAD_VERTEX_FLOATS..quads.end * QUAD_VERTEX_FLOATS];
+            let quads = quads.len();
+            let offset = {
+                let mut state = self.state.borrow_mut();
+                buffers.prepare_indices(&mut state, quads);
+                buffers.upload_vertices(&mut state, chunk)
+            };
+
+            /* Point the attributes at wherever this batch landed in the vertex buffer */
+            self.point_attributes(program, &QUAD_VERTEX_LAYOUT, VERTEX_FLOATS, offset, 0);
+
+            self.draw_triangles((quads * BASE_QUAD_INDICES.len()) as i32);
+        }
+    }
+
+    /* Draws `count` sprites from per-instance data, the transform happens in the vertex shader */
+    pub fn draw_instances(
+        &self,
+        buffers: &DrawBuffers,
+        program: &Program,
+        instances: &[f32],
+        count: usize,
+    ) {
+        self.bind_vertex_array(buffers, program);
+
+        let offset = {
+            let mut state = self.state.borrow_mut();
+            buffers.prepare_indices(&mut state, 1);
+            buffers.upload_vertices(&mut state, &instances[..count * INSTANCE_FLOATS])
+        };
+        self.point_attributes(program, &INSTANCE_LAYOUT, INSTANCE_FLOATS, offset, 1);
+
+        self.context.draw_elements_instanced_with_i32(
+            WebGl2RenderingContext::TRIANGLES,
+            BASE_QUAD_INDICES.len() as i32,
+            WebGl2RenderingContext::UNSIGNED_SHORT,
+            0,
+            count as i32,
         );
+    }
 
+    /* Same fragment stage as `program`, with the instanced vertex shader in front of it. Resolved
+    once per program and kept on it, this runs for every instanced sprite */
+    pub fn instanced_program(&self, program: &Rc<Program>) -> Rc<Program> {
+        if program.is_instanced() {
+            return program.clone();
+        }
+
+        program
+            .instanced_variant
+            .get_or_init(|| {
+                self.create_program(
+                    Some(INSTANCED_VERTEX_SHADER),
+                    Some(&program.fragment_source),
+                )
+            })
+            .clone()
+    }
+
+    pub fn draw_triangles(&self, count: i32) {
         self.context.draw_elements_with_i32(
             WebGl2RenderingContext::TRIANGLES,
             count,
             WebGl2RenderingContext::UNSIGNED_SHORT,
             0,
         );
-
-        self.context.use_program(None);
-        self.context
-            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
     }
 
     pub fn clear_color(&self, red: f32, green: f32, blue: f32, alpha: f32) {
-        self.context.clear_color(red, green, blue, alpha);
+        self.state
+            .borrow_mut()
+            .set_clear_color(red, green, blue, alpha);
         self.context.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
     }
+
+    /* Number of GL state calls issued and avoided since the last call */
+    pub fn take_state_stats(&self) -> StateStats {
+        self.state.borrow_mut().take_stats()
+    }
+}
+
+/* Every create call returns null while the context is lost. GL accepts null wherever an object is
+passed and ignores calls until the context comes back, when Renderer::restore, Assets::restore_textures
+and Camera::restore recreate whatever was stood in for */
+pub fn created<T: JsCast>(object: Option<T>) -> T {
+    object.unwrap_or_else(|| JsValue::NULL.unchecked_into())
 }
 
 pub fn with_renderer<T, F>(f: F) -> T
@@ -526,3 +1388,69 @@ where
         f(renderer_borrow)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTICLES: usize = 100_000;
+
+    #[test]
+    fn batch_ranges_cover_every_quad_once() {
+        let ranges: Vec<Range<usize>> = batch_ranges(PARTICLES).collect();
+
+        assert_eq!(ranges.len(), PARTICLES.div_ceil(MAX_BATCH_QUADS));
+        assert_eq!(ranges.first().map(|range| range.start), Some(0));
+        assert_eq!(ranges.last().map(|range| range.end), Some(PARTICLES));
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert!(ranges.iter().all(|range| range.len() <= MAX_BATCH_QUADS));
+    }
+
+    #[test]
+    fn batch_ranges_handle_exact_and_empty_counts() {
+        assert_eq!(batch_ranges(0).count(), 0);
+
+        let exact: Vec<Range<usize>> = batch_ranges(MAX_BATCH_QUADS).collect();
+        assert_eq!(exact.len(), 1);
+        assert_eq!(exact[0], 0..MAX_BATCH_QUADS);
+
+        let over: Vec<Range<usize>> = batch_ranges(MAX_BATCH_QUADS + 1).collect();
+        assert_eq!(over.len(), 2);
+        assert_eq!(over[1], MAX_BATCH_QUADS..MAX_BATCH_QUADS + 1);
+    }
+
+    #[test]
+    fn largest_batch_indices_fit_in_u16() {
+        let indices = quad_indices(MAX_BATCH_QUADS);
+
+        assert_eq!(indices.len(), MAX_BATCH_QUADS * BASE_QUAD_INDICES.len());
+        assert_eq!(indices.iter().max(), Some(&u16::MAX));
+        /* Wrapping around would make a later quad point back at an earlier one */
+        for (quad, corners) in indices.chunks(BASE_QUAD_INDICES.len()).enumerate() {
+            let base = quad as u32 * 4;
+            assert!(corners
+                .iter()
+                .all(|&index| (base..base + 4).contains(&(index as u32))));
+        }
+    }
+
+    /* Every particle's vertices end up in exactly one batch, in order */
+    #[test]
+    fn particle_vertices_split_without_loss() {
+        let vertices: Vec<f32> = (0..PARTICLES * QUAD_VERTEX_FLOATS)
+            .map(|index| index as f32)
+            .collect();
+
+        let mut drawn = Vec::with_capacity(vertices.len());
+        for quads in batch_ranges(PARTICLES) {
+            let chunk = &vertices[quads.start * QUAD_VERTEX_FLOATS..quads.end * QUAD_VERTEX_FLOATS];
+            assert_eq!(chunk.len() / QUAD_VERTEX_FLOATS, quads.len());
+            assert!(quads.len() * 4 <= u16::MAX as usize + 1);
+            drawn.extend_from_slice(chunk);
+        }
+
+        assert_eq!(drawn, vertices);
+    }
+}
@@ -0,0 +1,460 @@
+#![allow(unused)]
+
+/* GLSL ES 3.00 checks shared by build.rs, which runs them over src/shaders, and the tests, which also
+run them over the vertex and fragment pairs the renderer links. Only depends on the glsl crate so
+build.rs can include it by path */
+
+use std::fs;
+use std::path::Path;
+
+use glsl::parser::Parse;
+use glsl::syntax::{
+    Declaration, ExternalDeclaration, Preprocessor, PreprocessorVersionProfile, StorageQualifier,
+    TranslationUnit, TypeQualifierSpec, TypeSpecifierNonArray,
+};
+
+/* Every vertex shader gets the quad layout bound to these (see render::QUAD_VERTEX_LAYOUT) */
+pub const REQUIRED_VERTEX_INPUTS: [(&str, TypeSpecifierNonArray); 2] = [
+    ("position", TypeSpecifierNonArray::Vec3),
+    ("vert_texture_coords", TypeSpecifierNonArray::Vec2),
+];
+/* Sprite and post fragments all sample their source through this */
+pub const REQUIRED_FRAGMENT_UNIFORMS: [(&str, TypeSpecifierNonArray); 1] =
+    [("texture_sampler", TypeSpecifierNonArray::Sampler2D)];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    Vertex,
+    Fragment,
+}
+
+impl Stage {
+    pub fn from_extension(extension: &str) -> Option<Stage> {
+        match extension {
+            "vert" => Some(Stage::Vertex),
+            "frag" => Some(Stage::Fragment),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Variable {
+    pub storage: StorageQualifier,
+    pub ty: TypeSpecifierNonArray,
+    pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderInfo {
+    pub name: String,
+    pub stage: Stage,
+    pub variables: Vec<Variable>,
+}
+
+impl ShaderInfo {
+    fn with_storage(&self, storage: StorageQualifier) -> impl Iterator<Item = &Variable> {
+        self.variables
+            .iter()
+            .filter(move |variable| variable.storage == storage)
+    }
+}
+
+/* Parses one shader and checks what WebGL2 requires of it. `name` only labels the errors */
+pub fn validate_source(name: &str, source: &str, stage: Stage) -> Result<ShaderInfo, String> {
+    let unit = TranslationUnit::parse(source)
+        .map_err(|error| format!("{}: GLSL syntax error: {}", name, error))?;
+
+    let mut errors = Vec::new();
+    let mut has_version = false;
+    let mut has_float_precision = false;
+    let mut has_main = false;
+    let mut variables = Vec::new();
+
+    for (index, declaration) in (unit.0).0.iter().enumerate() {
+        match declaration {
+            ExternalDeclaration::Preprocessor(Preprocessor::Version(version)) => {
+                if index != 0 {
+                    errors.push("#version must be the first directive".to_string());
+                }
+                if version.version != 300 || version.profile != Some(PreprocessorVersionProfile::ES)
+                {
+                    errors.push("expected #version 300 es".to_string());
+                }
+                has_version = true;
+            }
+            ExternalDeclaration::Declaration(Declaration::Precision(_, ty))
+                if ty.ty == TypeSpecifierNonArray::Float =>
+            {
+                has_float_precision = true;
+            }
+            ExternalDeclaration::Declaration(Declaration::InitDeclaratorList(list)) => {
+                let head = &list.head;
+                let storage = head.ty.qualifier.as_ref().and_then(|qualifier| {
+                    qualifier.qualifiers.0.iter().find_map(|spec| match spec {
+                        TypeQualifierSpec::Storage(storage) => Some(storage.clone()),
+                        _ => None,
+                    })
+                });
+
+                if let Some(storage) = storage {
+                    let names = head
+                        .name
+                        .iter()
+                        .map(|name| name.0.clone())
+                        .chain(list.tail.iter().map(|tail| tail.ident.ident.0.clone()));
+
+                    for name in names {
+                        variables.push(Variable {
+                            storage: storage.clone(),
+                            ty: head.ty.ty.ty.clone(),
+                            name,
+                        });
+                    }
+                }
+            }
+            ExternalDeclaration::FunctionDefinition(function)
+                if function.prototype.name.0 == "main" =>
+            {
+                has_main = true;
+            }
+            _ => (),
+        }
+    }
+
+    if !has_version {
+        errors.push("missing #version 300 es".to_string());
+    }
+    if !has_main {
+        errors.push("missing void main()".to_string());
+    }
+
+    if stage == Stage::Vertex {
+        for (name, ty) in REQUIRED_VERTEX_INPUTS {
+            check_variable(&variables, StorageQualifier::In, name, ty, &mut errors);
+        }
+    }
+
+    /* Fragment shaders have no default float precision in GLSL ES, and need somewhere to write */
+    if stage == Stage::Fragment {
+        for (name, ty) in REQUIRED_FRAGMENT_UNIFORMS {
+            check_variable(&variables, StorageQualifier::Uniform, name, ty, &mut errors);
+        }
+        if !has_float_precision {
+            errors.push("missing default float precision".to_string());
+        }
+        if !variables
+            .iter()
+            .any(|variable| variable.storage == StorageQualifier::Out)
+        {
+            errors.push("missing an out variable for the fragment color".to_string());
+        }
+    }
+
+    if !errors.is_empty() {
+        let lines: Vec<String> = errors
+            .iter()
+            .map(|error| format!("{}: {}", name, error))
+            .collect();
+        return Err(lines.join("\n"));
+    }
+
+    Ok(ShaderInfo {
+        name: name.to_string(),
+        stage,
+        variables,
+    })
+}
+
+fn check_variable(
+    variables: &[Variable],
+    storage: StorageQualifier,
+    name: &str,
+    ty: TypeSpecifierNonArray,
+    errors: &mut Vec<String>,
+) {
+    match variables
+        .iter()
+        .find(|variable| variable.storage == storage && variable.name == name)
+    {
+        Some(variable) if variable.ty != ty => errors.push(format!(
+            "{} is declared as {:?}, expected {:?}",
+            name, variable.ty, ty
+        )),
+        Some(_) => (),
+        None => errors.push(format!("missing {:?} {:?} {}", storage, ty, name)),
+    }
+}
+
+/* What linking would reject: fragment inputs the vertex shader doesn't write, and uniforms both
+stages declare with different types */
+pub fn validate_link(vertex: &ShaderInfo, fragment: &ShaderInfo) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    for input in fragment.with_storage(StorageQualifier::In) {
+        let written = vertex
+            .with_storage(StorageQualifier::Out)
+            .any(|output| output.name == input.name && output.ty == input.ty);
+        if !written {
+            errors.push(format!(
+                "{}: input {} is not written by {}",
+                fragment.name, input.name, vertex.name
+            ));
+        }
+    }
+
+    for uniform in fragment.with_storage(StorageQualifier::Uniform) {
+        let mismatched = vertex
+            .with_storage(StorageQualifier::Uniform)
+            .any(|other| other.name == uniform.name && other.ty != uniform.ty);
+        if mismatched {
+            errors.push(format!(
+                "{}: uniform {} has a different type in {}",
+                fragment.name, uniform.name, vertex.name
+            ));
+        }
+    }
+
+    errors
+}
+
+/* Validates both sources and that they link, the way create_program would pair them */
+pub fn validate_program(vertex_source: &str, fragment_source: &str) -> Result<(), String> {
+    let vertex = validate_source("vertex shader", vertex_source, Stage::Vertex);
+    let fragment = validate_source("fragment shader", fragment_source, Stage::Fragment);
+
+    let (vertex, fragment) = match (vertex, fragment) {
+        (Ok(vertex), Ok(fragment)) => (vertex, fragment),
+        (vertex, fragment) => {
+            let errors: Vec<String> = [vertex.err(), fragment.err()]
+                .into_iter()
+                .flatten()
+                .collect();
+            return Err(errors.join("\n"));
+        }
+    };
+
+    let errors = validate_link(&vertex, &fragment);
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("\n")),
+    }
+}
+
+/* Every .vert/.frag in `dir`. Fragment shaders are paired with any of the vertex shaders, since
+which one they get linked with is decided at runtime */
+pub fn validate_dir(dir: &Path) -> Result<(), String> {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(|error| format!("{}: {}", dir.display(), error))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort();
+
+    let mut shaders = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        let Some(stage) = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Stage::from_extension)
+        else {
+            continue;
+        };
+
+        let name = path.display().to_string();
+        let result = fs::read_to_string(&path)
+            .map_err(|error| format!("{}: {}", name, error))
+            .and_then(|source| validate_source(&name, &source, stage));
+        match result {
+            Ok(info) => shaders.push(info),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    let vertex_shaders: Vec<&ShaderInfo> = shaders
+        .iter()
+        .filter(|shader| shader.stage == Stage::Vertex)
+        .collect();
+    for fragment in shaders
+        .iter()
+        .filter(|shader| shader.stage == Stage::Fragment)
+    {
+        let links = vertex_shaders
+            .iter()
+            .map(|vertex| validate_link(vertex, fragment))
+            .collect::<Vec<_>>();
+        if !links.iter().any(|link| link.is_empty()) {
+            if let Some(first) = links.into_iter().next() {
+                errors.extend(first);
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERTEX: &str = "#version 300 es
+in vec3 position;
+in vec2 vert_texture_coords;
+out vec2 texture_coords;
+void main() {
+    texture_coords = vert_texture_coords;
+    gl_Position = vec4(position, 1.0);
+}
+";
+
+    const FRAGMENT: &str = "#version 300 es
+precision highp float;
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+out vec4 output_color;
+void main() {
+    output_color = texture(texture_sampler, texture_coords);
+}
+";
+
+    #[test]
+    fn accepts_a_valid_program() {
+        assert_eq!(validate_program(VERTEX, FRAGMENT), Ok(()));
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        let broken = FRAGMENT.replace("output_color = texture", "output_color = = texture");
+        let error = validate_source("broken.frag", &broken, Stage::Fragment).unwrap_err();
+        assert!(
+            error.contains("broken.frag: GLSL syntax error"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_version() {
+        let source = FRAGMENT.replace("#version 300 es\n", "");
+        let error = validate_source("shader", &source, Stage::Fragment).unwrap_err();
+        assert!(error.contains("missing #version 300 es"), "{}", error);
+    }
+
+    #[test]
+    fn reports_the_wrong_version() {
+        let source = VERTEX.replace("300 es", "330");
+        let error = validate_source("shader", &source, Stage::Vertex).unwrap_err();
+        assert!(error.contains("expected #version 300 es"), "{}", error);
+    }
+
+    #[test]
+    fn requires_fragment_precision_and_output() {
+        let source = FRAGMENT
+            .replace("precision highp float;\n", "")
+            .replace("out vec4 output_color;\n", "vec4 output_color;\n");
+        let error = validate_source("shader", &source, Stage::Fragment).unwrap_err();
+        assert!(
+            error.contains("missing default float precision"),
+            "{}",
+            error
+        );
+        assert!(error.contains("missing an out variable"), "{}", error);
+    }
+
+    #[test]
+    fn requires_the_quad_inputs() {
+        let source = VERTEX.replace("in vec2 vert_texture_coords;", "in vec2 vert_uv;");
+        let error = validate_source("shader", &source, Stage::Vertex).unwrap_err();
+        assert!(
+            error.contains("missing In Vec2 vert_texture_coords"),
+            "{}",
+            error
+        );
+
+        let source = VERTEX.replace("in vec3 position;", "in vec2 position;");
+        let error = validate_source("shader", &source, Stage::Vertex).unwrap_err();
+        assert!(
+            error.contains("position is declared as Vec2, expected Vec3"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn requires_the_texture_sampler() {
+        let source = FRAGMENT.replace("texture_sampler", "source_sampler");
+        let error = validate_source("shader", &source, Stage::Fragment).unwrap_err();
+        assert!(
+            error.contains("missing Uniform Sampler2D texture_sampler"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reports_inputs_the_vertex_shader_does_not_write() {
+        let fragment = FRAGMENT.replace("in vec2 texture_coords;", "in vec3 texture_coords;");
+        let error = validate_program(VERTEX, &fragment).unwrap_err();
+        assert!(
+            error.contains("input texture_coords is not written"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reports_mismatched_uniforms() {
+        let vertex = VERTEX.replace(
+            "in vec3 position;",
+            "in vec3 position;\nuniform float scale;",
+        );
+        let fragment = FRAGMENT.replace("in vec2", "uniform vec2 scale;\nin vec2");
+        let error = validate_program(&vertex, &fragment).unwrap_err();
+        assert!(
+            error.contains("uniform scale has a different type"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn shipped_shaders_are_valid() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaders");
+        validate_dir(&dir).unwrap();
+    }
+
+    /* The pairs create_program links at runtime */
+    #[test]
+    fn runtime_programs_link() {
+        use crate::{lighting, post, render};
+
+        let fragments = [
+            render::BASE_FRAGMENT_SHADER,
+            lighting::NORMAL_FRAGMENT_SHADER,
+            lighting::FLAT_NORMAL_FRAGMENT_SHADER,
+            lighting::LIGHT_FRAGMENT_SHADER,
+            lighting::LIGHTING_FRAGMENT_SHADER,
+            post::BLOOM_FRAGMENT_SHADER,
+            post::BLUR_FRAGMENT_SHADER,
+            post::VIGNETTE_FRAGMENT_SHADER,
+            post::CRT_FRAGMENT_SHADER,
+            post::PIXELATE_FRAGMENT_SHADER,
+            post::CHROMATIC_FRAGMENT_SHADER,
+            post::COLOR_GRADE_FRAGMENT_SHADER,
+            post::TONE_FRAGMENT_SHADER,
+            post::GRAIN_FRAGMENT_SHADER,
+        ];
+        for fragment in fragments {
+            validate_program(render::BASE_VERTEX_SHADER, fragment).unwrap();
+        }
+
+        validate_program(
+            render::INSTANCED_VERTEX_SHADER,
+            render::BASE_FRAGMENT_SHADER,
+        )
+        .unwrap();
+    }
+}
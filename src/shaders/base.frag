@@ -0,0 +1,13 @@
+#version 300 es
+precision highp float;
+
+// Textures and vertex_color are premultiplied alpha unless the texture opted out,
+// in which case the blend function premultiplies on the way into the target
+in vec2 texture_coords;
+in vec4 vertex_color;
+uniform sampler2D texture_sampler;
+out vec4 output_color;
+
+void main() {
+    output_color = texture(texture_sampler, texture_coords) * vertex_color;
+}
@@ -0,0 +1,13 @@
+#version 300 es
+
+in vec3 position;
+in vec2 vert_texture_coords;
+in vec4 vert_color;
+out vec2 texture_coords;
+out vec4 vertex_color;
+
+void main() {
+    texture_coords = vec2(vert_texture_coords.x, vert_texture_coords.y);
+    vertex_color = vert_color;
+    gl_Position = vec4(position, 1.0);
+}
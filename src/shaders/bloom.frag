@@ -0,0 +1,50 @@
+#version 300 es
+precision highp float;
+
+#define TWO_PI 6.283185307179586
+
+// Adds a glow around everything brighter than the threshold, sampled in rings around each pixel
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 texel_size;
+uniform float threshold;
+uniform float intensity;
+// In target pixels
+uniform float radius;
+uniform int directions;
+uniform int quality;
+out vec4 output_color;
+
+void main() {
+    vec2 uv = texture_coords;
+    vec4 color = texture(texture_sampler, uv);
+
+    if (intensity <= 0.0 || radius <= 0.0 || directions <= 0 || quality <= 0) {
+        output_color = color;
+        return;
+    }
+
+    vec3 bloom = vec3(0.0);
+    float weight_sum = 0.0;
+
+    for (int direction = 0; direction < directions; direction++) {
+        float angle = float(direction) * TWO_PI / float(directions);
+        vec2 ray = vec2(sin(angle), cos(angle)) * radius * texel_size;
+
+        for (int sample_index = 1; sample_index <= quality; sample_index++) {
+            float along = float(sample_index) / float(quality);
+            vec2 sample_uv = clamp(uv + ray * along, vec2(0.0), vec2(1.0));
+
+            // Only color counts as bright, thresholding alpha would make any opaque pixel glow
+            vec3 highlight = max(texture(texture_sampler, sample_uv).rgb - threshold, 0.0);
+            float weight = exp(-2.0 * along);
+            bloom += highlight * weight;
+            weight_sum += weight;
+        }
+    }
+
+    // Premultiplied, so the glow also covers as much as it adds to keep rgb within alpha
+    vec3 glow = bloom / weight_sum * intensity;
+    float coverage = max(glow.r, max(glow.g, glow.b));
+    output_color = vec4(color.rgb + glow, min(color.a + coverage, 1.0));
+}
@@ -0,0 +1,23 @@
+#version 300 es
+precision highp float;
+
+#define MAX_TAPS 32
+
+// One direction of a separable Gaussian blur, the weights come from post::gaussian_weights
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+// Offset between taps, along the pass direction
+uniform vec2 blur_step;
+uniform float weights[MAX_TAPS];
+uniform int taps;
+out vec4 output_color;
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords) * weights[0];
+    for (int tap = 1; tap < taps; tap++) {
+        vec2 offset = blur_step * float(tap);
+        color += texture(texture_sampler, texture_coords + offset) * weights[tap];
+        color += texture(texture_sampler, texture_coords - offset) * weights[tap];
+    }
+    output_color = color;
+}
@@ -0,0 +1,18 @@
+#version 300 es
+precision highp float;
+
+// Splits red and blue apart, more towards the edges like a cheap lens
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 texel_size;
+// In target pixels at the edges of the view
+uniform float offset;
+out vec4 output_color;
+
+void main() {
+    vec2 shift = (texture_coords * 2.0 - 1.0) * offset * texel_size;
+    vec4 red = texture(texture_sampler, texture_coords + shift);
+    vec4 green = texture(texture_sampler, texture_coords);
+    vec4 blue = texture(texture_sampler, texture_coords - shift);
+    output_color = vec4(red.r, green.g, blue.b, max(green.a, max(red.a, blue.a)));
+}
@@ -0,0 +1,36 @@
+#version 300 es
+precision highp float;
+
+// Looks colors up in a LUT laid out as a strip of blue slices, red across each slice and green down
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform sampler2D lut_sampler;
+// Entries per channel, the strip is lut_size * lut_size by lut_size pixels
+uniform float lut_size;
+uniform float amount;
+out vec4 output_color;
+
+vec3 lookup(vec3 color) {
+    float blue = color.b * (lut_size - 1.0);
+    float slice = floor(blue);
+    vec2 texel = vec2(1.0 / (lut_size * lut_size), 1.0 / lut_size);
+    // Stay half a texel inside each slice so filtering doesn't bleed into its neighbour
+    vec2 uv = vec2(color.r * (lut_size - 1.0) + 0.5, color.g * (lut_size - 1.0) + 0.5) * texel;
+
+    vec3 low = texture(lut_sampler, uv + vec2(slice / lut_size, 0.0)).rgb;
+    vec3 high = texture(lut_sampler, uv + vec2(min(slice + 1.0, lut_size - 1.0) / lut_size, 0.0)).rgb;
+    return mix(low, high, blue - slice);
+}
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords);
+    if (color.a <= 0.0) {
+        output_color = color;
+        return;
+    }
+
+    // The LUT maps straight colors, the target holds premultiplied ones
+    vec3 straight = clamp(color.rgb / color.a, 0.0, 1.0);
+    vec3 graded = mix(straight, lookup(straight), amount);
+    output_color = vec4(graded * color.a, color.a);
+}
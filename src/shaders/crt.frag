@@ -0,0 +1,30 @@
+#version 300 es
+precision highp float;
+
+#define PI 3.141592653589793
+
+// Bulges the image like a curved tube and darkens every other line
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 texel_size;
+uniform float curvature;
+uniform float scanline_intensity;
+// Lines over the height of the view, 0 gives one per target row pair
+uniform float scanline_count;
+out vec4 output_color;
+
+void main() {
+    vec2 centered = texture_coords * 2.0 - 1.0;
+    centered += centered * (centered.yx * centered.yx) * curvature;
+    vec2 uv = centered * 0.5 + 0.5;
+
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+        output_color = vec4(0.0);
+        return;
+    }
+
+    vec4 color = texture(texture_sampler, uv);
+    float lines = scanline_count > 0.0 ? scanline_count : 0.5 / texel_size.y;
+    float scanline = 0.5 + 0.5 * cos(uv.y * lines * 2.0 * PI);
+    output_color = vec4(color.rgb * (1.0 - scanline_intensity * scanline), color.a);
+}
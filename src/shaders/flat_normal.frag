@@ -0,0 +1,13 @@
+#version 300 es
+precision highp float;
+
+// Sprites without a normal map face the viewer, cut out by their own texture's alpha so they
+// cover what was drawn below them only where they are visible
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+out vec4 output_color;
+
+void main() {
+    float alpha = texture(texture_sampler, texture_coords).a;
+    output_color = vec4(0.5, 0.5, 1.0, alpha);
+}
@@ -0,0 +1,23 @@
+#version 300 es
+precision highp float;
+
+// Noise that changes every frame, like film grain
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 texel_size;
+uniform float time;
+uniform float intensity;
+// Size of a grain in target pixels
+uniform float grain_size;
+out vec4 output_color;
+
+float hash(vec2 point) {
+    return fract(sin(dot(point, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords);
+    vec2 cell = floor(texture_coords / (texel_size * max(grain_size, 1.0)));
+    float noise = hash(cell + fract(time) * 101.0) - 0.5;
+    output_color = vec4(clamp(color.rgb + noise * intensity * color.a, 0.0, color.a), color.a);
+}
@@ -0,0 +1,44 @@
+#version 300 es
+
+/* Unit quad corner, shared by every instance */
+in vec3 position;
+in vec2 vert_texture_coords;
+
+/* Per-sprite data, see render::INSTANCE_LAYOUT */
+in vec2 instance_position;
+in vec2 instance_size;
+in vec2 instance_scale;
+in vec2 instance_origin;
+in float instance_rotation;
+in vec4 instance_uv_rect;
+in vec4 instance_color;
+
+uniform vec2 camera_size;
+uniform vec2 camera_scroll;
+uniform float camera_zoom;
+uniform float camera_rotation;
+
+out vec2 texture_coords;
+out vec4 vertex_color;
+
+vec2 rotate(vec2 point, float radians) {
+    float cos_theta = cos(radians);
+    float sin_theta = sin(radians);
+    return vec2(point.x * cos_theta - point.y * sin_theta, point.x * sin_theta + point.y * cos_theta);
+}
+
+/* Same steps as Camera::transform_tris */
+void main() {
+    vec2 point = (position.xy * instance_size - instance_origin) * instance_scale;
+    point = rotate(point, -instance_rotation);
+    point += instance_position;
+
+    point -= camera_scroll;
+    point *= camera_zoom;
+    point = rotate(point, camera_rotation);
+    point /= camera_size;
+
+    texture_coords = instance_uv_rect.xy + vert_texture_coords * instance_uv_rect.zw;
+    vertex_color = instance_color;
+    gl_Position = vec4(point.x, -point.y, 0.0, 1.0);
+}
@@ -0,0 +1,39 @@
+#version 300 es
+precision highp float;
+
+// Adds one light to the light target, same math as lighting::Light::contribution.
+// texture_coords is the fragment's world position and texture_sampler the normal target
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 target_size;
+
+uniform int light_kind;
+uniform vec2 light_position;
+uniform vec3 light_color;
+uniform float light_radius;
+uniform float light_falloff;
+uniform float light_height;
+uniform vec2 light_direction;
+uniform vec2 light_cone;
+out vec4 output_color;
+
+void main() {
+    vec3 normal = texture(texture_sampler, gl_FragCoord.xy / target_size).xyz * 2.0 - 1.0;
+
+    vec3 to_light = vec3(-light_direction, light_height);
+    float attenuation = 1.0;
+    if (light_kind != 2) {
+        vec2 offset = light_position - texture_coords;
+        float distance = length(offset);
+        attenuation = pow(clamp(1.0 - distance / light_radius, 0.0, 1.0), light_falloff);
+        to_light = vec3(offset, light_height);
+
+        if (light_kind == 1 && distance > 0.0) {
+            float angle = dot(-offset / distance, light_direction);
+            attenuation *= smoothstep(light_cone.x, light_cone.y, angle);
+        }
+    }
+
+    float diffuse = max(dot(normalize(normal), normalize(to_light)), 0.0);
+    output_color = vec4(light_color * attenuation * diffuse, 0.0);
+}
@@ -0,0 +1,14 @@
+#version 300 es
+precision highp float;
+
+// Multiplies the camera's resolved target by the accumulated light
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform sampler2D light_sampler;
+out vec4 output_color;
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords);
+    vec3 light = texture(light_sampler, texture_coords).rgb;
+    output_color = vec4(color.rgb * light, color.a);
+}
@@ -0,0 +1,18 @@
+#version 300 es
+precision highp float;
+
+// Turns a normal map sample into a world space normal for the lighting pass. The sprite's
+// rotation and flips come in through vertex_color as a column-major 2x2 matrix
+in vec2 texture_coords;
+in vec4 vertex_color;
+uniform sampler2D texture_sampler;
+out vec4 output_color;
+
+void main() {
+    vec4 texel = texture(texture_sampler, texture_coords);
+    vec3 normal = texel.xyz * 2.0 - 1.0;
+
+    // Normal maps point green up, world space y points down
+    vec2 world_normal = mat2(vertex_color.xy, vertex_color.zw) * vec2(normal.x, -normal.y);
+    output_color = vec4(vec3(world_normal, normal.z) * 0.5 + 0.5, texel.a);
+}
@@ -0,0 +1,15 @@
+#version 300 es
+precision highp float;
+
+// Samples the middle of blocks of target pixels
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform vec2 texel_size;
+uniform float pixel_size;
+out vec4 output_color;
+
+void main() {
+    vec2 block = texel_size * max(pixel_size, 1.0);
+    vec2 uv = (floor(texture_coords / block) + 0.5) * block;
+    output_color = texture(texture_sampler, uv);
+}
@@ -0,0 +1,13 @@
+#version 300 es
+precision highp float;
+
+// Remixes the channels with a matrix from post::tone_matrix, for grayscale and sepia
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform mat3 tone_matrix;
+out vec4 output_color;
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords);
+    output_color = vec4(clamp(tone_matrix * color.rgb, 0.0, color.a), color.a);
+}
@@ -0,0 +1,19 @@
+#version 300 es
+precision highp float;
+
+// Fades the edges of the view towards a color
+in vec2 texture_coords;
+uniform sampler2D texture_sampler;
+uniform float strength;
+// Distance from the center where the fade starts, 1 touches the middle of the edges
+uniform float radius;
+uniform float softness;
+uniform vec3 vignette_color;
+out vec4 output_color;
+
+void main() {
+    vec4 color = texture(texture_sampler, texture_coords);
+    float from_center = length(texture_coords * 2.0 - 1.0);
+    float amount = strength * smoothstep(radius, radius + softness, from_center);
+    output_color = vec4(mix(color.rgb, vignette_color * color.a, amount), color.a);
+}
@@ -0,0 +1,390 @@
+#![allow(unused)]
+
+use crate::decode::DecodedImage;
+use crate::lighting::{Light, LightKind};
+use crate::math::{Mat3, Rect, Vec2};
+use crate::render::BASE_QUAD_VERTS;
+use crate::sprite::Sprite;
+
+/* Closed polygon in world space that blocks light */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Occluder {
+    pub points: Vec<Vec2>,
+}
+
+impl Occluder {
+    pub fn new(points: Vec<Vec2>) -> Occluder {
+        Occluder { points }
+    }
+
+    /* The sprite's rotated and scaled quad */
+    pub fn from_sprite(sprite: &Sprite) -> Occluder {
+        let corners = [0, 1, 2, 3]
+            .map(|corner| Vec2::new(BASE_QUAD_VERTS[corner * 3], BASE_QUAD_VERTS[corner * 3 + 1]));
+        Occluder::from_hull(&corners, &sprite.quad_transform())
+    }
+
+    /* A hull in the sprite's unit quad space (see alpha_hull) placed with Sprite::quad_transform */
+    pub fn from_hull(hull: &[Vec2], transform: &Mat3) -> Occluder {
+        Occluder::new(
+            hull.iter()
+                .map(|point| transform.transform_point(*point))
+                .collect(),
+        )
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::from_points(&self.points)
+    }
+}
+
+/* Twice the polygon's area, positive when the points go counter-clockwise with y up */
+pub fn signed_area(points: &[Vec2]) -> f32 {
+    (0..points.len())
+        .map(|index| points[index].cross(points[(index + 1) % points.len()]))
+        .sum()
+}
+
+/* Smallest convex polygon around the points, counter-clockwise with y up, without collinear points */
+pub fn convex_hull(points: &[Vec2]) -> Vec<Vec2> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    sorted.dedup();
+    if sorted.len() < 3 {
+        return sorted;
+    }
+
+    /* Andrew's monotone chain, the lower half then the upper half */
+    let mut hull: Vec<Vec2> = Vec::with_capacity(sorted.len() * 2);
+    for pass in 0..2 {
+        let start = hull.len();
+        for point in &sorted {
+            while hull.len() >= start + 2 {
+                let last = hull[hull.len() - 1];
+                let before = hull[hull.len() - 2];
+                if (last - before).cross(*point - last) > 0.0 {
+                    break;
+                }
+                hull.pop();
+            }
+            hull.push(*point);
+        }
+        /* The last point is where the other half starts */
+        hull.pop();
+        sorted.reverse();
+    }
+
+    hull
+}
+
+/* Convex hull of the pixels at or above `threshold` alpha, in the unit quad space sprites are
+drawn from (-1 to 1, y down like image rows), so it can go through Occluder::from_hull */
+pub fn alpha_hull(image: &DecodedImage, threshold: u8) -> Vec<Vec2> {
+    let (width, height) = (image.width as f32, image.height as f32);
+    let to_local =
+        |x: u32, y: u32| Vec2::new(x as f32 / width * 2.0 - 1.0, y as f32 / height * 2.0 - 1.0);
+
+    /* Only the outermost solid pixel on each side of a row can be on the hull */
+    let mut points = Vec::new();
+    for y in 0..image.height {
+        let solid = |x: &u32| image.pixel(*x, y)[3] >= threshold;
+        let Some(left) = (0..image.width).find(solid) else {
+            continue;
+        };
+        let right = (0..image.width).rev().find(solid).unwrap_or(left);
+
+        points.extend([
+            to_local(left, y),
+            to_local(left, y + 1),
+            to_local(right + 1, y),
+            to_local(right + 1, y + 1),
+        ]);
+    }
+
+    convex_hull(&points)
+}
+
+/* Where light travels from towards `point`, None for a point sitting on the light */
+fn light_ray(light: &Light, point: Vec2) -> Option<Vec2> {
+    match light.kind {
+        LightKind::Directional { .. } => Some(light.direction()),
+        _ => {
+            let ray = point - light.position;
+            (ray.length_squared() > 0.0).then(|| ray.normalize())
+        }
+    }
+}
+
+/* Quads covering the shadow behind the occluder, made by pushing every edge that faces away
+from the light `extent` world units along the light's rays. Corners go around each quad */
+pub fn shadow_quads(points: &[Vec2], light: &Light, extent: f32) -> Vec<[Vec2; 4]> {
+    if points.len() < 2 {
+        return Vec::new();
+    }
+
+    let winding = signed_area(points).signum();
+    let mut quads = Vec::new();
+    for index in 0..points.len() {
+        let start = points[index];
+        let end = points[(index + 1) % points.len()];
+
+        let edge = end - start;
+        let outward = Vec2::new(edge.y, -edge.x) * winding;
+        let Some(ray) = light_ray(light, (start + end) * 0.5) else {
+            continue;
+        };
+        if outward.dot(ray) <= 0.0 {
+            continue;
+        }
+
+        let (Some(start_ray), Some(end_ray)) = (light_ray(light, start), light_ray(light, end))
+        else {
+            continue;
+        };
+        quads.push([
+            start,
+            end,
+            end + end_ray * extent,
+            start + start_ray * extent,
+        ]);
+    }
+
+    quads
+}
+
+/* Splits a light into `count` dimmer copies spread over its source_radius, whose shadows
+overlap into soft edges. Lights without a size stay as they are */
+pub fn sample_lights(light: &Light, count: usize) -> Vec<Light> {
+    if light.source_radius <= 0.0 || count <= 1 {
+        return vec![*light];
+    }
+
+    (0..count)
+        .map(|sample| {
+            /* Spread evenly from one edge of the source to the other */
+            let spread = sample as f32 / (count - 1) as f32 * 2.0 - 1.0;
+            let mut sampled = *light;
+            sampled.intensity = light.intensity / count as f32;
+
+            match &mut sampled.kind {
+                LightKind::Directional { direction } => *direction += spread * light.source_radius,
+                _ => {
+                    let radians = sample as f32 / count as f32 * std::f32::consts::TAU;
+                    sampled.position +=
+                        Vec2::new(radians.cos(), radians.sin()) * light.source_radius;
+                }
+            }
+            sampled
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Color;
+
+    fn square() -> Vec<Vec2> {
+        vec![
+            Vec2::new(-1.0, -1.0),
+            Vec2::new(1.0, -1.0),
+            Vec2::new(1.0, 1.0),
+            Vec2::new(-1.0, 1.0),
+        ]
+    }
+
+    fn close(first: Vec2, second: Vec2) -> bool {
+        (first - second).length() < 1e-4
+    }
+
+    /* Which square edges cast, by the index of their first point */
+    fn casting_edges(points: &[Vec2], light: &Light) -> Vec<usize> {
+        shadow_quads(points, light, 10.0)
+            .iter()
+            .map(|quad| points.iter().position(|point| *point == quad[0]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn signed_area_follows_winding() {
+        let points = square();
+        assert_eq!(signed_area(&points), 8.0);
+
+        let reversed: Vec<Vec2> = points.into_iter().rev().collect();
+        assert_eq!(signed_area(&reversed), -8.0);
+    }
+
+    #[test]
+    fn hulls_wind_counter_clockwise_either_way() {
+        let clockwise: Vec<Vec2> = square().into_iter().rev().collect();
+        for points in [square(), clockwise] {
+            let hull = convex_hull(&points);
+            assert_eq!(hull.len(), 4);
+            assert!(signed_area(&hull) > 0.0);
+        }
+    }
+
+    #[test]
+    fn hulls_drop_inner_collinear_and_repeated_points() {
+        let mut points = square();
+        points.extend([
+            Vec2::new(0.0, -1.0),
+            Vec2::new(1.0, 0.0),
+            Vec2::new(0.0, 0.0),
+            Vec2::new(0.5, 0.2),
+            Vec2::new(-1.0, 1.0),
+        ]);
+
+        let mut hull = convex_hull(&points);
+        hull.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+        let mut expected = square();
+        expected.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+        assert_eq!(hull, expected);
+
+        let line = [Vec2::ZERO, Vec2::new(1.0, 1.0), Vec2::new(2.0, 2.0)];
+        assert!(convex_hull(&line).len() <= 2);
+        assert_eq!(convex_hull(&[Vec2::ZERO, Vec2::ZERO]), [Vec2::ZERO]);
+    }
+
+    #[test]
+    fn alpha_hull_wraps_solid_pixels() {
+        /* 4x4 with the middle 2x2 solid */
+        let mut pixels = vec![0; 4 * 4 * 4];
+        for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+            pixels[(y * 4 + x) * 4 + 3] = 255;
+        }
+        let image = DecodedImage::new(4, 4, pixels);
+
+        let hull = alpha_hull(&image, 128);
+        assert_eq!(hull.len(), 4);
+        for corner in [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)] {
+            let corner = Vec2::new(corner.0, corner.1);
+            assert!(hull.contains(&corner), "{:?} not in {:?}", corner, hull);
+        }
+
+        assert!(alpha_hull(&image, 255).len() == 4);
+        assert!(alpha_hull(&DecodedImage::new(2, 2, vec![0; 16]), 1).is_empty());
+    }
+
+    #[test]
+    fn point_lights_cast_from_edges_facing_away() {
+        let light = Light::point(Vec2::new(-10.0, 0.0), 100.0, Color::WHITE);
+
+        /* Bottom, right and top face away from a light left of the square, left faces it */
+        assert_eq!(casting_edges(&square(), &light), [0, 1, 2]);
+
+        /* Winding doesn't change which edges cast */
+        let clockwise: Vec<Vec2> = square().into_iter().rev().collect();
+        let mut edges: Vec<Vec2> = shadow_quads(&clockwise, &light, 10.0)
+            .iter()
+            .map(|quad| (quad[0] + quad[1]) * 0.5)
+            .collect();
+        edges.sort_by(|a, b| a.y.total_cmp(&b.y));
+        assert_eq!(
+            edges,
+            [
+                Vec2::new(0.0, -1.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(0.0, 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn directional_lights_cast_from_edges_facing_along_them() {
+        let light = Light::directional(0.0, Color::WHITE);
+        assert_eq!(casting_edges(&square(), &light), [1]);
+
+        let light = Light::directional(100.0, Color::WHITE);
+        assert_eq!(casting_edges(&square(), &light), [2, 3]);
+
+        let light = Light::directional(45.0, Color::WHITE);
+        assert_eq!(casting_edges(&square(), &light), [1, 2]);
+    }
+
+    #[test]
+    fn shadows_extrude_by_the_extent() {
+        let light = Light::point(Vec2::new(-10.0, 0.0), 100.0, Color::WHITE);
+        for quad in shadow_quads(&square(), &light, 25.0) {
+            for (corner, extruded) in [(quad[1], quad[2]), (quad[0], quad[3])] {
+                let offset = extruded - corner;
+                assert!((offset.length() - 25.0).abs() < 1e-3);
+                /* Straight away from the light */
+                assert!(close(
+                    offset.normalize(),
+                    (corner - light.position).normalize()
+                ));
+            }
+        }
+
+        let light = Light::directional(0.0, Color::WHITE);
+        let quads = shadow_quads(&square(), &light, 40.0);
+        assert_eq!(
+            quads,
+            [[
+                Vec2::new(1.0, -1.0),
+                Vec2::new(1.0, 1.0),
+                Vec2::new(41.0, 1.0),
+                Vec2::new(41.0, -1.0),
+            ]]
+        );
+    }
+
+    #[test]
+    fn degenerate_occluders_cast_nothing() {
+        let light = Light::point(Vec2::ZERO, 100.0, Color::WHITE);
+        assert!(shadow_quads(&[], &light, 10.0).is_empty());
+        assert!(shadow_quads(&[Vec2::new(1.0, 1.0)], &light, 10.0).is_empty());
+
+        /* An edge running through the light has nowhere to extrude its near corner from */
+        let touching = [Vec2::ZERO, Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0)];
+        for quad in shadow_quads(&touching, &light, 10.0) {
+            assert!(quad
+                .iter()
+                .all(|corner| corner.x.is_finite() && corner.y.is_finite()));
+            assert!(!quad.contains(&Vec2::ZERO));
+        }
+    }
+
+    #[test]
+    fn sampled_lights_split_the_intensity() {
+        let mut light = Light::point(Vec2::new(5.0, 5.0), 100.0, Color::WHITE);
+        light.intensity = 3.0;
+
+        assert_eq!(sample_lights(&light, 6), [light]);
+
+        light.source_radius = 2.0;
+        assert_eq!(sample_lights(&light, 1), [light]);
+
+        let samples = sample_lights(&light, 6);
+        assert_eq!(samples.len(), 6);
+        let total: f32 = samples.iter().map(|sample| sample.intensity).sum();
+        assert!((total - 3.0).abs() < 1e-5);
+        for sample in &samples {
+            assert!(((sample.position - light.position).length() - 2.0).abs() < 1e-4);
+            assert_eq!(sample.radius, light.radius);
+        }
+    }
+
+    #[test]
+    fn sampled_directional_lights_spread_their_angle() {
+        let mut light = Light::directional(90.0, Color::WHITE);
+        light.source_radius = 10.0;
+
+        let directions: Vec<f32> = sample_lights(&light, 3)
+            .iter()
+            .map(|sample| match sample.kind {
+                LightKind::Directional { direction } => direction,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(directions, [80.0, 90.0, 100.0]);
+    }
+
+    #[test]
+    fn occluders_follow_the_hull_transform() {
+        let transform = Mat3::translation(Vec2::new(10.0, 0.0)) * Mat3::scale(Vec2::new(2.0, 3.0));
+        let occluder = Occluder::from_hull(&square(), &transform);
+        assert_eq!(occluder.bounds(), Rect::new(8.0, -3.0, 4.0, 6.0));
+    }
+}
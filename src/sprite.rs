@@ -1,17 +1,25 @@
 #![allow(unused)]
 use crate::{
+    animation::{Animation, AnimationPlayer},
     assets::{self, Image},
     camera::DrawCall,
     log,
     render::{BASE_QUAD_INDICES, BASE_QUAD_UVS, BASE_QUAD_VERTS},
 };
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 use crate::{
-    camera::{self, Camera},
+    camera::{self, Camera, CLIP_RECT},
     console_log,
+    follow::FollowTarget,
+    math::{to_radians, Color, Mat3, Rect, Vec2},
     object::Object,
-    render::{self, Renderer},
+    program::Program,
+    render::{self, Renderer, INSTANCE_FLOATS},
+    texture::TextureOptions,
 };
 use web_sys::{HtmlImageElement, WebGlProgram, WebGlTexture};
 
@@ -27,9 +35,25 @@ pub struct Sprite {
 
     pub rotation: f32,
 
+    /* Pivot for scale and rotation in unscaled pixels from the center */
+    pub originx: f32,
+    pub originy: f32,
+
+    /* Normalized region of the texture to draw */
+    pub uv_rect: Rect,
+    pub color: Color,
+
+    /* Drives uv_rect every update when set */
+    pub animation: Option<AnimationPlayer>,
+
     pub camera: Rc<RefCell<Camera>>,
     pub image: Option<Rc<RefCell<Image>>>,
-    pub shader: WebGlProgram,
+    /* Shades the sprite on cameras with lighting, laid out like `image` */
+    pub normal_map: Option<Rc<RefCell<Image>>>,
+    pub shader: Rc<Program>,
+
+    /* Published x and y for cameras following this sprite, see follow_target */
+    pub position_handle: Option<Rc<Cell<Vec2>>>,
 }
 
 impl Sprite {
@@ -38,16 +62,41 @@ impl Sprite {
         y: f32,
         camera: Rc<RefCell<Camera>>,
         image: &str,
-        shader: Option<WebGlProgram>,
+        shader: Option<Rc<Program>>,
+    ) -> Sprite {
+        let image_ref = assets::Assets::load_image(image).await;
+        Sprite::from_image(x, y, camera, image_ref, shader)
+    }
+
+    /* Loads an animated GIF as a spritesheet and starts playing it */
+    pub async fn new_animated(
+        x: f32,
+        y: f32,
+        camera: Rc<RefCell<Camera>>,
+        gif: &str,
+        shader: Option<Rc<Program>>,
+    ) -> Sprite {
+        let loaded = assets::Assets::load_gif(gif, TextureOptions::new()).await;
+
+        let (image_ref, animation) = loaded.unzip();
+        let mut sprite = Sprite::from_image(x, y, camera, image_ref, shader);
+        if let Some(animation) = animation {
+            sprite.play(animation);
+        }
+        sprite
+    }
+
+    pub fn from_image(
+        x: f32,
+        y: f32,
+        camera: Rc<RefCell<Camera>>,
+        image_ref: Option<Rc<RefCell<Image>>>,
+        shader: Option<Rc<Program>>,
     ) -> Sprite {
         let program = shader.unwrap_or_else(|| {
-            render::with_renderer(|renderer| {
-                renderer.base_program.as_ref().unwrap().as_ref().clone()
-            })
+            render::with_renderer(|renderer| renderer.base_program.clone().unwrap())
         });
 
-        let image_ref = assets::Assets::load_image(image).await;
-
         let image_binding = image_ref.clone();
         let image_pointer = &image_binding.as_ref();
 
@@ -56,19 +105,12 @@ impl Sprite {
 
         if let Some(pointer) = image_pointer {
             let borrowed = pointer.borrow();
-
-            let html_image = &borrowed.html_image;
             let webl_gl_texture = &borrowed.webl_gl_texture;
 
-            width = html_image.width() as f32;
-            height = html_image.height() as f32;
+            width = borrowed.width as f32;
+            height = borrowed.height as f32;
 
             render::with_renderer(|renderer| {
-                renderer.set_texture_filtering(webl_gl_texture, true);
-
-                renderer.use_program(&program);
-
-                renderer.bind_vert_attribs(&renderer.quads_buffer, &program);
                 renderer.bind_frag_uniforms(&program, webl_gl_texture);
             });
         }
@@ -85,42 +127,167 @@ impl Sprite {
 
             rotation: 0.0,
 
+            originx: 0.0,
+            originy: 0.0,
+
+            uv_rect: Rect::UNIT,
+            color: Color::WHITE,
+
+            animation: None,
+
             camera,
             image: image_ref,
+            normal_map: None,
             shader: program,
+
+            position_handle: None,
         }
     }
+
+    /* Something Camera::start_follow can track while the sprite stays owned by its object. The
+    position is published on every update and draw, so move the sprite before updating it for the
+    camera to catch up the same frame */
+    pub fn follow_target(&mut self) -> FollowTarget {
+        let handle = self
+            .position_handle
+            .get_or_insert_with(|| Rc::new(Cell::new(Vec2::ZERO)));
+        handle.set(Vec2::new(self.x, self.y));
+        FollowTarget::Position(Rc::downgrade(handle))
+    }
+
+    fn publish_position(&self) {
+        if let Some(handle) = &self.position_handle {
+            handle.set(Vec2::new(self.x, self.y));
+        }
+    }
+
+    /* Normal maps are tangent space with green pointing up. They hold directions rather than
+    colors, so they are uploaded without premultiplying */
+    pub async fn load_normal_map(&mut self, path: &str) {
+        let options = TextureOptions::new().with_premultiplied_alpha(false);
+        self.normal_map = assets::Assets::load_image_with(path, options).await;
+    }
+
+    /* Plays from the start, sizing the sprite to one frame */
+    pub fn play(&mut self, animation: Rc<Animation>) {
+        self.width = animation.frame_width;
+        self.height = animation.frame_height;
+
+        let player = AnimationPlayer::new(animation);
+        self.uv_rect = player.uv_rect();
+        self.animation = Some(player);
+    }
+
+    /* Local pixel space (centered on the sprite) to world space */
+    pub fn transform(&self) -> Mat3 {
+        Mat3::translation(Vec2::new(self.x, self.y))
+            * Mat3::rotation(-to_radians(self.rotation))
+            * Mat3::scale(Vec2::new(self.scalex, self.scaley))
+            * Mat3::translation(Vec2::new(-self.originx, -self.originy))
+    }
+
+    /* Maps the unit quad in BASE_QUAD_VERTS onto the sprite in world space */
+    pub fn quad_transform(&self) -> Mat3 {
+        self.transform() * Mat3::scale(Vec2::new(self.width, self.height))
+    }
+
+    /* World space bounding box, accounting for rotation, scale and origin */
+    pub fn bounds(&self) -> Rect {
+        CLIP_RECT.transform(&self.quad_transform())
+    }
+
+    /* Whether the texture was uploaded with premultiplied alpha */
+    pub fn premultiplied(&self) -> bool {
+        match &self.image {
+            Some(image) => image.borrow().options.premultiply_alpha,
+            None => true,
+        }
+    }
+
+    /* Tint as the shaders want it, premultiplied when the texture is */
+    pub fn vertex_color(&self) -> Color {
+        if self.premultiplied() {
+            self.color.premultiplied()
+        } else {
+            self.color
+        }
+    }
+
+    /* Per-sprite data for the instanced path, laid out as render::INSTANCE_LAYOUT */
+    pub fn instance_data(&self) -> [f32; INSTANCE_FLOATS] {
+        let [u, v, uv_width, uv_height] = self.uv_rect.to_array();
+        let [red, green, blue, alpha] = self.vertex_color().to_array();
+
+        [
+            self.x,
+            self.y,
+            self.width,
+            self.height,
+            self.scalex,
+            self.scaley,
+            self.originx,
+            self.originy,
+            to_radians(self.rotation),
+            u,
+            v,
+            uv_width,
+            uv_height,
+            red,
+            green,
+            blue,
+            alpha,
+        ]
+    }
 }
 
 impl Object for Sprite {
-    fn update(&mut self, _delta_time: f32) {}
+    fn update(&mut self, delta_time: f32) {
+        self.publish_position();
+        if let Some(player) = &mut self.animation {
+            player.update(delta_time);
+            self.uv_rect = player.uv_rect();
+        }
+    }
 
     fn draw(&self, renderer: &render::Renderer) {
+        self.publish_position();
         if let Some(ref image) = self.image {
             let mut camera = self.camera.borrow_mut();
-            let vertices = camera.transform_tris(self);
-            let top = camera.draws.last_mut();
+            if camera.cull(self) {
+                return;
+            }
+
+            let image = image.borrow();
+            let texture = &image.webl_gl_texture;
+            let premultiplied = image.options.premultiply_alpha;
+
+            if camera.lighting.is_some() {
+                let vertices = camera.normal_tris(self);
+                match &self.normal_map {
+                    Some(normal_map) => {
+                        camera.push_normal_quad(&normal_map.borrow().webl_gl_texture, &vertices)
+                    }
+                    None => camera.push_flat_normal_quad(texture, &vertices),
+                }
+            }
 
-            let texture = &image.borrow().webl_gl_texture;
+            if camera.instanced {
+                let program = renderer.instanced_program(&self.shader);
 
-            /* Batch draws that use the same texture and program */
-            if let Some(draw) = top {
-                if draw.program == self.shader && draw.texture == *texture {
-                    draw.vertices.append(&mut vertices.clone());
+                /* Falls through to the CPU path if the instanced variant failed to link */
+                if program.is_instanced() {
+                    let mut instance = self.instance_data();
+                    let snap = camera.pixel_snap(self);
+                    instance[0] += snap.x;
+                    instance[1] += snap.y;
 
-                    draw.count += 1;
+                    camera.push_instance(texture, &program, &instance, premultiplied);
                     return;
                 }
             }
 
-            let draw_call = DrawCall {
-                texture: texture.clone(),
-                program: self.shader.clone(),
-                vertices,
-                count: 1,
-            };
-
-            camera.draws.push(draw_call);
+            let vertices = camera.transform_tris(self);
+            camera.push_quad(texture, &self.shader, &vertices, premultiplied);
         }
     }
 }
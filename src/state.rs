@@ -0,0 +1,343 @@
+#![allow(unused)]
+
+use web_sys::{
+    WebGl2RenderingContext, WebGlBuffer, WebGlFramebuffer, WebGlProgram, WebGlSampler,
+    WebGlTexture, WebGlVertexArrayObject,
+};
+
+pub const MAX_TEXTURE_UNITS: usize = 16;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StateStats {
+    pub issued: u32,
+    pub skipped: u32,
+}
+
+/* Mirror of the GL binding state, every change goes through here so redundant calls can be skipped */
+pub struct GlState {
+    pub context: WebGl2RenderingContext,
+
+    pub program: Option<WebGlProgram>,
+    pub active_unit: u32,
+    pub textures: [Option<WebGlTexture>; MAX_TEXTURE_UNITS],
+    /* Overrides the filtering of whatever texture is bound to the unit, None samples as uploaded */
+    pub samplers: [Option<WebGlSampler>; MAX_TEXTURE_UNITS],
+    pub vertex_array: Option<WebGlVertexArrayObject>,
+    pub array_buffer: Option<WebGlBuffer>,
+    pub element_buffer: Option<WebGlBuffer>,
+    pub read_framebuffer: Option<WebGlFramebuffer>,
+    pub draw_framebuffer: Option<WebGlFramebuffer>,
+
+    pub blend: bool,
+    /* Source and destination factors for color, then for alpha */
+    pub blend_func: (u32, u32, u32, u32),
+    pub viewport: (i32, i32, i32, i32),
+    /* Scissor box while the scissor test is enabled */
+    pub scissor: Option<(i32, i32, i32, i32)>,
+    pub stencil_test: bool,
+    /* Bits the stencil buffer can be written in */
+    pub stencil_mask: u32,
+    /* Comparison, reference and mask */
+    pub stencil_func: (u32, i32, u32),
+    /* On stencil fail, depth fail and pass */
+    pub stencil_op: (u32, u32, u32),
+    pub color_mask: [bool; 4],
+    pub clear_color: Option<[f32; 4]>,
+
+    pub stats: StateStats,
+}
+
+impl GlState {
+    pub fn new(context: WebGl2RenderingContext) -> GlState {
+        GlState {
+            context,
+
+            program: None,
+            active_unit: 0,
+            textures: Default::default(),
+            samplers: Default::default(),
+            vertex_array: None,
+            array_buffer: None,
+            element_buffer: None,
+            read_framebuffer: None,
+            draw_framebuffer: None,
+
+            blend: false,
+            blend_func: (
+                WebGl2RenderingContext::ONE,
+                WebGl2RenderingContext::ZERO,
+                WebGl2RenderingContext::ONE,
+                WebGl2RenderingContext::ZERO,
+            ),
+            viewport: (0, 0, 0, 0),
+            scissor: None,
+            stencil_test: false,
+            stencil_mask: u32::MAX,
+            stencil_func: (WebGl2RenderingContext::ALWAYS, 0, u32::MAX),
+            stencil_op: (
+                WebGl2RenderingContext::KEEP,
+                WebGl2RenderingContext::KEEP,
+                WebGl2RenderingContext::KEEP,
+            ),
+            color_mask: [true; 4],
+            clear_color: None,
+
+            stats: StateStats::default(),
+        }
+    }
+
+    pub fn use_program(&mut self, program: Option<&WebGlProgram>) {
+        if self.program.as_ref() == program {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.use_program(program);
+        self.program = program.cloned();
+        self.stats.issued += 1;
+    }
+
+    pub fn active_texture(&mut self, unit: u32) {
+        if self.active_unit == unit {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context
+            .active_texture(WebGl2RenderingContext::TEXTURE0 + unit);
+        self.active_unit = unit;
+        self.stats.issued += 1;
+    }
+
+    /* Leaves `unit` active even when the bind is skipped, texture parameters and mipmap generation
+    that follow apply to the active unit */
+    pub fn bind_texture(&mut self, unit: u32, texture: Option<&WebGlTexture>) {
+        self.active_texture(unit);
+        if self.textures[unit as usize].as_ref() == texture {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context
+            .bind_texture(WebGl2RenderingContext::TEXTURE_2D, texture);
+        self.textures[unit as usize] = texture.cloned();
+        self.stats.issued += 1;
+    }
+
+    pub fn bind_sampler(&mut self, unit: u32, sampler: Option<&WebGlSampler>) {
+        if self.samplers[unit as usize].as_ref() == sampler {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.bind_sampler(unit, sampler);
+        self.samplers[unit as usize] = sampler.cloned();
+        self.stats.issued += 1;
+    }
+
+    /* The element buffer binding lives in the vertex array, so the caller passes the one it captured */
+    pub fn bind_vertex_array(
+        &mut self,
+        vertex_array: Option<&WebGlVertexArrayObject>,
+        element_buffer: Option<&WebGlBuffer>,
+    ) {
+        if self.vertex_array.as_ref() == vertex_array {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.bind_vertex_array(vertex_array);
+        self.vertex_array = vertex_array.cloned();
+        self.element_buffer = element_buffer.cloned();
+        self.stats.issued += 1;
+    }
+
+    pub fn bind_buffer(&mut self, target: u32, buffer: Option<&WebGlBuffer>) {
+        let bound = match target {
+            WebGl2RenderingContext::ELEMENT_ARRAY_BUFFER => &mut self.element_buffer,
+            _ => &mut self.array_buffer,
+        };
+
+        if bound.as_ref() == buffer {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.bind_buffer(target, buffer);
+        *bound = buffer.cloned();
+        self.stats.issued += 1;
+    }
+
+    pub fn bind_framebuffer(&mut self, target: u32, framebuffer: Option<&WebGlFramebuffer>) {
+        let read = target != WebGl2RenderingContext::DRAW_FRAMEBUFFER;
+        let draw = target != WebGl2RenderingContext::READ_FRAMEBUFFER;
+
+        if (!read || self.read_framebuffer.as_ref() == framebuffer)
+            && (!draw || self.draw_framebuffer.as_ref() == framebuffer)
+        {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.bind_framebuffer(target, framebuffer);
+        if read {
+            self.read_framebuffer = framebuffer.cloned();
+        }
+        if draw {
+            self.draw_framebuffer = framebuffer.cloned();
+        }
+        self.stats.issued += 1;
+    }
+
+    pub fn set_blend(&mut self, enabled: bool) {
+        if self.blend == enabled {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        if enabled {
+            self.context.enable(WebGl2RenderingContext::BLEND);
+        } else {
+            self.context.disable(WebGl2RenderingContext::BLEND);
+        }
+        self.blend = enabled;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_blend_func(&mut self, source: u32, destination: u32) {
+        self.set_blend_func_separate(source, destination, source, destination);
+    }
+
+    pub fn set_blend_func_separate(
+        &mut self,
+        source_color: u32,
+        destination_color: u32,
+        source_alpha: u32,
+        destination_alpha: u32,
+    ) {
+        let blend_func = (
+            source_color,
+            destination_color,
+            source_alpha,
+            destination_alpha,
+        );
+        if self.blend_func == blend_func {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.blend_func_separate(
+            source_color,
+            destination_color,
+            source_alpha,
+            destination_alpha,
+        );
+        self.blend_func = blend_func;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_viewport(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        if self.viewport == (x, y, width, height) {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.viewport(x, y, width, height);
+        self.viewport = (x, y, width, height);
+        self.stats.issued += 1;
+    }
+
+    pub fn set_scissor(&mut self, scissor: Option<(i32, i32, i32, i32)>) {
+        if self.scissor == scissor {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        match scissor {
+            Some((x, y, width, height)) => {
+                if self.scissor.is_none() {
+                    self.context.enable(WebGl2RenderingContext::SCISSOR_TEST);
+                }
+                self.context.scissor(x, y, width, height);
+            }
+            None => self.context.disable(WebGl2RenderingContext::SCISSOR_TEST),
+        }
+        self.scissor = scissor;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_stencil_test(&mut self, enabled: bool) {
+        if self.stencil_test == enabled {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        if enabled {
+            self.context.enable(WebGl2RenderingContext::STENCIL_TEST);
+        } else {
+            self.context.disable(WebGl2RenderingContext::STENCIL_TEST);
+        }
+        self.stencil_test = enabled;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_stencil_mask(&mut self, mask: u32) {
+        if self.stencil_mask == mask {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.stencil_mask(mask);
+        self.stencil_mask = mask;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_stencil_func(&mut self, function: u32, reference: i32, mask: u32) {
+        if self.stencil_func == (function, reference, mask) {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.stencil_func(function, reference, mask);
+        self.stencil_func = (function, reference, mask);
+        self.stats.issued += 1;
+    }
+
+    pub fn set_stencil_op(&mut self, fail: u32, depth_fail: u32, pass: u32) {
+        if self.stencil_op == (fail, depth_fail, pass) {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.stencil_op(fail, depth_fail, pass);
+        self.stencil_op = (fail, depth_fail, pass);
+        self.stats.issued += 1;
+    }
+
+    pub fn set_color_mask(&mut self, mask: [bool; 4]) {
+        if self.color_mask == mask {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        let [red, green, blue, alpha] = mask;
+        self.context.color_mask(red, green, blue, alpha);
+        self.color_mask = mask;
+        self.stats.issued += 1;
+    }
+
+    pub fn set_clear_color(&mut self, red: f32, green: f32, blue: f32, alpha: f32) {
+        if self.clear_color == Some([red, green, blue, alpha]) {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        self.context.clear_color(red, green, blue, alpha);
+        self.clear_color = Some([red, green, blue, alpha]);
+        self.stats.issued += 1;
+    }
+
+    pub fn take_stats(&mut self) -> StateStats {
+        std::mem::take(&mut self.stats)
+    }
+}
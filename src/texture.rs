@@ -0,0 +1,113 @@
+#![allow(unused)]
+
+use web_sys::WebGl2RenderingContext;
+
+/* From EXT_texture_filter_anisotropic, web-sys doesn't expose the extension's constants */
+pub const TEXTURE_MAX_ANISOTROPY_EXT: u32 = 0x84FE;
+pub const MAX_TEXTURE_MAX_ANISOTROPY_EXT: u32 = 0x84FF;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Wrap {
+    Clamp,
+    Repeat,
+    Mirror,
+}
+
+impl Wrap {
+    pub fn gl_enum(&self) -> u32 {
+        match self {
+            Wrap::Clamp => WebGl2RenderingContext::CLAMP_TO_EDGE,
+            Wrap::Repeat => WebGl2RenderingContext::REPEAT,
+            Wrap::Mirror => WebGl2RenderingContext::MIRRORED_REPEAT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextureOptions {
+    pub min_filter: Filter,
+    pub mag_filter: Filter,
+    /* Generates mipmaps and samples between levels when minifying, stops zoomed out shimmering */
+    pub mipmaps: bool,
+    pub wrap_s: Wrap,
+    pub wrap_t: Wrap,
+    /* Requested anisotropy, clamped to what the device supports and ignored without the extension */
+    pub anisotropy: f32,
+    /* Multiply color by alpha at upload so filtering doesn't bleed dark edges, see render::blend_factors */
+    pub premultiply_alpha: bool,
+}
+
+impl TextureOptions {
+    pub fn new() -> TextureOptions {
+        TextureOptions {
+            min_filter: Filter::Linear,
+            mag_filter: Filter::Linear,
+            mipmaps: true,
+            wrap_s: Wrap::Clamp,
+            wrap_t: Wrap::Clamp,
+            anisotropy: 1.0,
+            premultiply_alpha: true,
+        }
+    }
+
+    /* Hard texel edges at any zoom */
+    pub fn nearest() -> TextureOptions {
+        TextureOptions {
+            min_filter: Filter::Nearest,
+            mag_filter: Filter::Nearest,
+            mipmaps: false,
+            ..TextureOptions::new()
+        }
+    }
+
+    pub fn with_wrap(self, wrap: Wrap) -> TextureOptions {
+        TextureOptions {
+            wrap_s: wrap,
+            wrap_t: wrap,
+            ..self
+        }
+    }
+
+    pub fn with_premultiplied_alpha(self, premultiply_alpha: bool) -> TextureOptions {
+        TextureOptions {
+            premultiply_alpha,
+            ..self
+        }
+    }
+
+    pub fn with_mipmaps(self, mipmaps: bool) -> TextureOptions {
+        TextureOptions { mipmaps, ..self }
+    }
+
+    pub fn with_anisotropy(self, anisotropy: f32) -> TextureOptions {
+        TextureOptions { anisotropy, ..self }
+    }
+
+    pub fn min_filter_gl(&self) -> u32 {
+        match (self.min_filter, self.mipmaps) {
+            (Filter::Nearest, false) => WebGl2RenderingContext::NEAREST,
+            (Filter::Linear, false) => WebGl2RenderingContext::LINEAR,
+            (Filter::Nearest, true) => WebGl2RenderingContext::NEAREST_MIPMAP_LINEAR,
+            (Filter::Linear, true) => WebGl2RenderingContext::LINEAR_MIPMAP_LINEAR,
+        }
+    }
+
+    pub fn mag_filter_gl(&self) -> u32 {
+        match self.mag_filter {
+            Filter::Nearest => WebGl2RenderingContext::NEAREST,
+            Filter::Linear => WebGl2RenderingContext::LINEAR,
+        }
+    }
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions::new()
+    }
+}